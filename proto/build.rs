@@ -25,6 +25,22 @@ const INCLUDES: &'static [&'static str] = &[
     "grr/grr/proto",
 ];
 
+const RRG_PROTOS: &'static [&'static str] = &[
+    "rrg/control.proto",
+    "rrg/finder.proto",
+    "rrg/log.proto",
+    "rrg/metadata.proto",
+    "rrg/progress.proto",
+    "rrg/startup.proto",
+    "rrg/stat.proto",
+    "rrg/timeline.proto",
+    "rrg/traversal.proto",
+];
+
+const RRG_INCLUDES: &'static [&'static str] = &[
+    "rrg",
+];
+
 fn main() {
     // Because GRR proto files are not something that PROST! can accept (because
     // of the missing package declaration), we create a temporary directory with
@@ -50,6 +66,24 @@ fn main() {
         includes.push(path_after);
     }
 
+    // RRG-specific proto files declare their own package, so unlike the GRR
+    // ones they can be fed to the compiler directly. However, they can import
+    // the (patched) GRR files as well, so these have to be included. Because
+    // GRR messages are generated at the root of the crate, PROST! has to be
+    // told where to find them.
+    let mut rrg_includes = RRG_INCLUDES.iter()
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+    rrg_includes.extend(includes.iter().cloned());
+
+    prost_build::Config::new()
+        .extern_path(".grr", "crate")
+        .compile_protos(RRG_PROTOS, &rrg_includes)
+        .expect("failed to compile RRG proto files");
+
+    // Note that the GRR files have to be compiled after the RRG ones: PROST!
+    // emits an (empty) module also for the extern package, overwriting the one
+    // generated before.
     prost_build::compile_protos(&protos, &includes)
         .expect("failed to compile proto files");
 
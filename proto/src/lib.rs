@@ -5,6 +5,14 @@
 
 include!(concat!(env!("OUT_DIR"), "/grr.rs"));
 
+/// Protocol Buffers messages that are specific to RRG.
+///
+/// These messages are not part of the GRR protocol and are used for features
+/// that GRR agents do not have.
+pub mod rrg {
+    include!(concat!(env!("OUT_DIR"), "/rrg.rs"));
+}
+
 impl From<bool> for DataBlob {
 
     fn from(value: bool) -> DataBlob {
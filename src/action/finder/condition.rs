@@ -0,0 +1,542 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Conditions that files reported by the file finder have to satisfy.
+//!
+//! Conditions are divided into two groups: metadata conditions (e.g. on the
+//! file size) that are cheap to check and content conditions (e.g. presence
+//! of a literal) that require reading the file. The latter also yield matches
+//! that are reported to the server.
+
+use std::fs::{File, Metadata};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use rrg_proto::file_finder_condition::Type as ConditionType;
+use rrg_proto::file_finder_contents_literal_match_condition::Mode as LiteralMode;
+
+use crate::session::{MissingFieldError, ParseError};
+
+/// A size of chunks in which files are read when looking for literals.
+const SEARCH_CHUNK_SIZE: usize = 1 << 20;
+
+/// A default number of bytes reported around literal matches.
+const DEFAULT_CONTEXT_SIZE: u64 = 10;
+
+/// A condition on a file found by the file finder.
+#[derive(Debug, PartialEq)]
+pub enum Condition {
+    /// The file was last modified within the given range.
+    ModificationTime(TimeRange),
+    /// The file was last accessed within the given range.
+    AccessTime(TimeRange),
+    /// The file inode was last changed within the given range.
+    InodeChangeTime(TimeRange),
+    /// The file size is within the given (inclusive) range.
+    Size {
+        /// A minimum size of the file.
+        min: u64,
+        /// A maximum size of the file.
+        max: u64,
+    },
+    /// The file has the given mode bits (as in `st_mode`) set and unset.
+    Mode {
+        /// A mask selecting the mode bits to check.
+        mask: u32,
+        /// Expected values of the selected mode bits.
+        mode: u32,
+    },
+    /// The file has the given Linux inode flags (as in `chattr`) set and unset.
+    ///
+    /// These are not mode bits: GRR exposes them as "ext flags" conditions.
+    #[cfg(target_os = "linux")]
+    Flags {
+        /// Flags that have to be set.
+        set: u32,
+        /// Flags that have to be unset.
+        unset: u32,
+    },
+    /// The file contains the given literal.
+    Literal(LiteralMatch),
+}
+
+/// A range of timestamps (in microseconds since the epoch).
+#[derive(Debug, PartialEq)]
+pub struct TimeRange {
+    /// A minimum (inclusive) timestamp, if bounded.
+    min: Option<u64>,
+    /// A maximum (inclusive) timestamp, if bounded.
+    max: Option<u64>,
+}
+
+impl TimeRange {
+
+    /// Checks whether the given timestamp falls within the range.
+    ///
+    /// Unknown timestamps are considered to be out of any bounded range.
+    fn contains(&self, time: Option<u64>) -> bool {
+        if self.min.is_none() && self.max.is_none() {
+            return true;
+        }
+
+        match time {
+            Some(time) => {
+                self.min.map_or(true, |min| min <= time) &&
+                self.max.map_or(true, |max| time <= max)
+            }
+            None => false,
+        }
+    }
+}
+
+/// A specification of a literal to look for in file contents.
+#[derive(Debug, PartialEq)]
+pub struct LiteralMatch {
+    /// The literal to look for.
+    literal: Vec<u8>,
+    /// Whether all the occurrences should be reported (or just the first one).
+    all_hits: bool,
+    /// An offset at which the search should begin.
+    start_offset: u64,
+    /// A maximum number of bytes to search through (if limited).
+    length: Option<u64>,
+    /// A number of bytes preceding the occurrence to report.
+    bytes_before: u64,
+    /// A number of bytes following the occurrence to report.
+    bytes_after: u64,
+}
+
+/// An occurrence of a literal in the file contents.
+#[derive(Debug, PartialEq)]
+pub struct Hit {
+    /// An offset of the reported data within the file.
+    pub offset: u64,
+    /// The occurrence together with the surrounding bytes.
+    pub data: Vec<u8>,
+}
+
+impl Condition {
+
+    /// Parses a condition from its raw proto.
+    pub fn from_proto(proto: rrg_proto::FileFinderCondition) -> Result<Condition, ParseError> {
+        let condition_type = proto.condition_type
+            .ok_or(MissingFieldError::new("condition type"))?;
+
+        match ConditionType::from_i32(condition_type) {
+            Some(ConditionType::ModificationTime) => {
+                let proto = proto.modification_time
+                    .ok_or(MissingFieldError::new("modification time"))?;
+
+                Ok(Condition::ModificationTime(TimeRange {
+                    min: proto.min_last_modified_time,
+                    max: proto.max_last_modified_time,
+                }))
+            }
+            Some(ConditionType::AccessTime) => {
+                let proto = proto.access_time
+                    .ok_or(MissingFieldError::new("access time"))?;
+
+                Ok(Condition::AccessTime(TimeRange {
+                    min: proto.min_last_access_time,
+                    max: proto.max_last_access_time,
+                }))
+            }
+            Some(ConditionType::InodeChangeTime) => {
+                let proto = proto.inode_change_time
+                    .ok_or(MissingFieldError::new("inode change time"))?;
+
+                Ok(Condition::InodeChangeTime(TimeRange {
+                    min: proto.min_last_inode_change_time,
+                    max: proto.max_last_inode_change_time,
+                }))
+            }
+            Some(ConditionType::Size) => {
+                let proto = proto.size
+                    .ok_or(MissingFieldError::new("size"))?;
+
+                Ok(Condition::Size {
+                    min: proto.min_file_size.unwrap_or(0),
+                    max: proto.max_file_size.unwrap_or(u64::MAX),
+                })
+            }
+            #[cfg(target_os = "linux")]
+            Some(ConditionType::ExtFlags) => {
+                let proto = proto.ext_flags
+                    .ok_or(MissingFieldError::new("ext flags"))?;
+
+                Ok(Condition::Flags {
+                    set: proto.linux_bits_set.unwrap_or(0),
+                    unset: proto.linux_bits_unset.unwrap_or(0),
+                })
+            }
+            #[cfg(not(target_os = "linux"))]
+            Some(ConditionType::ExtFlags) => {
+                Err(ParseError::malformed("ext flags conditions are only supported on Linux"))
+            }
+            Some(ConditionType::ContentsLiteralMatch) => {
+                let proto = proto.contents_literal_match
+                    .ok_or(MissingFieldError::new("contents literal match"))?;
+
+                let literal = match proto.literal {
+                    Some(ref literal) if !literal.is_empty() => literal.clone(),
+                    _ => return Err(MissingFieldError::new("literal").into()),
+                };
+
+                let all_hits = proto.mode == Some(LiteralMode::AllHits.into());
+
+                Ok(Condition::Literal(LiteralMatch {
+                    literal: literal,
+                    all_hits: all_hits,
+                    start_offset: proto.start_offset.unwrap_or(0),
+                    length: proto.length,
+                    bytes_before: proto.bytes_before.map_or(DEFAULT_CONTEXT_SIZE, u64::from),
+                    bytes_after: proto.bytes_after.map_or(DEFAULT_CONTEXT_SIZE, u64::from),
+                }))
+            }
+            Some(ConditionType::ContentsRegexMatch) => {
+                Err(ParseError::malformed("regex conditions are not supported"))
+            }
+            None => {
+                Err(ParseError::malformed(format!("unknown condition type: {}", condition_type)))
+            }
+        }
+    }
+
+    /// Parses a mode condition from its raw proto.
+    pub fn from_mode_proto(proto: rrg_proto::rrg::FileFinderModeCondition) -> Result<Condition, ParseError> {
+        let mask = proto.mask
+            .ok_or(MissingFieldError::new("mode mask"))?;
+
+        Ok(Condition::Mode {
+            mask: mask,
+            mode: proto.mode.unwrap_or(0),
+        })
+    }
+
+    /// Checks whether the condition requires reading the file contents.
+    pub fn is_content(&self) -> bool {
+        match *self {
+            Condition::Literal(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Checks whether the file at `path` with the given `metadata` satisfies
+    /// the condition.
+    ///
+    /// Content conditions are not checked by this method and are always
+    /// considered to be satisfied.
+    #[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+    pub fn check_metadata(&self, path: &Path, metadata: &Metadata) -> bool {
+        match *self {
+            Condition::ModificationTime(ref range) => {
+                range.contains(micros(metadata.modified()))
+            }
+            Condition::AccessTime(ref range) => {
+                range.contains(micros(metadata.accessed()))
+            }
+            Condition::InodeChangeTime(ref range) => {
+                range.contains(inode_change_time(metadata))
+            }
+            Condition::Size { min, max } => {
+                min <= metadata.len() && metadata.len() <= max
+            }
+            Condition::Mode { mask, mode } => match self::mode(metadata) {
+                Some(actual) => actual & mask == mode & mask,
+                None => false,
+            },
+            #[cfg(target_os = "linux")]
+            Condition::Flags { set, unset } => {
+                match super::super::statentry::linux_flags(path) {
+                    Ok(flags) => flags & set == set && flags & unset == 0,
+                    Err(_) => false,
+                }
+            }
+            Condition::Literal(_) => true,
+        }
+    }
+}
+
+impl LiteralMatch {
+
+    /// Searches the file at `path` for occurrences of the literal.
+    ///
+    /// If only the first hit was requested, at most one hit is returned.
+    pub fn search(&self, path: &Path) -> std::io::Result<Vec<Hit>> {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(self.start_offset))?;
+
+        let mut reader = file.take(self.length.unwrap_or(u64::MAX));
+
+        let mut offsets = Vec::new();
+        let mut buffer = Vec::new();
+        let mut buffer_offset = self.start_offset;
+        let mut chunk = vec![0; SEARCH_CHUNK_SIZE];
+
+        'search: loop {
+            let count = reader.read(&mut chunk[..])?;
+            if count == 0 {
+                break;
+            }
+            buffer.extend_from_slice(&chunk[..count]);
+
+            for position in find_all(&buffer, &self.literal) {
+                offsets.push(buffer_offset + position as u64);
+                if !self.all_hits {
+                    break 'search;
+                }
+            }
+
+            // We keep the tail of the buffer that is too short to contain the
+            // literal, as the literal might continue in the next chunk.
+            let keep = std::cmp::min(self.literal.len() - 1, buffer.len());
+            let drained = buffer.len() - keep;
+            buffer.drain(..drained);
+            buffer_offset += drained as u64;
+        }
+
+        let mut file = reader.into_inner();
+        offsets.into_iter()
+            .map(|offset| self.hit(&mut file, offset))
+            .collect()
+    }
+
+    /// Reads the occurrence at `offset` with its surroundings from `file`.
+    fn hit(&self, file: &mut File, offset: u64) -> std::io::Result<Hit> {
+        let start = std::cmp::max(offset.saturating_sub(self.bytes_before), self.start_offset);
+        let end = offset + self.literal.len() as u64 + self.bytes_after;
+
+        file.seek(SeekFrom::Start(start))?;
+
+        let mut data = Vec::new();
+        file.take(end - start).read_to_end(&mut data)?;
+
+        Ok(Hit {
+            offset: start,
+            data: data,
+        })
+    }
+}
+
+/// Returns positions of all (possibly overlapping) occurrences of `needle`.
+fn find_all<'a>(haystack: &'a [u8], needle: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+    haystack.windows(needle.len())
+        .enumerate()
+        .filter(move |(_, window)| *window == needle)
+        .map(|(position, _)| position)
+}
+
+/// Converts a timestamp (if available) to microseconds since the epoch.
+///
+/// Timestamps before the epoch are not representable and yield `None`.
+fn micros(time: std::io::Result<std::time::SystemTime>) -> Option<u64> {
+    rrg_proto::micros(time.ok()?).ok()
+}
+
+/// Returns the inode change time (in microseconds since the epoch).
+#[cfg(target_family = "unix")]
+fn inode_change_time(metadata: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt as _;
+
+    if metadata.ctime() < 0 {
+        return None;
+    }
+
+    Some(metadata.ctime() as u64 * 1_000_000 + metadata.ctime_nsec() as u64 / 1_000)
+}
+
+/// Returns the inode change time (in microseconds since the epoch).
+#[cfg(not(target_family = "unix"))]
+fn inode_change_time(_metadata: &Metadata) -> Option<u64> {
+    None
+}
+
+/// Returns the mode bits (as in `st_mode`) of the file.
+#[cfg(target_family = "unix")]
+fn mode(metadata: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::MetadataExt as _;
+
+    Some(metadata.mode())
+}
+
+/// Returns the mode bits (as in `st_mode`) of the file.
+#[cfg(not(target_family = "unix"))]
+fn mode(_metadata: &Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn literal(literal: &[u8], all_hits: bool) -> LiteralMatch {
+        LiteralMatch {
+            literal: literal.to_vec(),
+            all_hits: all_hits,
+            start_offset: 0,
+            length: None,
+            bytes_before: 2,
+            bytes_after: 2,
+        }
+    }
+
+    #[test]
+    fn test_from_proto_size() {
+        let condition = Condition::from_proto(rrg_proto::FileFinderCondition {
+            condition_type: Some(ConditionType::Size.into()),
+            size: Some(rrg_proto::FileFinderSizeCondition {
+                min_file_size: Some(42),
+                max_file_size: None,
+            }),
+            ..Default::default()
+        }).unwrap();
+
+        assert_eq!(condition, Condition::Size { min: 42, max: u64::MAX });
+    }
+
+    #[test]
+    fn test_from_proto_missing_options() {
+        let condition = Condition::from_proto(rrg_proto::FileFinderCondition {
+            condition_type: Some(ConditionType::ModificationTime.into()),
+            ..Default::default()
+        });
+
+        assert!(condition.is_err());
+    }
+
+    #[test]
+    fn test_from_proto_regex_unsupported() {
+        let condition = Condition::from_proto(rrg_proto::FileFinderCondition {
+            condition_type: Some(ConditionType::ContentsRegexMatch.into()),
+            ..Default::default()
+        });
+
+        assert!(condition.is_err());
+    }
+
+    #[test]
+    fn test_time_range() {
+        let range = TimeRange { min: Some(10), max: Some(20) };
+        assert!(range.contains(Some(10)));
+        assert!(range.contains(Some(20)));
+        assert!(!range.contains(Some(21)));
+        assert!(!range.contains(None));
+
+        let unbounded = TimeRange { min: None, max: None };
+        assert!(unbounded.contains(None));
+    }
+
+    #[test]
+    fn test_check_metadata_size() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("foo");
+        std::fs::write(&path, b"foobar").unwrap();
+
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert!(Condition::Size { min: 6, max: 6 }.check_metadata(&path, &metadata));
+        assert!(!Condition::Size { min: 7, max: 10 }.check_metadata(&path, &metadata));
+    }
+
+    #[test]
+    fn test_from_mode_proto() {
+        let condition = Condition::from_mode_proto(rrg_proto::rrg::FileFinderModeCondition {
+            mask: Some(0o4000),
+            mode: Some(0o4000),
+        }).unwrap();
+
+        assert_eq!(condition, Condition::Mode { mask: 0o4000, mode: 0o4000 });
+    }
+
+    #[test]
+    fn test_from_mode_proto_missing_mask() {
+        let condition = Condition::from_mode_proto(Default::default());
+        assert!(condition.is_err());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_check_metadata_mode() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("foo");
+        std::fs::write(&path, b"foobar").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+
+        let metadata = std::fs::metadata(&path).unwrap();
+
+        let regular = Condition::Mode { mask: 0o170000, mode: 0o100000 };
+        assert!(regular.check_metadata(&path, &metadata));
+
+        let world_readable = Condition::Mode { mask: 0o004, mode: 0o004 };
+        assert!(!world_readable.check_metadata(&path, &metadata));
+
+        let group_readable = Condition::Mode { mask: 0o070, mode: 0o040 };
+        assert!(group_readable.check_metadata(&path, &metadata));
+    }
+
+    #[test]
+    fn test_check_metadata_modification_time() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("foo");
+        std::fs::write(&path, b"foobar").unwrap();
+
+        let metadata = std::fs::metadata(&path).unwrap();
+        let now = rrg_proto::micros(std::time::SystemTime::now()).unwrap();
+
+        let past = Condition::ModificationTime(TimeRange { min: None, max: Some(1) });
+        assert!(!past.check_metadata(&path, &metadata));
+
+        let recent = Condition::ModificationTime(TimeRange { min: Some(1), max: Some(now + 1_000_000) });
+        assert!(recent.check_metadata(&path, &metadata));
+    }
+
+    #[test]
+    fn test_search_first_hit() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("foo");
+        std::fs::write(&path, b"xxfooyyfoozz").unwrap();
+
+        let hits = literal(b"foo", false).search(&path).unwrap();
+        assert_eq!(hits, vec![Hit { offset: 0, data: b"xxfooyy".to_vec() }]);
+    }
+
+    #[test]
+    fn test_search_all_hits() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("foo");
+        std::fs::write(&path, b"xxfooyyfoozz").unwrap();
+
+        let hits = literal(b"foo", true).search(&path).unwrap();
+        assert_eq!(hits, vec![
+            Hit { offset: 0, data: b"xxfooyy".to_vec() },
+            Hit { offset: 5, data: b"yyfoozz".to_vec() },
+        ]);
+    }
+
+    #[test]
+    fn test_search_across_chunks() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("foo");
+
+        let mut data = vec![0; SEARCH_CHUNK_SIZE - 1];
+        data.extend_from_slice(b"foo");
+        std::fs::write(&path, &data).unwrap();
+
+        let hits = literal(b"foo", true).search(&path).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].offset, SEARCH_CHUNK_SIZE as u64 - 3);
+    }
+
+    #[test]
+    fn test_search_no_hits() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("foo");
+        std::fs::write(&path, b"foobar").unwrap();
+
+        assert!(literal(b"baz", true).search(&path).unwrap().is_empty());
+    }
+}
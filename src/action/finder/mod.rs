@@ -0,0 +1,828 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! A handler and associated types for the file finder action.
+//!
+//! The file finder action looks for files matching glob patterns and a set of
+//! conditions (on their metadata or contents). Depending on the requested mode,
+//! for every found file it reports its stat entry, its hash or sends its whole
+//! contents to the transfer store.
+//!
+//! The action is compatible with the `FileFinder` action of GRR agents, except
+//! that it does not support regex conditions and path interpolation.
+
+mod condition;
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use rrg_proto::file_finder_action::Action as ActionType;
+use rrg_proto::path_spec::PathType;
+use sha2::{Digest, Sha256};
+
+use crate::fs::{ErrorSummary, Pattern, WalkError};
+use crate::session::{self, MissingFieldError, ParseError, Progress, Session};
+use self::condition::{Condition, Hit};
+use super::Shrunk;
+use super::statentry::StatOptions;
+
+/// A default maximum size of files to hash or download.
+const DEFAULT_MAX_SIZE: u64 = 500 * 1024 * 1024;
+
+/// A default size of chunks in which downloaded files are sent.
+const DEFAULT_CHUNK_SIZE: u64 = 512 * 1024;
+
+/// A size of the buffer used for reading files when hashing them.
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A request type for the file finder action.
+#[derive(Debug)]
+pub struct Request {
+    /// Glob patterns of paths to look for.
+    paths: Vec<Pattern>,
+    /// Conditions that the found files have to satisfy.
+    conditions: Vec<Condition>,
+    /// An action to perform on the found files.
+    action: Action,
+    /// Whether symlinks should be followed when collecting file metadata.
+    follow_links: bool,
+    /// Whether contents of non-regular files (e.g. devices) should be read.
+    process_non_regular_files: bool,
+}
+
+/// An action to perform on the found files.
+#[derive(Debug, PartialEq)]
+enum Action {
+    /// Only collect metadata of the files.
+    Stat {
+        /// Whether to collect extended attributes.
+        collect_ext_attrs: bool,
+    },
+    /// Collect metadata and compute hashes of the files.
+    Hash {
+        /// A maximum size of files to hash (larger ones are only stat-ed).
+        max_size: u64,
+    },
+    /// Collect metadata and send contents of the files to the transfer store.
+    Download {
+        /// A maximum size of files to download (larger ones are only stat-ed).
+        max_size: u64,
+        /// A size of chunks in which the files are sent.
+        chunk_size: u64,
+    },
+}
+
+/// A response type for the file finder action.
+pub struct Response {
+    /// Metadata of the found file.
+    stat: super::statentry::Entry,
+    /// A path of the found file.
+    path: PathBuf,
+    /// Occurrences of literals (if requested by conditions).
+    matches: Vec<Hit>,
+    /// A hash of the file contents (if requested).
+    hash: Option<Hash>,
+    /// Chunks of the file sent to the transfer store (if requested).
+    chunks: Option<Vec<Chunk>>,
+}
+
+/// A hash of the file contents.
+#[derive(Debug)]
+struct Hash {
+    /// A SHA-256 digest of the file contents.
+    sha256: Vec<u8>,
+    /// A number of hashed bytes.
+    size: u64,
+}
+
+/// A description of a file chunk sent to the transfer store.
+#[derive(Debug)]
+struct Chunk {
+    /// An offset of the chunk within the file.
+    offset: u64,
+    /// A length of the chunk.
+    length: u64,
+    /// A SHA-256 digest of the chunk.
+    digest: Vec<u8>,
+}
+
+/// A response type for raw file chunks sent to the transfer store.
+struct ChunkResponse {
+    /// Contents of the chunk.
+    data: Vec<u8>,
+}
+
+/// A file that matched a pattern and satisfied all the conditions.
+struct Found {
+    /// A path matched by the pattern.
+    path: PathBuf,
+    /// A path of the actual file (different if symlinks are followed).
+    destination: PathBuf,
+    /// Metadata of the actual file.
+    metadata: std::fs::Metadata,
+    /// Occurrences of literals (if requested by conditions).
+    matches: Vec<Hit>,
+}
+
+/// Handles requests for the file finder action.
+pub fn handle<S: Session>(session: &mut S, request: Request) -> session::Result<()> {
+    let mut visited = HashSet::new();
+    let mut progress = Progress::default();
+    let mut summary = ErrorSummary::new();
+
+    for pattern in &request.paths {
+        for path in pattern.expand() {
+            let path = match path {
+                Ok(path) => path,
+                Err(error) => {
+                    summary.record(error);
+                    continue;
+                }
+            };
+
+            // Different patterns (or different components of the same pattern)
+            // can match the same path, but we want to report every file once.
+            if !visited.insert(path.clone()) {
+                continue;
+            }
+
+            progress.entries += 1;
+            progress.path = Some(path.clone());
+            session.progress(|| progress.clone())?;
+
+            let found = match find(&request, path) {
+                Ok(Some(found)) => found,
+                Ok(None) => continue,
+                Err((path, error)) => {
+                    summary.record(WalkError::new(path, error));
+                    continue;
+                }
+            };
+
+            progress.bytes += found.metadata.len();
+            process(session, &request, found, &mut summary)?;
+        }
+    }
+
+    session.report_errors(summary)
+}
+
+/// Checks whether the file at `path` satisfies all the request conditions.
+///
+/// The path is returned along with the error if checking the conditions fails,
+/// so that it can be reported.
+fn find(request: &Request, path: PathBuf) -> Result<Option<Found>, (PathBuf, std::io::Error)> {
+    let destination = if request.follow_links {
+        match std::fs::canonicalize(&path) {
+            Ok(destination) => destination,
+            Err(error) => return Err((path, error)),
+        }
+    } else {
+        path.clone()
+    };
+
+    let metadata = match std::fs::symlink_metadata(&destination) {
+        Ok(metadata) => metadata,
+        Err(error) => return Err((path, error)),
+    };
+
+    let satisfied = request.conditions.iter()
+        .all(|condition| condition.check_metadata(&destination, &metadata));
+    if !satisfied {
+        return Ok(None);
+    }
+
+    // Contents of non-regular files are not read unless explicitly requested,
+    // so such files cannot satisfy any content condition.
+    let readable = metadata.is_file() || request.process_non_regular_files;
+    if !readable && request.conditions.iter().any(Condition::is_content) {
+        return Ok(None);
+    }
+
+    let mut matches = Vec::new();
+    for condition in &request.conditions {
+        let literal = match *condition {
+            Condition::Literal(ref literal) => literal,
+            _ => continue,
+        };
+
+        let hits = match literal.search(&destination) {
+            Ok(hits) => hits,
+            Err(error) => return Err((path, error)),
+        };
+        if hits.is_empty() {
+            return Ok(None);
+        }
+
+        matches.extend(hits);
+    }
+
+    Ok(Some(Found {
+        path: path,
+        destination: destination,
+        metadata: metadata,
+        matches: matches,
+    }))
+}
+
+/// Performs the requested action on the `found` file and replies with results.
+///
+/// Failures concerning the particular file are recorded in the `summary` and
+/// the file is skipped, while session errors are considered critical and abort
+/// the whole action.
+fn process<S>(session: &mut S, request: &Request, found: Found,
+              summary: &mut ErrorSummary) -> session::Result<()>
+where
+    S: Session,
+{
+    let collect_ext_attrs = match request.action {
+        Action::Stat { collect_ext_attrs } => collect_ext_attrs,
+        _ => false,
+    };
+
+    let stat = StatOptions::new()
+        .ext_attrs(collect_ext_attrs)
+        .flags(true)
+        .btime(true)
+        .follow_symlink(request.follow_links)
+        .stat(&found.path);
+
+    let stat = match stat {
+        Ok(stat) => stat,
+        Err(error) => {
+            summary.record(WalkError::new(found.path, error));
+            return Ok(());
+        }
+    };
+
+    let readable = found.metadata.is_file() || request.process_non_regular_files;
+    let size = found.metadata.len();
+
+    let mut hash = None;
+    let mut chunks = None;
+
+    match request.action {
+        Action::Stat { .. } => (),
+        Action::Hash { max_size } => {
+            if readable && size <= max_size {
+                match self::hash(&found.destination) {
+                    Ok(result) => hash = Some(result),
+                    Err(error) => {
+                        summary.record(WalkError::new(found.path, error));
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        Action::Download { max_size, chunk_size } => {
+            if readable && size <= max_size {
+                // Chunks cannot be split any further, so each one of them has
+                // to fit into a single message.
+                let limit = (session.max_message_size() / 2) as u64;
+                let chunk_size = std::cmp::max(std::cmp::min(chunk_size, limit), 1);
+
+                match download(session, &found.destination, chunk_size)? {
+                    Ok(result) => chunks = Some(result),
+                    Err(error) => {
+                        summary.record(WalkError::new(found.path, error));
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    session.reply(Response {
+        stat: stat,
+        path: found.path,
+        matches: found.matches,
+        hash: hash,
+        chunks: chunks,
+    })
+}
+
+/// Computes the hash of the contents of the file at `path`.
+fn hash(path: &Path) -> std::io::Result<Hash> {
+    let mut file = File::open(path)?;
+
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; HASH_BUFFER_SIZE];
+    let mut size = 0;
+
+    loop {
+        let count = file.read(&mut buffer[..])?;
+        if count == 0 {
+            break;
+        }
+
+        hasher.input(&buffer[..count]);
+        size += count as u64;
+    }
+
+    Ok(Hash {
+        sha256: hasher.result().to_vec(),
+        size: size,
+    })
+}
+
+/// Sends the contents of the file at `path` to the transfer store.
+///
+/// The outer result fails only if sending the chunks fails, while the inner
+/// one carries errors encountered when reading the file.
+fn download<S>(session: &mut S, path: &Path, chunk_size: u64)
+               -> session::Result<std::io::Result<Vec<Chunk>>>
+where
+    S: Session,
+{
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(error) => return Ok(Err(error)),
+    };
+    let mut file = std::io::BufReader::new(file);
+
+    let mut chunks = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        let mut data = Vec::new();
+        if let Err(error) = (&mut file).take(chunk_size).read_to_end(&mut data) {
+            return Ok(Err(error));
+        }
+
+        if data.is_empty() {
+            break;
+        }
+
+        let length = data.len() as u64;
+        chunks.push(Chunk {
+            offset: offset,
+            length: length,
+            digest: Sha256::digest(&data).to_vec(),
+        });
+
+        session.send(session::Sink::TRANSFER_STORE, ChunkResponse { data })?;
+        session.heartbeat();
+
+        offset += length;
+    }
+
+    Ok(Ok(chunks))
+}
+
+impl super::Request for Request {
+
+    const RDF_NAME: Option<&'static str> = Some("FileFinderArgs");
+
+    type Proto = rrg_proto::rrg::FileFinderArgs;
+
+    fn from_proto(proto: rrg_proto::rrg::FileFinderArgs) -> Result<Request, ParseError> {
+        if let Some(pathtype) = proto.pathtype {
+            if pathtype != PathType::Os as i32 {
+                return Err(ParseError::malformed("only OS paths are supported"));
+            }
+        }
+
+        let paths = proto.paths.iter()
+            .map(|path| Pattern::parse(path))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut conditions = proto.conditions.into_iter()
+            .map(Condition::from_proto)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for condition in proto.mode_conditions {
+            conditions.push(Condition::from_mode_proto(condition)?);
+        }
+
+        let action = match proto.action {
+            Some(action) => Action::from_proto(action)?,
+            None => Action::Stat { collect_ext_attrs: false },
+        };
+
+        Ok(Request {
+            paths: paths,
+            conditions: conditions,
+            action: action,
+            follow_links: proto.follow_links.unwrap_or(false),
+            process_non_regular_files: proto.process_non_regular_files.unwrap_or(false),
+        })
+    }
+}
+
+impl Action {
+
+    /// Parses the action from its raw proto.
+    fn from_proto(proto: rrg_proto::FileFinderAction) -> Result<Action, ParseError> {
+        let action_type = proto.action_type.unwrap_or(ActionType::Stat as i32);
+
+        match ActionType::from_i32(action_type) {
+            Some(ActionType::Stat) => Ok(Action::Stat {
+                collect_ext_attrs: proto.stat
+                    .and_then(|stat| stat.collect_ext_attrs)
+                    .unwrap_or(false),
+            }),
+            Some(ActionType::Hash) => Ok(Action::Hash {
+                max_size: proto.hash
+                    .and_then(|hash| hash.max_size)
+                    .unwrap_or(DEFAULT_MAX_SIZE),
+            }),
+            Some(ActionType::Download) => {
+                let download = proto.download
+                    .ok_or(MissingFieldError::new("download options"))?;
+
+                Ok(Action::Download {
+                    max_size: download.max_size.unwrap_or(DEFAULT_MAX_SIZE),
+                    chunk_size: download.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE),
+                })
+            }
+            None => {
+                Err(ParseError::malformed(format!("unknown action type: {}", action_type)))
+            }
+        }
+    }
+}
+
+impl super::Response for Response {
+
+    const RDF_NAME: Option<&'static str> = Some("FileFinderResult");
+
+    type Proto = rrg_proto::rrg::FileFinderResult;
+
+    fn into_proto(self) -> rrg_proto::rrg::FileFinderResult {
+        let pathspec = rrg_proto::PathSpec {
+            pathtype: Some(PathType::Os as i32),
+            path: Some(self.path.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+
+        let matches = self.matches.into_iter()
+            .map(|hit| rrg_proto::BufferReference {
+                offset: Some(hit.offset),
+                length: Some(hit.data.len() as u64),
+                data: Some(hit.data),
+                pathspec: Some(pathspec.clone()),
+            })
+            .collect();
+
+        let hash_entry = self.hash.map(|hash| rrg_proto::Hash {
+            sha256: Some(hash.sha256),
+            num_bytes: Some(hash.size),
+            ..Default::default()
+        });
+
+        let transferred_file = self.chunks.map(|chunks| {
+            let chunk_size = chunks.first().map(|chunk| chunk.length);
+
+            rrg_proto::BlobImageDescriptor {
+                chunks: chunks.into_iter()
+                    .map(|chunk| rrg_proto::BlobImageChunkDescriptor {
+                        offset: Some(chunk.offset),
+                        length: Some(chunk.length),
+                        digest: Some(chunk.digest),
+                    })
+                    .collect(),
+                chunk_size: chunk_size,
+            }
+        });
+
+        rrg_proto::rrg::FileFinderResult {
+            stat_entry: Some(self.stat.into_proto()),
+            matches: matches,
+            hash_entry: hash_entry,
+            transferred_file: transferred_file,
+            match_data: None,
+        }
+    }
+
+    fn shrink(proto: Self::Proto, limit: usize) -> Option<Shrunk<Self::Proto>> {
+        shrink_matches(proto, limit)
+    }
+}
+
+/// A number of bytes reserved for the key and length of the stat entry field.
+const STAT_ENTRY_FIELD_RESERVE: usize = 8;
+
+/// Moves contents of matches of the result `proto` to the transfer store.
+///
+/// Matches keep their offsets and lengths, while their contents are described
+/// by the `match_data` blob image. If the result is still too large to fit in
+/// `limit` afterwards, extended attributes of its stat entry are truncated.
+fn shrink_matches(mut proto: rrg_proto::rrg::FileFinderResult, limit: usize)
+                  -> Option<Shrunk<rrg_proto::rrg::FileFinderResult>> {
+    use prost::Message as _;
+
+    let chunk_size = std::cmp::max(limit, 1) as u64;
+
+    let mut shrunk = Shrunk::new(rrg_proto::rrg::FileFinderResult::default());
+    let mut chunks = Vec::new();
+
+    for hit in proto.matches.iter_mut() {
+        let data = match hit.data.take() {
+            Some(data) => data,
+            None => continue,
+        };
+
+        let offset = hit.offset.unwrap_or(0);
+        let length = data.len() as u64;
+
+        let digests = shrunk.move_blob(data, limit);
+        for (index, digest) in digests.into_iter().enumerate() {
+            let start = index as u64 * chunk_size;
+
+            chunks.push(rrg_proto::BlobImageChunkDescriptor {
+                offset: Some(offset + start),
+                length: Some(std::cmp::min(chunk_size, length - start)),
+                digest: Some(digest),
+            });
+        }
+    }
+
+    if !chunks.is_empty() {
+        proto.match_data = Some(rrg_proto::BlobImageDescriptor {
+            chunks: chunks,
+            chunk_size: Some(chunk_size),
+        });
+    }
+
+    if proto.encoded_len() > limit {
+        let stat_entry = proto.stat_entry.take()?;
+
+        let used = proto.encoded_len() + STAT_ENTRY_FIELD_RESERVE;
+        let stat_entry = super::statentry::shrink_ext_attrs(stat_entry, limit.checked_sub(used)?)?;
+        proto.stat_entry = Some(stat_entry.proto);
+    }
+
+    shrunk.proto = proto;
+    Some(shrunk)
+}
+
+impl super::Response for ChunkResponse {
+
+    const RDF_NAME: Option<&'static str> = Some("DataBlob");
+
+    type Proto = rrg_proto::DataBlob;
+
+    fn into_proto(self) -> rrg_proto::DataBlob {
+        self.data.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs::{create_dir, write};
+
+    use super::*;
+    use crate::action::Request as _;
+
+    fn request(root: &Path, patterns: &[&str], action: Action) -> Request {
+        Request {
+            paths: patterns.iter()
+                .map(|pattern| Pattern::parse(&format!("{}/{}", root.display(), pattern)).unwrap())
+                .collect(),
+            conditions: vec![],
+            action: action,
+            follow_links: false,
+            process_non_regular_files: false,
+        }
+    }
+
+    const STAT: Action = Action::Stat { collect_ext_attrs: false };
+
+    #[test]
+    fn test_from_proto_default_action() {
+        let request = Request::from_proto(rrg_proto::rrg::FileFinderArgs {
+            paths: vec![String::from("/foo/**")],
+            ..Default::default()
+        }).unwrap();
+
+        assert_eq!(request.action, STAT);
+        assert_eq!(request.paths.len(), 1);
+    }
+
+    #[test]
+    fn test_from_proto_mode_conditions() {
+        let request = Request::from_proto(rrg_proto::rrg::FileFinderArgs {
+            paths: vec![String::from("/foo/**")],
+            mode_conditions: vec![rrg_proto::rrg::FileFinderModeCondition {
+                mask: Some(0o4000),
+                mode: Some(0o4000),
+            }],
+            ..Default::default()
+        }).unwrap();
+
+        assert_eq!(request.conditions, vec![Condition::Mode {
+            mask: 0o4000,
+            mode: 0o4000,
+        }]);
+    }
+
+    #[test]
+    fn test_from_proto_relative_path() {
+        let request = Request::from_proto(rrg_proto::rrg::FileFinderArgs {
+            paths: vec![String::from("foo")],
+            ..Default::default()
+        });
+
+        assert!(request.is_err());
+    }
+
+    #[test]
+    fn test_from_proto_download_without_options() {
+        let request = Request::from_proto(rrg_proto::rrg::FileFinderArgs {
+            paths: vec![String::from("/foo")],
+            action: Some(rrg_proto::FileFinderAction {
+                action_type: Some(ActionType::Download.into()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+
+        assert!(request.is_err());
+    }
+
+    #[test]
+    fn test_stat() {
+        let tempdir = tempfile::tempdir().unwrap();
+        write(tempdir.path().join("foo.txt"), b"foo").unwrap();
+        write(tempdir.path().join("bar.log"), b"bar").unwrap();
+
+        let request = request(tempdir.path(), &["*.txt"], STAT);
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request).is_ok());
+
+        assert_eq!(session.reply_count(), 1);
+
+        let response = session.reply::<Response>(0);
+        assert_eq!(response.path, tempdir.path().join("foo.txt"));
+        assert!(response.hash.is_none());
+    }
+
+    #[test]
+    fn test_duplicates_reported_once() {
+        let tempdir = tempfile::tempdir().unwrap();
+        write(tempdir.path().join("foo"), b"foo").unwrap();
+
+        let request = request(tempdir.path(), &["foo", "f*", "**1/foo"], STAT);
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request).is_ok());
+
+        assert_eq!(session.reply_count(), 1);
+    }
+
+    #[test]
+    fn test_conditions() {
+        let tempdir = tempfile::tempdir().unwrap();
+        create_dir(tempdir.path().join("dir")).unwrap();
+        write(tempdir.path().join("dir").join("small"), b"foo").unwrap();
+        write(tempdir.path().join("dir").join("large"), b"foobarbaz").unwrap();
+
+        let mut request = request(tempdir.path(), &["**"], STAT);
+        request.conditions.push(Condition::Size { min: 5, max: 100 });
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request).is_ok());
+
+        let paths = session.replies::<Response>()
+            .map(|response| response.path.clone())
+            .collect::<Vec<_>>();
+
+        assert_eq!(paths, vec![tempdir.path().join("dir").join("large")]);
+    }
+
+    #[test]
+    fn test_hash() {
+        let tempdir = tempfile::tempdir().unwrap();
+        write(tempdir.path().join("foo"), b"foo").unwrap();
+
+        let request = request(tempdir.path(), &["foo"], Action::Hash {
+            max_size: DEFAULT_MAX_SIZE,
+        });
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request).is_ok());
+
+        let hash = session.reply::<Response>(0).hash.as_ref().unwrap();
+        assert_eq!(hash.size, 3);
+        assert_eq!(hash.sha256, Sha256::digest(b"foo").to_vec());
+    }
+
+    #[test]
+    fn test_hash_oversized() {
+        let tempdir = tempfile::tempdir().unwrap();
+        write(tempdir.path().join("foo"), b"foobar").unwrap();
+
+        let request = request(tempdir.path(), &["foo"], Action::Hash {
+            max_size: 3,
+        });
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request).is_ok());
+
+        assert_eq!(session.reply_count(), 1);
+        assert!(session.reply::<Response>(0).hash.is_none());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_error_summary_permission_denied() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::fs::{set_permissions, Permissions};
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("foo");
+        write(&path, b"foo").unwrap();
+
+        set_permissions(&path, Permissions::from_mode(0o000)).unwrap();
+        // Permissions are not enforced for privileged users.
+        if File::open(&path).is_ok() {
+            return;
+        }
+
+        let request = request(tempdir.path(), &["foo"], Action::Hash {
+            max_size: DEFAULT_MAX_SIZE,
+        });
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request).is_ok());
+        assert_eq!(session.reply_count(), 0);
+
+        let summaries = session.error_summaries();
+        assert_eq!(summaries.len(), 1);
+
+        let counts = summaries[0].counts().collect::<Vec<_>>();
+        assert_eq!(counts, vec![(std::io::ErrorKind::PermissionDenied, 1)]);
+        assert_eq!(summaries[0].errors()[0].path(), path);
+    }
+
+    #[test]
+    fn test_download() {
+        let tempdir = tempfile::tempdir().unwrap();
+        write(tempdir.path().join("foo"), b"foobarbaz").unwrap();
+
+        let request = request(tempdir.path(), &["foo"], Action::Download {
+            max_size: DEFAULT_MAX_SIZE,
+            chunk_size: 4,
+        });
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request).is_ok());
+
+        let sink = session::Sink::TRANSFER_STORE;
+        let data = session.responses::<ChunkResponse>(sink)
+            .map(|chunk| chunk.data.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(data, vec![b"foob".to_vec(), b"arba".to_vec(), b"z".to_vec()]);
+
+        let chunks = session.reply::<Response>(0).chunks.as_ref().unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[1].offset, 4);
+        assert_eq!(chunks[2].length, 1);
+        assert_eq!(chunks[2].digest, Sha256::digest(b"z").to_vec());
+    }
+
+    #[test]
+    fn test_shrink_matches() {
+        use crate::action::Response as _;
+
+        let reference = |offset, data: &[u8]| rrg_proto::BufferReference {
+            offset: Some(offset),
+            length: Some(data.len() as u64),
+            data: Some(data.to_vec()),
+            pathspec: None,
+        };
+
+        let proto = rrg_proto::rrg::FileFinderResult {
+            stat_entry: Some(Default::default()),
+            matches: vec![
+                reference(0, &[0xf0; 1536]),
+                reference(4096, &[0x0f; 512]),
+            ],
+            ..Default::default()
+        };
+
+        let shrunk = Response::shrink(proto, 1024).unwrap();
+        assert!(shrunk.proto.matches.iter().all(|hit| hit.data.is_none()));
+        assert_eq!(shrunk.proto.matches[1].offset, Some(4096));
+        assert_eq!(shrunk.proto.matches[1].length, Some(512));
+
+        assert_eq!(shrunk.blobs, vec![
+            vec![0xf0; 1024],
+            vec![0xf0; 512],
+            vec![0x0f; 512],
+        ]);
+
+        let chunks = shrunk.proto.match_data.unwrap().chunks;
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[1].offset, Some(1024));
+        assert_eq!(chunks[1].length, Some(512));
+        assert_eq!(chunks[2].offset, Some(4096));
+        assert_eq!(chunks[2].digest, Some(Sha256::digest(&[0x0f; 512]).to_vec()));
+    }
+}
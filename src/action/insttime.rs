@@ -17,7 +17,7 @@ use crate::session::{self, Session};
 use std::{fs, path::Path};
 
 /// A response type for the install date action.
-struct Response {
+pub struct Response {
     /// Install date of the operating system, or `None` if the attemps to
     /// obtain install date failed.
     time: Option<SystemTime>,
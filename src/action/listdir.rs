@@ -7,15 +7,13 @@
 //!
 //! A list directory action stats all files in the provided directory.
 
+use crate::fs::{list_dir, ErrorSummary, WalkError};
 use crate::session::{self, Session};
-use rrg_proto::{ListDirRequest, StatEntry, path_spec::PathType,
-                path_spec::Options, micros};
+use rrg_proto::{ListDirRequest, path_spec::PathType, path_spec::Options};
 
-use std::fs::{self, Metadata};
-use std::path::{PathBuf, Path};
+use std::path::PathBuf;
 use std::fmt::{Display, Formatter};
-use log::warn;
-use std::time::SystemTime;
+use super::statentry::StatOptions;
 
 /// An error type used when some path can't be read. E.g. listed directory
 /// path or some inner file path.
@@ -121,169 +119,37 @@ impl From<ParseError> for session::ParseError {
 
 
 /// A response type for the list directory action.
-pub struct Response {
-    mode: Option<u64>,
-    ino: Option<u32>,
-    dev: Option<u32>,
-    nlink: Option<u32>,
-    uid: Option<u32>,
-    gid: Option<u32>,
-    size: u64,
-    atime: Option<SystemTime>,
-    mtime: Option<SystemTime>,
-    ctime: Option<SystemTime>,
-    blocks: Option<u32>,
-    blksize: Option<u32>,
-    rdev: Option<u32>,
-    flags_linux: Option<u32>,
-    symlink: Option<PathBuf>,
-    path: PathBuf,
-    crtime: Option<SystemTime>,
-}
-
-impl Default for Response {
-
-    fn default() -> Response {
-        Response {
-            mode: None,
-            ino: None,
-            dev: None,
-            nlink: None,
-            uid: None,
-            gid: None,
-            size: Default::default(),
-            atime: None,
-            mtime: None,
-            ctime: None,
-            blocks: None,
-            blksize: None,
-            rdev: None,
-            flags_linux: None,
-            symlink: None,
-            path: Default::default(),
-            crtime: None,
-        }
-    }
-}
+pub type Response = super::statentry::Entry;
 
 /// A request type for the list directory action.
 pub struct Request {
     path: PathBuf,
 }
 
-/// Returns the last access time of provided `Metadata`.
-fn get_access_time(metadata: &Metadata) -> Option<SystemTime> {
-    match metadata.accessed() {
-        Ok(atime) => Some(atime),
-        Err(err) => {
-            warn!("unable to get last access time: {}", err);
-            None
-        }
-    }
-}
-
-/// Returns the last modification time of provided `Metadata`.
-fn get_modification_time(metadata: &Metadata) -> Option<SystemTime> {
-    match metadata.modified() {
-        Ok(mtime) => Some(mtime),
-        Err(err) => {
-            warn!("unable to get last modification time: {}", err);
-            None
-        }
-    }
-}
-
-/// Returns the creation time of provided `Metadata`.
-fn get_creation_time(metadata: &Metadata) -> Option<SystemTime> {
-    match metadata.created() {
-        Ok(crtime) => Some(crtime),
-        Err(err) => {
-            warn!("unable to get creation time: {}", err);
-            None
-        }
-    }
-}
-
-/// Returns the last status change time of provided `Metadata`.
-#[cfg(target_os = "linux")]
-fn get_status_change_time(metadata: &Metadata) -> Option<SystemTime> {
-    use std::time::{Duration, UNIX_EPOCH};
-    use std::os::unix::fs::MetadataExt;
-
-    UNIX_EPOCH.checked_add(Duration::from_secs(metadata.ctime() as u64))
-}
-
-/// Reads a symbolic link, returning the path to the file that the link points to.
-#[cfg(target_os = "linux")]
-fn get_symlink(metadata: &Metadata, file_path: &Path) -> Option<PathBuf> {
-    if !metadata.file_type().is_symlink() { return None; }
+pub fn handle<S: Session>(session: &mut S, request: Request)
+                          -> session::Result<()> {
+    let mut summary = ErrorSummary::new();
 
-    match fs::read_link(file_path) {
-        Ok(file) => Some(file),
-        Err(error) => {
-            warn!("unable to read symlink: {}", error);
-            None
+    let mut paths = Vec::new();
+    for entry in list_dir(&request.path).map_err(Error::ReadPath)?.with_errors() {
+        match entry {
+            Ok(entry) => paths.push(entry.path),
+            Err(error) => summary.record(error),
         }
     }
-}
-
-/// Fills all fields of `Response` using path to the file.
-#[cfg(target_os = "linux")]
-fn fill_response(file_path: &Path) -> Result<Response, Error> {
-    use std::os::unix::fs::MetadataExt;
-    let metadata = fs::symlink_metadata(file_path)
-        .map_err(Error::ReadPath)?;
-
-    Ok(Response {
-        mode: Some(metadata.mode().into()),
-        ino: Some(metadata.ino() as u32),
-        dev: Some(metadata.dev() as u32),
-        nlink: Some(metadata.nlink() as u32),
-        uid: Some(metadata.uid() as u32),
-        gid: Some(metadata.gid() as u32),
-        size: metadata.size(),
-        atime: get_access_time(&metadata),
-        mtime: get_modification_time(&metadata),
-        ctime: get_status_change_time(&metadata),
-        blocks: Some(metadata.blocks() as u32),
-        blksize: Some(metadata.blksize() as u32),
-        rdev: Some(metadata.rdev() as u32),
-        flags_linux: get_linux_flags(file_path),
-        symlink: get_symlink(&metadata, file_path),
-        path: file_path.clone().to_path_buf(),
-        crtime: get_creation_time(&metadata),
-    })
-}
-
-/// Fills all fields of `Response` using path to the file.
-#[cfg(not(target_os = "linux"))]
-fn fill_response(file_path: &Path) -> Result<Response, Error> {
-    let metadata = fs::symlink_metadata(file_path)
-        .map_err(Error::ReadPath)?;
-
-    Ok(Response {
-        size: metadata.len(),
-        atime: get_access_time(&metadata),
-        mtime: get_modification_time(&metadata),
-        path: file_path.clone().to_path_buf(),
-        crtime: get_creation_time(&metadata),
-        ..Default::default()
-    })
-}
-
-pub fn handle<S: Session>(session: &mut S, request: Request)
-                          -> session::Result<()> {
-    let dir_path = &request.path;
-    let mut paths: Vec<PathBuf> = dir_path.read_dir()
-        .map_err(Error::ReadPath)?.filter_map(|entry| entry.ok())
-        .map(|entry| entry.path()).collect();
     paths.sort();
 
-    for file_path in &paths {
-        session.reply(fill_response(file_path)?)?;
+    // Files can disappear between listing the directory and reading their
+    // metadata, these are reported as skipped instead of failing the action.
+    let options = StatOptions::new().flags(true).btime(true);
+    for file_path in paths {
+        match options.stat(&file_path) {
+            Ok(response) => session.reply(response)?,
+            Err(error) => summary.record(WalkError::new(file_path, error)),
+        }
     }
 
-    Ok(())
+    session.report_errors(summary)
 }
 
 /// Constructs `PathBuf` from `String`. If provided string is empty constructs
@@ -295,29 +161,10 @@ fn get_path(path: &Option<String>) -> PathBuf {
     }
 }
 
-/// Fills `st_linux_flags` field.
-#[cfg(target_os = "linux")]
-fn get_linux_flags(path: &Path) -> Option<u32> {
-    use std::os::raw::c_long;
-    use std::fs::File;
-    use std::os::unix::io::AsRawFd;
-
-    let file = match File::open(path) {
-        Ok(file) => file,
-        Err(_) => return None,
-    };
-    let mut linux_flags: c_long = 0;
-    let linux_flags_ptr: *mut c_long = &mut linux_flags;
-    unsafe {
-        match ioctls::fs_ioc_getflags(file.as_raw_fd(), linux_flags_ptr) {
-            0 => Some(linux_flags as u32),
-            _ => None,
-        }
-    }
-}
-
 impl super::Request for Request {
 
+    const RDF_NAME: Option<&'static str> = Some("ListDirRequest");
+
     type Proto = ListDirRequest;
 
     fn from_proto(proto: Self::Proto) -> Result<Request, session::ParseError> {
@@ -341,69 +188,12 @@ impl super::Request for Request {
     }
 }
 
-/// Converts idiomatic `SystemTime` to `u64` for the protocol buffer.
-fn get_time_since_unix_epoch(sys_time: &Option<SystemTime>) -> Option<u64> {
-    match sys_time {
-        Some(time) => match micros(*time) {
-            Ok(time) => Some(time),
-            Err(error) => {
-                warn!("failed to convert time: {}", error);
-                None
-            }
-        }
-        None => None,
-    }
-}
-
-impl super::Response for Response {
-
-    const RDF_NAME: Option<&'static str> = Some("StatEntry");
-
-    type Proto = StatEntry;
-
-    fn into_proto(self) -> Self::Proto {
-        StatEntry {
-            st_mode: self.mode,
-            st_ino: self.ino,
-            st_dev: self.dev,
-            st_nlink: self.nlink,
-            st_uid: self.uid,
-            st_gid: self.gid,
-            st_size: Some(self.size),
-            st_atime: get_time_since_unix_epoch(&self.atime),
-            st_mtime: get_time_since_unix_epoch(&self.mtime),
-            st_ctime: get_time_since_unix_epoch(&self.ctime),
-            st_blocks: self.blocks,
-            st_blksize: self.blksize,
-            st_rdev: self.rdev,
-            st_flags_osx: None,
-            st_flags_linux: self.flags_linux,
-            symlink:
-            self.symlink
-                .map(|symlink| symlink.to_string_lossy().to_string()),
-            registry_type: None,
-            resident: None,
-            pathspec: Some(rrg_proto::PathSpec {
-                // Represents `CaseLiteral` path option (other options are not
-                // supported).
-                path_options: Some(Options::CaseLiteral as i32),
-                // Represents OS path type (other types are not supported).
-                pathtype: Some(PathType::Os as i32),
-                path: Some(self.path.to_string_lossy().to_string()),
-                ..Default::default()
-            }),
-            registry_data: None,
-            st_crtime: get_time_since_unix_epoch(&self.crtime),
-            ext_attrs: vec![],
-        }
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::action::Request;
     use tempfile::tempdir;
+    use std::time::SystemTime;
 
     #[cfg(target_os = "linux")]
     use std::os::unix::fs::MetadataExt;
@@ -502,6 +292,59 @@ mod tests {
         assert_eq!(session.reply_count(), 0);
     }
 
+    #[test]
+    fn test_error_summary() {
+        let dir = tempdir().unwrap();
+        std::fs::File::create(dir.path().join("file")).unwrap();
+
+        let request = super::Request {
+            path: PathBuf::from(dir.path()),
+        };
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request).is_ok());
+
+        let summaries = session.error_summaries();
+        assert_eq!(summaries.len(), 1);
+        assert!(summaries[0].is_empty());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_error_summary_permission_denied() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::fs::{set_permissions, Permissions};
+
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("file");
+        std::fs::File::create(&file_path).unwrap();
+
+        // Without the execute permission the directory can be listed, but the
+        // metadata of its entries cannot be read.
+        set_permissions(dir.path(), Permissions::from_mode(0o644)).unwrap();
+        // Permissions are not enforced for privileged users.
+        if std::fs::symlink_metadata(&file_path).is_ok() {
+            return;
+        }
+
+        let request = super::Request {
+            path: PathBuf::from(dir.path()),
+        };
+        let mut session = session::test::Fake::new();
+        let result = handle(&mut session, request);
+
+        set_permissions(dir.path(), Permissions::from_mode(0o755)).unwrap();
+
+        assert!(result.is_ok());
+        assert_eq!(session.reply_count(), 0);
+
+        let summaries = session.error_summaries();
+        assert_eq!(summaries.len(), 1);
+
+        let counts = summaries[0].counts().collect::<Vec<_>>();
+        assert_eq!(counts, vec![(std::io::ErrorKind::PermissionDenied, 1)]);
+        assert_eq!(summaries[0].errors()[0].path(), file_path);
+    }
+
     #[test]
     fn test_nonexistent_path() {
         let dir = tempdir().unwrap();
@@ -564,13 +407,13 @@ mod tests {
         assert_eq!(inner_dir.uid.unwrap(), users::get_current_uid());
         assert_eq!(inner_dir.gid.unwrap(), users::get_current_gid());
         assert_eq!(inner_dir.dev.unwrap(),
-                   dir_path.metadata().unwrap().dev() as u32);
+                   dir_path.metadata().unwrap().dev());
         assert_eq!(inner_dir.mode.unwrap() & 0o40000, 0o40000);
         assert_eq!(inner_dir.nlink.unwrap(), 2);
         assert!(inner_dir.atime.unwrap() <= SystemTime::now());
         assert!(inner_dir.ctime.unwrap() <= SystemTime::now());
         assert!(inner_dir.mtime.unwrap() <= SystemTime::now());
-        assert!(inner_dir.crtime.unwrap() <= SystemTime::now());
+        assert!(inner_dir.btime.unwrap() <= SystemTime::now());
     }
 
     #[test]
@@ -597,7 +440,7 @@ mod tests {
         assert!(symlink.atime.unwrap() <= SystemTime::now());
         assert!(symlink.ctime.unwrap() <= SystemTime::now());
         assert!(symlink.mtime.unwrap() <= SystemTime::now());
-        assert!(symlink.crtime.unwrap() <= SystemTime::now());
+        assert!(symlink.btime.unwrap() <= SystemTime::now());
     }
 
     #[test]
@@ -624,13 +467,13 @@ mod tests {
         assert_eq!(file.uid.unwrap(), users::get_current_uid());
         assert_eq!(file.gid.unwrap(), users::get_current_gid());
         assert_eq!(file.dev.unwrap(),
-                   dir_path.metadata().unwrap().dev() as u32);
+                   dir_path.metadata().unwrap().dev());
         assert_eq!(file.nlink.unwrap(), 1);
         assert!(file.symlink.is_none());
         assert!(file.atime.unwrap() <= SystemTime::now());
         assert!(file.ctime.unwrap() <= SystemTime::now());
         assert!(file.mtime.unwrap() <= SystemTime::now());
-        assert!(file.crtime.unwrap() <= SystemTime::now());
+        assert!(file.btime.unwrap() <= SystemTime::now());
     }
 
     #[test]
@@ -650,7 +493,7 @@ mod tests {
         assert_eq!(file.size, 0);
         assert!(file.atime.unwrap() <= SystemTime::now());
         assert!(file.mtime.unwrap() <= SystemTime::now());
-        assert!(file.crtime.unwrap() <= SystemTime::now());
+        assert!(file.btime.unwrap() <= SystemTime::now());
     }
 
     #[test]
@@ -671,13 +514,6 @@ mod tests {
         assert_ne!(file.flags_linux.unwrap(), 0);
     }
 
-    #[test]
-    #[cfg(target_os = "linux")]
-    fn test_linux_flags_non_existing_path() {
-        let path_buf = PathBuf::from("some non existing path");
-        assert!(get_linux_flags(&path_buf).is_none());
-    }
-
     #[test]
     fn test_unicode_paths() {
         let dir = tempdir().unwrap();
@@ -697,36 +533,26 @@ mod tests {
         assert_eq!(file.size, 0);
         assert!(file.atime.unwrap() <= SystemTime::now());
         assert!(file.mtime.unwrap() <= SystemTime::now());
-        assert!(file.crtime.unwrap() <= SystemTime::now());
+        assert!(file.btime.unwrap() <= SystemTime::now());
         let file = &session.reply::<Response>(1);
         assert_eq!(file.size, 0);
         assert!(file.atime.unwrap() <= SystemTime::now());
         assert!(file.mtime.unwrap() <= SystemTime::now());
-        assert!(file.crtime.unwrap() <= SystemTime::now());
+        assert!(file.btime.unwrap() <= SystemTime::now());
         let file = &session.reply::<Response>(2);
         assert_eq!(file.size, 0);
         assert!(file.atime.unwrap() <= SystemTime::now());
         assert!(file.mtime.unwrap() <= SystemTime::now());
-        assert!(file.crtime.unwrap() <= SystemTime::now());
+        assert!(file.btime.unwrap() <= SystemTime::now());
         let file = &session.reply::<Response>(3);
         assert_eq!(file.size, 0);
         assert!(file.atime.unwrap() <= SystemTime::now());
         assert!(file.mtime.unwrap() <= SystemTime::now());
-        assert!(file.crtime.unwrap() <= SystemTime::now());
+        assert!(file.btime.unwrap() <= SystemTime::now());
         let file = &session.reply::<Response>(4);
         assert_eq!(file.size, 0);
         assert!(file.atime.unwrap() <= SystemTime::now());
         assert!(file.mtime.unwrap() <= SystemTime::now());
-        assert!(file.crtime.unwrap() <= SystemTime::now());
-    }
-
-    #[test]
-    #[cfg(target_os = "linux")]
-    fn test_fill_response_nonexistent_path() {
-        let dir = tempdir().unwrap();
-        let nonexistent_path = PathBuf::from(dir.path()
-            .join("nonexistent_subdir"));
-        assert!(!nonexistent_path.exists());
-        assert!(fill_response(&nonexistent_path).is_err());
+        assert!(file.btime.unwrap() <= SystemTime::now());
     }
 }
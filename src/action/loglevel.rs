@@ -0,0 +1,102 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! A handler and associated types for the log level action.
+//!
+//! The log level action changes the verbosity of the agent logs at runtime,
+//! optionally restoring the previous verbosity after some time.
+
+use std::time::Duration;
+
+use log::LevelFilter;
+
+use crate::logging;
+use crate::opts::Verbosity;
+use crate::session::{self, Session};
+
+/// A request type for the log level action.
+#[derive(Debug)]
+pub struct Request {
+    /// A verbosity level to switch to.
+    level: LevelFilter,
+    /// A time after which the previous verbosity should be restored.
+    revert_after: Option<Duration>,
+}
+
+/// Handles requests for the log level action.
+pub fn handle<S: Session>(_: &mut S, request: Request) -> session::Result<()> {
+    logging::set_level(request.level, request.revert_after);
+
+    Ok(())
+}
+
+impl super::Request for Request {
+
+    const RDF_NAME: Option<&'static str> = Some("SetLogLevelArgs");
+
+    type Proto = rrg_proto::rrg::SetLogLevelArgs;
+
+    fn from_proto(proto: Self::Proto) -> Result<Request, session::ParseError> {
+        let level = proto.level
+            .ok_or(session::MissingFieldError::new("level"))?;
+
+        let verbosity = level.parse::<Verbosity>()
+            .map_err(session::ParseError::malformed)?;
+
+        Ok(Request {
+            level: verbosity.level(),
+            revert_after: proto.revert_after_us.map(Duration::from_micros),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::action::Request as _;
+
+    #[test]
+    fn test_from_proto() {
+        let request = Request::from_proto(rrg_proto::rrg::SetLogLevelArgs {
+            level: Some(String::from("debug")),
+            revert_after_us: Some(5_000_000),
+        }).unwrap();
+
+        assert_eq!(request.level, LevelFilter::Debug);
+        assert_eq!(request.revert_after, Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_from_proto_without_revert() {
+        let request = Request::from_proto(rrg_proto::rrg::SetLogLevelArgs {
+            level: Some(String::from("trace")),
+            revert_after_us: None,
+        }).unwrap();
+
+        assert_eq!(request.level, LevelFilter::Trace);
+        assert_eq!(request.revert_after, None);
+    }
+
+    #[test]
+    fn test_from_proto_missing_level() {
+        let request = Request::from_proto(rrg_proto::rrg::SetLogLevelArgs {
+            level: None,
+            revert_after_us: None,
+        });
+
+        assert!(request.is_err());
+    }
+
+    #[test]
+    fn test_from_proto_invalid_level() {
+        let request = Request::from_proto(rrg_proto::rrg::SetLogLevelArgs {
+            level: Some(String::from("loud")),
+            revert_after_us: None,
+        });
+
+        assert!(request.is_err());
+    }
+}
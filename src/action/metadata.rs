@@ -6,8 +6,9 @@
 //! A handler and associated types for the metadata action.
 //!
 //! The metadata action collects basic information about the client (e.g. its
-//! version number and its name).
+//! version number and its name) as well as the estimated skew of its clock.
 
+use crate::clock;
 use crate::metadata::{Metadata};
 use crate::session::{self, Session};
 
@@ -15,12 +16,15 @@ use crate::session::{self, Session};
 pub struct Response {
     /// Metadata about the RRG agent.
     metadata: Metadata,
+    /// Estimated skew of the agent clock (in microseconds), if known.
+    clock_skew_micros: Option<i64>,
 }
 
 /// Handles requests for the metadata action.
 pub fn handle<S: Session>(session: &mut S, _: ()) -> session::Result<()> {
     session.reply(Response {
         metadata: Metadata::from_cargo(),
+        clock_skew_micros: clock::skew_micros(),
     })?;
 
     Ok(())
@@ -30,10 +34,21 @@ impl super::Response for Response {
 
     const RDF_NAME: Option<&'static str> = Some("ClientInformation");
 
-    type Proto = rrg_proto::ClientInformation;
-
-    fn into_proto(self) -> rrg_proto::ClientInformation {
-        self.metadata.into()
+    type Proto = rrg_proto::rrg::ClientInformation;
+
+    fn into_proto(self) -> rrg_proto::rrg::ClientInformation {
+        let info: rrg_proto::ClientInformation = self.metadata.into();
+
+        rrg_proto::rrg::ClientInformation {
+            client_name: info.client_name,
+            client_version: info.client_version,
+            revision: info.revision,
+            build_time: info.build_time,
+            client_binary_name: info.client_binary_name,
+            client_description: info.client_description,
+            labels: info.labels,
+            clock_skew_us: self.clock_skew_micros,
+        }
     }
 }
 
@@ -14,10 +14,16 @@
 //! Protocol Buffer messages sent by and to the GRR server. Handlers accept one
 //! instance of the corresponding request type and send some (zero or more)
 //! instances of the corresponding response type.
+//!
+//! Handlers are made available to the agent through the action [`Registry`].
+//!
+//! [`Registry`]: registry/struct.Registry.html
 
 #[cfg(target_os = "linux")]
 pub mod filesystems;
 
+pub mod finder;
+
 #[cfg(target_family = "unix")]
 pub mod interfaces;
 
@@ -27,10 +33,15 @@ pub mod listdir;
 pub mod timeline;
 pub mod network;
 pub mod stat;
+pub mod statentry;
 pub mod insttime;
 pub mod memsize;
+pub mod loglevel;
+pub mod registry;
 
-use crate::session::{self, Session, Task};
+pub use self::registry::Registry;
+
+use crate::session;
 
 /// Abstraction for action-specific requests.
 ///
@@ -40,6 +51,12 @@ use crate::session::{self, Session, Task};
 /// be able to parse raw messages into them.
 pub trait Request: Sized {
 
+    /// A name of the corresponding RDF class.
+    ///
+    /// Demands carrying arguments of a different class are rejected before
+    /// being parsed.
+    const RDF_NAME: Option<&'static str>;
+
     /// A type of the corresponding raw proto message.
     type Proto: prost::Message + Default;
 
@@ -70,10 +87,61 @@ pub trait Response: Sized {
 
     /// A method for converting structured responses into raw proto messages.
     fn into_proto(self) -> Self::Proto;
+
+    /// A method for shrinking raw proto messages that are too large to be sent.
+    ///
+    /// It is called by sessions for messages exceeding `limit` bytes once
+    /// serialized. Responses that can be chunked should override it: blobs
+    /// should be moved out to the transfer store and referenced by digests,
+    /// lists should be truncated and flagged as such.
+    ///
+    /// By default, responses cannot be shrunk and `None` is returned.
+    fn shrink(_proto: Self::Proto, _limit: usize) -> Option<Shrunk<Self::Proto>> {
+        None
+    }
+}
+
+/// A raw proto message shrunk to fit into the message size limit.
+pub struct Shrunk<P> {
+    /// The shrunk proto message.
+    pub proto: P,
+    /// Blobs moved out of the message that should go to the transfer store.
+    pub blobs: Vec<Vec<u8>>,
+}
+
+impl<P> Shrunk<P> {
+
+    /// Wraps the `proto` message that has been shrunk without moving any blobs.
+    pub fn new(proto: P) -> Shrunk<P> {
+        Shrunk {
+            proto: proto,
+            blobs: Vec::new(),
+        }
+    }
+
+    /// Moves the `data` out of the message as blobs of at most `limit` bytes.
+    ///
+    /// All the blobs except for the last one are exactly `limit` bytes long.
+    ///
+    /// SHA-256 digests of the blobs (in order) are returned, so that the message
+    /// can refer to them.
+    pub fn move_blob(&mut self, data: Vec<u8>, limit: usize) -> Vec<Vec<u8>> {
+        use sha2::{Digest, Sha256};
+
+        let mut digests = Vec::new();
+        for chunk in data.chunks(std::cmp::max(limit, 1)) {
+            digests.push(Sha256::digest(chunk).to_vec());
+            self.blobs.push(chunk.to_vec());
+        }
+
+        digests
+    }
 }
 
 impl Request for () {
 
+    const RDF_NAME: Option<&'static str> = None;
+
     type Proto = ();
 
     fn from_proto(unit: ()) -> Result<(), session::ParseError> {
@@ -90,35 +158,3 @@ impl Response for () {
     fn into_proto(self) {
     }
 }
-
-/// Dispatches `task` to a handler appropriate for the given `action`.
-///
-/// This method is a mapping between action names (as specified in the protocol)
-/// and action handlers (implemented on the agent).
-///
-/// If the given action is unknown (or not yet implemented), this function will
-/// return an error.
-pub fn dispatch<'s, S>(action: &str, task: Task<'s, S>) -> session::Result<()>
-where
-    S: Session,
-{
-    match action {
-        "SendStartupInfo" => task.execute(self::startup::handle),
-        "GetClientInfo" => task.execute(self::metadata::handle),
-        "ListDirectory" => task.execute(self::listdir::handle), 
-        "Timeline" => task.execute(self::timeline::handle),
-        "ListNetworkConnections" => task.execute(self::network::handle),
-        "GetFileStat" => task.execute(self::stat::handle),
-        "GetInstallDate" => task.execute(self::insttime::handle),
-
-        #[cfg(target_family = "unix")]
-        "EnumerateInterfaces" => task.execute(self::interfaces::handle),
-
-        #[cfg(target_os = "linux")]
-        "EnumerateFilesystems" => task.execute(self::filesystems::handle),
-
-
-        "GetMemorySize" => task.execute(self::memsize::handle),
-        action => return Err(session::Error::Dispatch(String::from(action))),
-    }
-}
@@ -159,6 +159,8 @@ fn make_connection_from_socket_info(
 
 impl super::Request for Request {
 
+    const RDF_NAME: Option<&'static str> = Some("ListNetworkConnectionsArgs");
+
     type Proto = ListNetworkConnectionsArgs;
 
     fn from_proto(proto: Self::Proto) -> Result<Request, session::ParseError> {
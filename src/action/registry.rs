@@ -0,0 +1,382 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! A registry of actions that the agent is able to execute.
+//!
+//! Every action registers its name, a short description, platforms it supports
+//! and its handler. The RDF classes of action requests and responses are
+//! recorded as well, so the registry can be introspected (e.g. to inform the
+//! server about the capabilities of the agent).
+//!
+//! Apart from the built-in actions, downstream crates can register their own
+//! ones and then run the agent with the extended registry.
+
+use std::collections::BTreeMap;
+
+use log::debug;
+
+use crate::session::{self, Session, Task};
+
+/// A platform that actions can be supported on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Platform {
+    /// The Linux operating system.
+    Linux,
+    /// The macOS operating system.
+    Macos,
+    /// The Windows operating system.
+    Windows,
+}
+
+impl Platform {
+
+    /// All the platforms supported by the agent.
+    pub const ALL: &'static [Platform] = &[
+        Platform::Linux, Platform::Macos, Platform::Windows,
+    ];
+
+    /// All the Unix-like platforms supported by the agent.
+    pub const UNIX: &'static [Platform] = &[
+        Platform::Linux, Platform::Macos,
+    ];
+
+    /// Returns the platform the agent is currently running on.
+    ///
+    /// If the agent runs on an operating system that is not one of the known
+    /// platforms, `None` is returned.
+    pub fn current() -> Option<Platform> {
+        if cfg!(target_os = "linux") {
+            Some(Platform::Linux)
+        } else if cfg!(target_os = "macos") {
+            Some(Platform::Macos)
+        } else if cfg!(target_os = "windows") {
+            Some(Platform::Windows)
+        } else {
+            None
+        }
+    }
+
+    /// Checks whether an action supported on `platforms` should be available.
+    ///
+    /// On unknown platforms (`current` is `None`) only actions supported on all
+    /// the known platforms are assumed to be available.
+    fn supports(current: Option<Platform>, platforms: &[Platform]) -> bool {
+        match current {
+            Some(platform) => platforms.contains(&platform),
+            None => Platform::ALL.iter().all(|platform| platforms.contains(platform)),
+        }
+    }
+
+    /// Yields a human-friendly name of the platform.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Platform::Linux => "linux",
+            Platform::Macos => "macos",
+            Platform::Windows => "windows",
+        }
+    }
+}
+
+/// Information about an action provided upon its registration.
+#[derive(Clone, Copy, Debug)]
+pub struct Info {
+    /// A name of the action (as specified in the protocol).
+    pub name: &'static str,
+    /// A short, human-friendly description of the action.
+    pub description: &'static str,
+    /// Platforms the action is supported on.
+    pub platforms: &'static [Platform],
+}
+
+/// Full metadata about a registered action.
+#[derive(Clone, Copy, Debug)]
+pub struct Metadata {
+    /// Information provided upon the action registration.
+    pub info: Info,
+    /// A name of the RDF class of action requests (if the action has any).
+    pub request: Option<&'static str>,
+    /// A name of the RDF class of action responses (if the action has any).
+    pub response: Option<&'static str>,
+}
+
+impl Into<rrg_proto::rrg::ActionInfo> for Metadata {
+
+    fn into(self) -> rrg_proto::rrg::ActionInfo {
+        let platforms = self.info.platforms.iter()
+            .map(|platform| String::from(platform.name()))
+            .collect();
+
+        rrg_proto::rrg::ActionInfo {
+            name: Some(String::from(self.info.name)),
+            description: Some(String::from(self.info.description)),
+            platforms: platforms,
+            request_type: self.request.map(String::from),
+            response_type: self.response.map(String::from),
+        }
+    }
+}
+
+/// A type-erased action handler.
+///
+/// Handlers are given the registry they are dispatched from, so that actions
+/// such as the startup one can report what the registry supports.
+type Handler<S> = Box<dyn for<'s> Fn(&Registry<S>, Task<'s, S>) -> session::Result<()>>;
+
+/// A single registered action.
+struct Entry<S: Session> {
+    /// Metadata about the action.
+    metadata: Metadata,
+    /// A handler of the action.
+    handler: Handler<S>,
+}
+
+/// A registry of actions executable with sessions of type `S`.
+pub struct Registry<S: Session> {
+    /// Registered actions keyed by their names.
+    entries: BTreeMap<&'static str, Entry<S>>,
+}
+
+impl<S: Session + 'static> Registry<S> {
+
+    /// Creates a new registry without any actions.
+    pub fn new() -> Registry<S> {
+        Registry {
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// Creates a new registry with all the built-in actions.
+    pub fn builtin() -> Registry<S> {
+        use super::*;
+
+        let mut registry = Registry::new();
+
+        registry.insert(Metadata {
+            info: Info {
+                name: "SendStartupInfo",
+                description: "Collects basic information about the agent startup.",
+                platforms: Platform::ALL,
+            },
+            request: None,
+            response: <startup::Response as super::Response>::RDF_NAME,
+        }, Box::new(|registry, task| task.execute(|session, ()| {
+            startup::handle(session, registry.actions().copied().collect())
+        })));
+
+        registry.register::<_, metadata::Response, _>(Info {
+            name: "GetClientInfo",
+            description: "Collects metadata about the agent.",
+            platforms: Platform::ALL,
+        }, metadata::handle);
+
+        registry.register::<_, listdir::Response, _>(Info {
+            name: "ListDirectory",
+            description: "Lists the contents of a directory.",
+            platforms: Platform::ALL,
+        }, listdir::handle);
+
+        registry.register::<_, timeline::Response, _>(Info {
+            name: "Timeline",
+            description: "Collects metadata of all files in a directory tree.",
+            platforms: Platform::ALL,
+        }, timeline::handle);
+
+        registry.register::<_, network::Response, _>(Info {
+            name: "ListNetworkConnections",
+            description: "Lists open network connections.",
+            platforms: Platform::ALL,
+        }, network::handle);
+
+        registry.register::<_, stat::Response, _>(Info {
+            name: "GetFileStat",
+            description: "Collects metadata of a single file.",
+            platforms: Platform::ALL,
+        }, stat::handle);
+
+        registry.register::<_, insttime::Response, _>(Info {
+            name: "GetInstallDate",
+            description: "Estimates the installation date of the system.",
+            platforms: Platform::ALL,
+        }, insttime::handle);
+
+        #[cfg(target_family = "unix")]
+        registry.register::<_, interfaces::Response, _>(Info {
+            name: "EnumerateInterfaces",
+            description: "Lists network interfaces of the system.",
+            platforms: Platform::UNIX,
+        }, interfaces::handle);
+
+        #[cfg(target_os = "linux")]
+        registry.register::<_, filesystems::Response, _>(Info {
+            name: "EnumerateFilesystems",
+            description: "Lists mounted filesystems.",
+            platforms: &[Platform::Linux],
+        }, filesystems::handle);
+
+        registry.register::<_, finder::Response, _>(Info {
+            name: "FileFinder",
+            description: "Finds files matching glob patterns and conditions.",
+            platforms: Platform::ALL,
+        }, finder::handle);
+
+        registry.register::<_, memsize::Response, _>(Info {
+            name: "GetMemorySize",
+            description: "Retrieves the size of the system memory.",
+            platforms: Platform::ALL,
+        }, memsize::handle);
+
+        registry.register::<_, (), _>(Info {
+            name: "SetLogLevel",
+            description: "Changes the verbosity of the agent logs.",
+            platforms: Platform::ALL,
+        }, loglevel::handle);
+
+        registry
+    }
+
+    /// Registers a new action with the given `info` and `handler`.
+    ///
+    /// The `Req` and `Resp` type parameters specify the request and response
+    /// types of the action. If the action is not supported on the current
+    /// platform, it is not registered. On platforms that are not one of the
+    /// known ones, only actions supported on all of them are registered.
+    /// Registering an action with a name of an already registered one replaces
+    /// it.
+    pub fn register<Req, Resp, H>(&mut self, info: Info, handler: H)
+    where
+        Req: super::Request,
+        Resp: super::Response,
+        H: Fn(&mut S, Req) -> session::Result<()> + 'static,
+    {
+        let metadata = Metadata {
+            info: info,
+            request: Req::RDF_NAME,
+            response: Resp::RDF_NAME,
+        };
+
+        self.insert(metadata, Box::new(move |_, task| task.execute(&handler)));
+    }
+
+    /// Registers an action with the given `metadata` and type-erased `handler`.
+    fn insert(&mut self, metadata: Metadata, handler: Handler<S>) {
+        let info = metadata.info;
+        if !Platform::supports(Platform::current(), info.platforms) {
+            debug!("the '{}' action is not supported on this platform", info.name);
+            return;
+        }
+
+        self.entries.insert(info.name, Entry {
+            metadata: metadata,
+            handler: handler,
+        });
+    }
+
+    /// Dispatches `task` to a handler appropriate for the given `action`.
+    ///
+    /// If the given action is not registered, this method will return an error.
+    pub fn dispatch<'s>(&self, action: &str, task: Task<'s, S>) -> session::Result<()> {
+        match self.entries.get(action) {
+            Some(entry) => (entry.handler)(self, task),
+            None => Err(session::Error::Dispatch(String::from(action))),
+        }
+    }
+
+    /// Returns an iterator over metadata of all the registered actions.
+    pub fn actions(&self) -> impl Iterator<Item = &Metadata> {
+        self.entries.values().map(|entry| &entry.metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::session::test::Fake;
+    use crate::session::Payload;
+
+    fn task(session: &mut Fake) -> Task<'_, Fake> {
+        Task {
+            session: session,
+            payload: Payload {
+                data: None,
+                rdf_name: None,
+                compressed: false,
+            },
+        }
+    }
+
+    #[test]
+    fn test_builtin_actions() {
+        let registry = Registry::<Fake>::builtin();
+
+        let listdir = registry.actions()
+            .find(|metadata| metadata.info.name == "ListDirectory")
+            .unwrap();
+
+        assert_eq!(listdir.request, Some("ListDirRequest"));
+        assert_eq!(listdir.response, Some("StatEntry"));
+    }
+
+    #[test]
+    fn test_dispatch_unknown() {
+        let registry = Registry::<Fake>::builtin();
+
+        let mut session = Fake::new();
+        match registry.dispatch("Foo", task(&mut session)) {
+            Err(session::Error::Dispatch(name)) => assert_eq!(name, "Foo"),
+            _ => panic!("unexpected dispatch result"),
+        }
+    }
+
+    #[test]
+    fn test_register_custom() {
+        let mut registry = Registry::<Fake>::new();
+        registry.register::<(), (), _>(Info {
+            name: "Custom",
+            description: "Replies with nothing.",
+            platforms: Platform::ALL,
+        }, |session: &mut Fake, ()| session.reply(()));
+
+        let mut session = Fake::new();
+        assert!(registry.dispatch("Custom", task(&mut session)).is_ok());
+        assert_eq!(session.reply_count(), 1);
+
+        let metadata = registry.actions().next().unwrap();
+        assert_eq!(metadata.info.name, "Custom");
+        assert_eq!(metadata.request, None);
+        assert_eq!(metadata.response, None);
+    }
+
+    #[test]
+    fn test_register_unsupported_platform() {
+        let platforms: &'static [Platform] = match Platform::current() {
+            Some(Platform::Windows) => &[Platform::Linux],
+            _ => &[Platform::Windows],
+        };
+
+        let mut registry = Registry::<Fake>::new();
+        registry.register::<(), (), _>(Info {
+            name: "Unsupported",
+            description: "Does nothing.",
+            platforms: platforms,
+        }, |_: &mut Fake, ()| Ok(()));
+
+        assert_eq!(registry.actions().count(), 0);
+    }
+
+    #[test]
+    fn test_unknown_platform_supports_all() {
+        assert!(Platform::supports(None, Platform::ALL));
+        assert!(!Platform::supports(None, Platform::UNIX));
+        assert!(Platform::supports(Some(Platform::Linux), Platform::UNIX));
+        assert!(!Platform::supports(Some(Platform::Windows), Platform::UNIX));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_current_platform() {
+        assert_eq!(Platform::current(), Some(Platform::Linux));
+    }
+}
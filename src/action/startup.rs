@@ -14,6 +14,9 @@ use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use log::error;
 
+use crate::action::registry;
+use crate::clock;
+use crate::crash;
 use crate::metadata::{Metadata};
 use crate::session::{self, Session};
 
@@ -23,13 +26,25 @@ pub struct Response {
     boot_time: SystemTime,
     /// Metadata about the RRG agent.
     metadata: Metadata,
+    /// Details of the crash that ended the previous run of the agent (if any).
+    previous_crash: Option<rrg_proto::ClientCrash>,
+    /// Actions supported by the agent.
+    actions: Vec<registry::Metadata>,
+    /// Estimated skew of the agent clock (in microseconds), if known.
+    clock_skew_micros: Option<i64>,
 }
 
 /// Handles requests for the startup action.
-pub fn handle<S: Session>(session: &mut S, _: ()) -> session::Result<()> {
+///
+/// The `actions` are metadata of actions supported by the agent, as reported
+/// by the registry used to serve requests.
+pub fn handle<S: Session>(session: &mut S, actions: Vec<registry::Metadata>) -> session::Result<()> {
     session.send(session::Sink::STARTUP, Response {
         boot_time: boot_time(),
         metadata: Metadata::from_cargo(),
+        previous_crash: crash::previous(),
+        actions: actions,
+        clock_skew_micros: clock::skew_micros(),
     })?;
 
     Ok(())
@@ -47,9 +62,9 @@ impl super::Response for Response {
 
     const RDF_NAME: Option<&'static str> = Some("StartupInfo");
 
-    type Proto = rrg_proto::StartupInfo;
+    type Proto = rrg_proto::rrg::StartupInfo;
 
-    fn into_proto(self) -> rrg_proto::StartupInfo {
+    fn into_proto(self) -> rrg_proto::rrg::StartupInfo {
         let boot_time_micros = match rrg_proto::micros(self.boot_time) {
             Ok(boot_time_micros) => boot_time_micros,
             Err(error) => {
@@ -58,9 +73,12 @@ impl super::Response for Response {
             }
         };
 
-        rrg_proto::StartupInfo {
+        rrg_proto::rrg::StartupInfo {
             client_info: Some(self.metadata.into()),
             boot_time: Some(boot_time_micros),
+            previous_crash: self.previous_crash,
+            actions: self.actions.into_iter().map(Into::into).collect(),
+            clock_skew_us: self.clock_skew_micros,
         }
     }
 }
@@ -73,7 +91,7 @@ mod tests {
     #[test]
     fn test_boot_time() {
         let mut session = session::test::Fake::new();
-        assert!(handle(&mut session, ()).is_ok());
+        assert!(handle(&mut session, vec![]).is_ok());
 
         assert_eq!(session.reply_count(), 0);
         assert_eq!(session.response_count(session::Sink::STARTUP), 1);
@@ -86,7 +104,7 @@ mod tests {
     #[test]
     fn test_metadata() {
         let mut session = session::test::Fake::new();
-        assert!(handle(&mut session, ()).is_ok());
+        assert!(handle(&mut session, vec![]).is_ok());
 
         assert_eq!(session.reply_count(), 0);
         assert_eq!(session.response_count(session::Sink::STARTUP), 1);
@@ -95,4 +113,31 @@ mod tests {
         assert!(response.metadata.version.as_numeric() > 0);
         assert_eq!(response.metadata.name, "rrg");
     }
+
+    #[test]
+    fn test_dispatch_reports_registered_actions() {
+        use crate::action::registry::{Info, Platform, Registry};
+        use crate::session::{test::Fake, Payload, Task};
+
+        let mut registry = Registry::<Fake>::builtin();
+        registry.register::<(), (), _>(Info {
+            name: "Custom",
+            description: "Does nothing.",
+            platforms: Platform::ALL,
+        }, |_: &mut Fake, ()| Ok(()));
+
+        let mut session = Fake::new();
+        let result = registry.dispatch("SendStartupInfo", Task {
+            session: &mut session,
+            payload: Payload {
+                data: None,
+                rdf_name: None,
+                compressed: false,
+            },
+        });
+        assert!(result.is_ok());
+
+        let response = session.response::<Response>(session::Sink::STARTUP, 0);
+        assert!(response.actions.iter().any(|metadata| metadata.info.name == "Custom"));
+    }
 }
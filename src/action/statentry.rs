@@ -0,0 +1,729 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Utilities for collecting metadata of filesystem entries.
+//!
+//! Several actions (e.g. listing a directory or stat-ing a single file) report
+//! metadata of files as `StatEntry` messages. All of them build these messages
+//! here, so that the reported information is complete and consistent no matter
+//! which action collected it.
+
+use std::fs::Metadata;
+use std::io::{ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use log::warn;
+use rrg_proto::path_spec::{Options, PathType};
+
+use super::Shrunk;
+
+/// Options specifying what metadata of an entry to collect.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::path::Path;
+///
+/// use rrg::action::statentry::StatOptions;
+///
+/// let entry = StatOptions::new()
+///     .flags(true)
+///     .btime(true)
+///     .stat(Path::new("/etc/passwd"))
+///     .unwrap();
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct StatOptions {
+    ext_attrs: bool,
+    flags: bool,
+    btime: bool,
+    follow_symlink: bool,
+}
+
+impl StatOptions {
+
+    /// Creates default options.
+    ///
+    /// By default only the basic metadata is collected and symlinks are not
+    /// followed.
+    pub fn new() -> StatOptions {
+        StatOptions {
+            ext_attrs: false,
+            flags: false,
+            btime: false,
+            follow_symlink: false,
+        }
+    }
+
+    /// Makes extended attributes of the entry collected.
+    pub fn ext_attrs(mut self, ext_attrs: bool) -> StatOptions {
+        self.ext_attrs = ext_attrs;
+        self
+    }
+
+    /// Makes file attribute flags (as in `lsattr` or `chflags`) collected.
+    ///
+    /// On Linux, obtaining the flags requires opening the file, so they are
+    /// collected only for regular files and directories (e.g. opening a FIFO
+    /// could block forever).
+    pub fn flags(mut self, flags: bool) -> StatOptions {
+        self.flags = flags;
+        self
+    }
+
+    /// Makes the birth (creation) time of the entry collected.
+    ///
+    /// Not all platforms and filesystems keep track of the birth time. If it is
+    /// not available, it is simply not reported.
+    pub fn btime(mut self, btime: bool) -> StatOptions {
+        self.btime = btime;
+        self
+    }
+
+    /// Makes metadata of the symlink target collected instead of the symlink.
+    ///
+    /// Chains of symlinks are resolved completely, but the entry still reports
+    /// the original path and the immediate target of the symlink.
+    pub fn follow_symlink(mut self, follow_symlink: bool) -> StatOptions {
+        self.follow_symlink = follow_symlink;
+        self
+    }
+
+    /// Collects metadata of the entry at `path`.
+    pub fn stat(&self, path: &Path) -> Result<Entry> {
+        let destination = if self.follow_symlink {
+            std::fs::canonicalize(path)?
+        } else {
+            path.to_path_buf()
+        };
+
+        let metadata = std::fs::symlink_metadata(&destination)?;
+
+        let mut entry = Entry::from_metadata(path.to_path_buf(), &metadata);
+        if self.follow_symlink || metadata.file_type().is_symlink() {
+            entry.symlink = symlink_target(path);
+        }
+        if self.btime {
+            entry.btime = metadata.created().ok();
+        }
+        if self.flags {
+            entry.collect_flags(&metadata, &destination);
+        }
+        if self.ext_attrs {
+            entry.ext_attrs = ext_attrs(&destination);
+        }
+
+        Ok(entry)
+    }
+}
+
+/// Metadata of a filesystem entry.
+///
+/// Fields that are not available on the current platform (or have not been
+/// requested) are not set.
+#[derive(Debug)]
+pub struct Entry {
+    /// A path of the entry (as requested, i.e. with symlinks unresolved).
+    pub(super) path: PathBuf,
+    /// A mode of the entry (both its type and permissions).
+    pub(super) mode: Option<u64>,
+    /// An inode number of the entry.
+    pub(super) ino: Option<u64>,
+    /// An identifier of the device the entry resides on.
+    pub(super) dev: Option<u64>,
+    /// A number of hard links to the entry.
+    pub(super) nlink: Option<u64>,
+    /// An identifier of the user owning the entry.
+    pub(super) uid: Option<u32>,
+    /// An identifier of the group owning the entry.
+    pub(super) gid: Option<u32>,
+    /// A size of the entry (in bytes).
+    pub(super) size: u64,
+    /// Time of the last access.
+    pub(super) atime: Option<SystemTime>,
+    /// Time of the last modification.
+    pub(super) mtime: Option<SystemTime>,
+    /// Time of the last status change.
+    pub(super) ctime: Option<SystemTime>,
+    /// Time of the birth (creation).
+    pub(super) btime: Option<SystemTime>,
+    /// A number of blocks allocated for the entry.
+    pub(super) blocks: Option<u64>,
+    /// A preferred block size for filesystem I/O.
+    pub(super) blksize: Option<u64>,
+    /// An identifier of the device the entry represents (if it is special).
+    pub(super) rdev: Option<u64>,
+    /// File attribute flags on Linux.
+    pub(super) flags_linux: Option<u32>,
+    /// File attribute flags on macOS.
+    pub(super) flags_osx: Option<u32>,
+    /// An immediate target of the symlink (if the entry is a symlink).
+    pub(super) symlink: Option<PathBuf>,
+    /// Extended attributes of the entry.
+    pub(super) ext_attrs: Vec<ExtAttr>,
+}
+
+/// An extended attribute of a filesystem entry.
+#[derive(Debug)]
+pub struct ExtAttr {
+    /// A name of the attribute.
+    pub(super) name: Vec<u8>,
+    /// A value of the attribute.
+    pub(super) value: Vec<u8>,
+}
+
+impl Entry {
+
+    /// Creates an entry with basic metadata of the entry at `path`.
+    #[cfg(target_family = "unix")]
+    fn from_metadata(path: PathBuf, metadata: &Metadata) -> Entry {
+        use std::os::unix::fs::MetadataExt;
+
+        Entry {
+            path: path,
+            mode: Some(metadata.mode().into()),
+            ino: Some(metadata.ino()),
+            dev: Some(metadata.dev()),
+            nlink: Some(metadata.nlink()),
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+            size: metadata.size(),
+            atime: time(metadata.accessed(), "access"),
+            mtime: time(metadata.modified(), "modification"),
+            ctime: status_change_time(metadata),
+            btime: None,
+            blocks: Some(metadata.blocks()),
+            blksize: Some(metadata.blksize()),
+            rdev: Some(metadata.rdev()),
+            flags_linux: None,
+            flags_osx: None,
+            symlink: None,
+            ext_attrs: vec![],
+        }
+    }
+
+    /// Creates an entry with basic metadata of the entry at `path`.
+    #[cfg(not(target_family = "unix"))]
+    fn from_metadata(path: PathBuf, metadata: &Metadata) -> Entry {
+        Entry {
+            path: path,
+            mode: None,
+            ino: None,
+            dev: None,
+            nlink: None,
+            uid: None,
+            gid: None,
+            size: metadata.len(),
+            atime: time(metadata.accessed(), "access"),
+            mtime: time(metadata.modified(), "modification"),
+            ctime: None,
+            btime: None,
+            blocks: None,
+            blksize: None,
+            rdev: None,
+            flags_linux: None,
+            flags_osx: None,
+            symlink: None,
+            ext_attrs: vec![],
+        }
+    }
+
+    /// Collects file attribute flags of the entry at `path`.
+    #[cfg(target_os = "linux")]
+    fn collect_flags(&mut self, metadata: &Metadata, path: &Path) {
+        if !metadata.is_file() && !metadata.is_dir() {
+            return;
+        }
+
+        match linux_flags(path) {
+            Ok(flags) => self.flags_linux = Some(flags),
+            Err(error) => warn!("unable to get flags of '{}': {}", path.display(), error),
+        }
+    }
+
+    /// Collects file attribute flags of the entry at `path`.
+    #[cfg(target_os = "macos")]
+    fn collect_flags(&mut self, metadata: &Metadata, _path: &Path) {
+        use std::os::macos::fs::MetadataExt;
+        self.flags_osx = Some(metadata.st_flags());
+    }
+
+    /// Collects file attribute flags of the entry at `path`.
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    fn collect_flags(&mut self, _metadata: &Metadata, _path: &Path) {
+    }
+}
+
+/// Retrieves Linux file attribute flags (as in `lsattr`) of the given `path`.
+#[cfg(target_os = "linux")]
+pub(super) fn linux_flags(path: &Path) -> Result<u32> {
+    use std::os::raw::c_long;
+    use std::os::unix::io::AsRawFd;
+    use std::fs::File;
+
+    let file = File::open(path)?;
+
+    let mut linux_flags: c_long = 0;
+    let linux_flags_ptr: *mut c_long = &mut linux_flags;
+    unsafe {
+        match ioctls::fs_ioc_getflags(file.as_raw_fd(), linux_flags_ptr) {
+            0 => Ok(linux_flags as u32),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    }
+}
+
+/// Reads the immediate target of the symlink at `path`.
+///
+/// If the entry at `path` is not a symlink, `None` is returned.
+fn symlink_target(path: &Path) -> Option<PathBuf> {
+    match std::fs::read_link(path) {
+        Ok(target) => Some(target),
+        Err(ref error) if error.kind() == ErrorKind::InvalidInput => None,
+        Err(error) => {
+            warn!("unable to read symlink '{}': {}", path.display(), error);
+            None
+        }
+    }
+}
+
+/// Retrieves extended attributes of the entry at `path`.
+#[cfg(target_family = "unix")]
+fn ext_attrs(path: &Path) -> Vec<ExtAttr> {
+    use std::os::unix::ffi::OsStringExt;
+
+    let xattrs = match xattr::list(path) {
+        Ok(xattr_list) => xattr_list,
+        Err(err) => {
+            warn!("Unable to get extended attributes: {}", err);
+            return vec![]
+        }
+    };
+
+    let mut result = vec![];
+    for attr in xattrs {
+        match xattr::get(path, &attr) {
+            Ok(attr_value) => result.push(ExtAttr {
+                name: attr.into_vec(),
+                value: attr_value.unwrap_or_default(),
+            }),
+
+            Err(err) => warn!("Unable to get an extended attribute: {}", err),
+        }
+    }
+    result
+}
+
+/// Retrieves extended attributes of the entry at `path`.
+#[cfg(not(target_family = "unix"))]
+fn ext_attrs(_path: &Path) -> Vec<ExtAttr> {
+    vec![]
+}
+
+/// Unwraps the result of a time query on metadata, logging failures.
+fn time(time: Result<SystemTime>, kind: &str) -> Option<SystemTime> {
+    match time {
+        Ok(time) => Some(time),
+        Err(error) => {
+            warn!("unable to get {} time: {}", kind, error);
+            None
+        }
+    }
+}
+
+/// Returns the (full-precision) time of the last status change of an entry.
+#[cfg(target_family = "unix")]
+fn status_change_time(metadata: &Metadata) -> Option<SystemTime> {
+    use std::convert::TryFrom;
+    use std::os::unix::fs::MetadataExt;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let secs = u64::try_from(metadata.ctime()).ok()?;
+    let nanos = u32::try_from(metadata.ctime_nsec()).ok()?;
+
+    UNIX_EPOCH.checked_add(Duration::new(secs, nanos))
+}
+
+/// Converts idiomatic `SystemTime` to epoch microseconds for the protocol buffer.
+fn micros(time: Option<SystemTime>) -> Option<u64> {
+    match rrg_proto::micros(time?) {
+        Ok(micros) => Some(micros),
+        Err(error) => {
+            warn!("failed to convert time: {}", error);
+            None
+        }
+    }
+}
+
+impl Into<rrg_proto::stat_entry::ExtAttr> for ExtAttr {
+
+    fn into(self) -> rrg_proto::stat_entry::ExtAttr {
+        rrg_proto::stat_entry::ExtAttr {
+            name: Some(self.name),
+            value: Some(self.value),
+        }
+    }
+}
+
+impl super::Response for Entry {
+
+    const RDF_NAME: Option<&'static str> = Some("StatEntry");
+
+    type Proto = rrg_proto::rrg::StatEntry;
+
+    fn into_proto(self) -> rrg_proto::rrg::StatEntry {
+        rrg_proto::rrg::StatEntry {
+            st_mode: self.mode,
+            st_ino: lower_bits(self.ino),
+            st_dev: lower_bits(self.dev),
+            st_nlink: lower_bits(self.nlink),
+            st_uid: self.uid,
+            st_gid: self.gid,
+            st_size: Some(self.size),
+            st_atime: micros(self.atime),
+            st_mtime: micros(self.mtime),
+            st_ctime: micros(self.ctime),
+            st_blocks: lower_bits(self.blocks),
+            st_blksize: lower_bits(self.blksize),
+            st_rdev: lower_bits(self.rdev),
+            symlink: self.symlink
+                .map(|symlink| symlink.to_string_lossy().to_string()),
+            pathspec: Some(rrg_proto::PathSpec {
+                // Represents `CaseLiteral` path option (other options are not
+                // supported).
+                path_options: Some(Options::CaseLiteral as i32),
+                // Represents OS path type (other types are not supported).
+                pathtype: Some(PathType::Os as i32),
+                path: Some(self.path.to_string_lossy().to_string()),
+                ..Default::default()
+            }),
+            st_crtime: micros(self.btime),
+            st_flags_osx: self.flags_osx,
+            st_flags_linux: self.flags_linux,
+            ext_attrs: self.ext_attrs.into_iter()
+                .map(|attr| attr.into()).collect(),
+            st_ino64: self.ino,
+            st_dev64: self.dev,
+            st_nlink64: self.nlink,
+            st_blocks64: self.blocks,
+            st_blksize64: self.blksize,
+            st_rdev64: self.rdev,
+            ext_attrs_truncated: None,
+        }
+    }
+
+    fn shrink(proto: Self::Proto, limit: usize) -> Option<Shrunk<Self::Proto>> {
+        shrink_ext_attrs(proto, limit)
+    }
+}
+
+/// Truncates the given 64-bit `value` to its lower 32 bits.
+///
+/// GRR declares some of the stat fields as 32-bit integers and decoding larger
+/// values would truncate them anyway, so it is done explicitly instead.
+fn lower_bits(value: Option<u64>) -> Option<u32> {
+    value.map(|value| value as u32)
+}
+
+/// A number of bytes reserved for the field with the number of dropped extended
+/// attributes.
+const EXT_ATTRS_TRUNCATED_RESERVE: usize = 8;
+
+/// Drops trailing extended attributes of the stat `proto` to fit in `limit`.
+///
+/// If any attributes are dropped, their number is set in the dedicated RRG
+/// extension field. `None` is returned if the entry is too large even without
+/// any attributes.
+pub(super) fn shrink_ext_attrs(mut proto: rrg_proto::rrg::StatEntry, limit: usize)
+                               -> Option<Shrunk<rrg_proto::rrg::StatEntry>> {
+    use prost::Message as _;
+
+    let limit = limit.checked_sub(EXT_ATTRS_TRUNCATED_RESERVE)?;
+
+    let mut dropped = 0;
+    while proto.encoded_len() > limit {
+        proto.ext_attrs.pop()?;
+        dropped += 1;
+    }
+
+    if dropped > 0 {
+        proto.ext_attrs_truncated = Some(dropped);
+    }
+
+    Some(Shrunk::new(proto))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs::File;
+
+    use crate::action::Response as _;
+
+    use super::*;
+
+    #[test]
+    fn test_stat_file() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("foo");
+        std::fs::write(&path, b"foobar").unwrap();
+
+        let entry = StatOptions::new().stat(&path).unwrap();
+        assert_eq!(entry.path, path);
+        assert_eq!(entry.size, 6);
+        assert!(entry.symlink.is_none());
+        assert!(entry.mtime.unwrap() <= SystemTime::now());
+    }
+
+    #[test]
+    fn test_stat_non_existent() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let entry = StatOptions::new().stat(&tempdir.path().join("foo"));
+        assert!(entry.is_err());
+    }
+
+    #[test]
+    #[cfg(target_family = "unix")]
+    fn test_stat_metadata() {
+        use std::os::unix::fs::MetadataExt;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("foo");
+        File::create(&path).unwrap();
+
+        let metadata = std::fs::metadata(&path).unwrap();
+
+        let entry = StatOptions::new().stat(&path).unwrap();
+        assert_eq!(entry.mode, Some(metadata.mode().into()));
+        assert_eq!(entry.ino, Some(metadata.ino()));
+        assert_eq!(entry.dev, Some(metadata.dev()));
+        assert_eq!(entry.nlink, Some(1));
+        assert_eq!(entry.uid, Some(metadata.uid()));
+        assert_eq!(entry.gid, Some(metadata.gid()));
+        assert_eq!(entry.rdev, Some(metadata.rdev()));
+
+        let ctime = Duration::new(metadata.ctime() as u64, metadata.ctime_nsec() as u32);
+        assert_eq!(entry.ctime, Some(UNIX_EPOCH + ctime));
+    }
+
+    #[test]
+    fn test_stat_btime() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("foo");
+        File::create(&path).unwrap();
+
+        let entry = StatOptions::new().stat(&path).unwrap();
+        assert!(entry.btime.is_none());
+
+        let entry = StatOptions::new().btime(true).stat(&path).unwrap();
+        assert_eq!(entry.btime, std::fs::metadata(&path).unwrap().created().ok());
+    }
+
+    #[test]
+    #[cfg(target_family = "unix")]
+    fn test_stat_symlink() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let target = tempdir.path().join("foo");
+        let symlink = tempdir.path().join("bar");
+        std::fs::write(&target, b"foobar").unwrap();
+        std::os::unix::fs::symlink(&target, &symlink).unwrap();
+
+        let entry = StatOptions::new().stat(&symlink).unwrap();
+        assert_eq!(entry.path, symlink);
+        assert_eq!(entry.symlink, Some(target.clone()));
+        assert_eq!(entry.mode.unwrap() & 0o170000, 0o120000);
+    }
+
+    #[test]
+    #[cfg(target_family = "unix")]
+    fn test_stat_follow_symlink() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let target = tempdir.path().join("foo");
+        let symlink = tempdir.path().join("bar");
+        std::fs::write(&target, b"foobar").unwrap();
+        std::os::unix::fs::symlink(&target, &symlink).unwrap();
+
+        let target_entry = StatOptions::new().stat(&target).unwrap();
+
+        let entry = StatOptions::new().follow_symlink(true).stat(&symlink).unwrap();
+        assert_eq!(entry.path, symlink);
+        assert_eq!(entry.symlink, Some(target));
+        assert_eq!(entry.size, 6);
+        assert_eq!(entry.mode, target_entry.mode);
+        assert_eq!(entry.ino, target_entry.ino);
+    }
+
+    #[test]
+    #[cfg(target_family = "unix")]
+    fn test_stat_follow_broken_symlink() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let symlink = tempdir.path().join("foo");
+        std::os::unix::fs::symlink(tempdir.path().join("bar"), &symlink).unwrap();
+
+        assert!(StatOptions::new().stat(&symlink).is_ok());
+        assert!(StatOptions::new().follow_symlink(true).stat(&symlink).is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_stat_flags() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("foo");
+        File::create(&path).unwrap();
+
+        let entry = StatOptions::new().stat(&path).unwrap();
+        assert!(entry.flags_linux.is_none());
+
+        let entry = StatOptions::new().flags(true).stat(&path).unwrap();
+        assert_eq!(entry.flags_linux, linux_flags(&path).ok());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_stat_flags_symlink() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let target = tempdir.path().join("foo");
+        let symlink = tempdir.path().join("bar");
+        File::create(&target).unwrap();
+        std::os::unix::fs::symlink(&target, &symlink).unwrap();
+
+        // Opening the symlink would yield flags of its target, so these are not
+        // reported for the symlink itself.
+        let entry = StatOptions::new().flags(true).stat(&symlink).unwrap();
+        assert!(entry.flags_linux.is_none());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_linux_flags_non_existent() {
+        let tempdir = tempfile::tempdir().unwrap();
+        assert!(linux_flags(&tempdir.path().join("foo")).is_err());
+    }
+
+    #[test]
+    #[cfg(target_family = "unix")]
+    fn test_extended_attributes() {
+        fn check_attribute(attribute: &ExtAttr,
+                           name: &str, value: Vec<u8>) {
+            assert_eq!(attribute.name.clone(), name.as_bytes().to_vec());
+            assert_eq!(attribute.value.clone(), value);
+        }
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("foo");
+        File::create(&path).unwrap();
+        xattr::set(&path, "user.simple_name", &[0, 28, 42]).unwrap();
+        xattr::set(&path, "user.ⓤⓝⓘⓒⓞⓓⓔ ⓝⓐⓜⓔ", &[0, 1]).unwrap();
+        xattr::set(&path, "user.без значения", &[]).unwrap();
+
+        let entry = StatOptions::new().stat(&path).unwrap();
+        assert!(entry.ext_attrs.is_empty());
+
+        let mut ext_attrs = StatOptions::new().ext_attrs(true).stat(&path).unwrap().ext_attrs;
+        ext_attrs.sort_by(|a, b| a.name.partial_cmp(&b.name).unwrap());
+
+        assert_eq!(ext_attrs.len(), 3);
+
+        check_attribute(&ext_attrs[0], "user.simple_name", vec![0, 28, 42]);
+        check_attribute(&ext_attrs[1], "user.без значения", vec![]);
+        check_attribute(&ext_attrs[2], "user.ⓤⓝⓘⓒⓞⓓⓔ ⓝⓐⓜⓔ", vec![0, 1]);
+    }
+
+    #[test]
+    fn test_into_proto_lossless() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("foo");
+        File::create(&path).unwrap();
+
+        let mut entry = StatOptions::new().stat(&path).unwrap();
+        entry.ino = Some(u64::max_value());
+        entry.dev = Some(1 << 40);
+        entry.nlink = Some((1 << 32) + 1);
+
+        let proto = entry.into_proto();
+        assert_eq!(proto.st_ino64, Some(u64::max_value()));
+        assert_eq!(proto.st_dev64, Some(1 << 40));
+        assert_eq!(proto.st_nlink64, Some((1 << 32) + 1));
+    }
+
+    #[test]
+    fn test_into_proto_wire_compatible() {
+        use prost::Message as _;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("foo");
+        std::fs::write(&path, b"foobar").unwrap();
+
+        let mut entry = StatOptions::new().btime(true).stat(&path).unwrap();
+        entry.ino = Some((1 << 40) + 42);
+        entry.dev = Some(1337);
+        entry.nlink = Some(1);
+        entry.blocks = Some((1 << 32) + 8);
+        entry.blksize = Some(4096);
+        entry.rdev = Some(0);
+
+        let mut data = Vec::new();
+        entry.into_proto().encode(&mut data).unwrap();
+
+        // This is what GRR actually gets: only the lower 32 bits of the values
+        // that do not fit in its fields, with extension fields ignored.
+        let proto = rrg_proto::StatEntry::decode(&data[..]).unwrap();
+        assert_eq!(proto.st_ino, Some(42));
+        assert_eq!(proto.st_dev, Some(1337));
+        assert_eq!(proto.st_nlink, Some(1));
+        assert_eq!(proto.st_blocks, Some(8));
+        assert_eq!(proto.st_blksize, Some(4096));
+        assert_eq!(proto.st_rdev, Some(0));
+        assert_eq!(proto.st_size, Some(6));
+        assert_eq!(proto.pathspec.unwrap().path, Some(path.to_string_lossy().to_string()));
+
+        let proto = rrg_proto::rrg::StatEntry::decode(&data[..]).unwrap();
+        assert_eq!(proto.st_ino64, Some((1 << 40) + 42));
+        assert_eq!(proto.st_blocks64, Some((1 << 32) + 8));
+    }
+
+    fn ext_attr(name: &str, value: Vec<u8>) -> rrg_proto::stat_entry::ExtAttr {
+        rrg_proto::stat_entry::ExtAttr {
+            name: Some(name.as_bytes().to_vec()),
+            value: Some(value),
+        }
+    }
+
+    #[test]
+    fn test_shrink_ext_attrs() {
+        use prost::Message as _;
+
+        let proto = rrg_proto::rrg::StatEntry {
+            ext_attrs: (0..10).map(|i| ext_attr(&format!("user.{}", i), vec![0; 100])).collect(),
+            ..Default::default()
+        };
+
+        let limit = proto.encoded_len() / 2;
+        let shrunk = shrink_ext_attrs(proto, limit).unwrap();
+        assert!(shrunk.blobs.is_empty());
+        assert!(shrunk.proto.encoded_len() <= limit);
+
+        let attrs = &shrunk.proto.ext_attrs;
+        let dropped = shrunk.proto.ext_attrs_truncated.unwrap();
+        assert!(dropped > 0);
+        assert_eq!(attrs.len() + dropped as usize, 10);
+        assert_eq!(attrs[0].name, Some(b"user.0".to_vec()));
+        assert_eq!(attrs.last().unwrap().name, Some(format!("user.{}", attrs.len() - 1).into_bytes()));
+    }
+
+    #[test]
+    fn test_shrink_ext_attrs_impossible() {
+        let proto = rrg_proto::rrg::StatEntry {
+            symlink: Some("x".repeat(1024)),
+            ext_attrs: vec![ext_attr("user.foo", vec![])],
+            ..Default::default()
+        };
+
+        assert!(shrink_ext_attrs(proto, 512).is_none());
+    }
+}
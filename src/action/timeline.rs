@@ -5,22 +5,56 @@
 
 //! A handler and associated types for the timeline action.
 
+mod checkpoint;
+mod content;
+mod snapshot;
+
+use std::collections::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::convert::TryFrom;
 use std::ffi::{OsStr, OsString};
-use std::fs::{symlink_metadata, read_dir, Metadata};
+use std::fs::Metadata;
+use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
 use std::result::Result;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use std::vec::Vec;
 
 use cfg_if::cfg_if;
+use log::warn;
 use sha2::{Digest, Sha256};
-use rrg_proto::{TimelineArgs, TimelineEntry, TimelineResult, DataBlob};
+use rrg_proto::DataBlob;
+use rrg_proto::rrg::{TimelineEntry, TimelineResult};
 
-use crate::gzchunked::{GzChunkedEncoder, GzChunkedCompression};
+use crate::fs::{Entry, ErrorSummary, Pattern, WalkError, WalkOptions};
+use crate::gzchunked::{GzChunkedEncoder, GzChunkedCompression, BLOCK_SIZE};
 use crate::session::{self, Session, Error, ParseError, MissingFieldError};
 
 /// A request type for the timeline action.
 pub struct Request {
-    root: PathBuf,
+    /// Roots of the trees to collect the timeline of.
+    roots: Vec<PathBuf>,
+    /// Patterns of paths to exclude (along with their descendants).
+    exclusions: Vec<Pattern>,
+    /// Whether to traverse filesystems mounted on other devices.
+    cross_device: bool,
+    /// A number of threads to traverse the filesystem with.
+    threads: usize,
+    /// A timestamp (in nanoseconds) since which changed entries are collected.
+    changed_since: Option<u64>,
+    /// A digest of the snapshot of a previous timeline to detect deletions.
+    snapshot: Option<Vec<u8>>,
+    /// A token identifying the collection across (resumed) requests.
+    resume_token: Option<Vec<u8>>,
+    /// A maximum size of files to include content digests of.
+    hash_max_size: Option<u64>,
+    /// A maximum rate (in bytes per second) of reading files for hashing.
+    hash_rate: Option<u64>,
+    /// Whether to collect entries in the order of their paths.
+    sorted: bool,
+    /// Whether to collect file attribute flags.
+    attributes: bool,
 }
 
 /// A newtype wrapper for SHA-256 chunk digest.
@@ -28,8 +62,37 @@ pub struct Request {
 struct ChunkDigest([u8; 32]);
 
 /// A response type for the timeline action (actual response).
-struct Response {
+pub struct Response {
     ids: Vec<ChunkDigest>,
+    /// A digest of the snapshot of collected paths (if it has been stored).
+    snapshot: Option<Vec<u8>>,
+    delta: DeltaInfo,
+    /// Whether the collection continued from a checkpoint.
+    resumed: bool,
+}
+
+/// Information about how an (incremental) timeline has been computed.
+#[derive(Debug, Clone, PartialEq)]
+struct DeltaInfo {
+    basis: Basis,
+    changed_since: Option<u64>,
+    /// A number of entries skipped because they did not change.
+    unchanged_count: u64,
+    /// A number of entries reported as deleted.
+    deleted_count: u64,
+    /// Whether the requested base snapshot was not available.
+    snapshot_unavailable: bool,
+}
+
+/// A basis against which a timeline delta is computed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Basis {
+    /// No delta, all entries are collected.
+    Full,
+    /// Only entries changed after the reference timestamp are collected.
+    ChangedSince,
+    /// Entries are compared against the snapshot of a previous timeline.
+    Snapshot,
 }
 
 /// A response type for the timeline action (transfer store chunks).
@@ -37,29 +100,68 @@ struct ChunkResponse {
     data: Vec<u8>,
 }
 
-/// An object for recursively traversing filesystem and gathering
-/// timeline info.
+/// An object for gathering timeline info of traversed filesystem entries.
 struct RecurseState {
-    device: u64,
     ids: Vec<ChunkDigest>,
     encoder: GzChunkedEncoder,
+    /// A number of entries processed so far.
+    entries: u64,
+    /// A total size of files processed so far.
+    bytes: u64,
+    /// Paths skipped so far because of errors.
+    errors: ErrorSummary,
+    /// State of the delta computation.
+    delta: Delta,
+    /// State of saving checkpoints (if the collection is resumable).
+    checkpointing: Option<Checkpointing>,
+    /// An index of the root being traversed.
+    root: usize,
+    /// A hasher of file contents (if requested).
+    hasher: Option<content::ContentHasher>,
+    /// Whether to collect file attribute flags.
+    attributes: bool,
 }
 
-/// Retrieves device ID from metadata.
-#[allow(unused_variables)]
-fn dev_from_metadata(metadata: &Metadata) -> u64 {
-    cfg_if! {
-        if #[cfg(target_family = "unix")] {
-            use std::os::unix::fs::MetadataExt;
-            metadata.dev()
-        } else if #[cfg(target_family = "windows")] {
-            0
-        } else {
-            compile_error!("unsupported OS family");
-        }
-    }
+/// State of the computation of a timeline delta.
+struct Delta {
+    /// Information about how the delta is computed (updated as we go).
+    info: DeltaInfo,
+    /// An index of collected paths (if the session can keep state).
+    index: Option<snapshot::Writer>,
+    /// A snapshot of a previous timeline (only if deletions are detected).
+    base: Option<Base>,
+    /// Hashes of paths that could not be traversed (only if deletions are
+    /// detected).
+    failed: HashSet<u64>,
+}
+
+/// State of saving checkpoints of a resumable collection.
+struct Checkpointing {
+    /// A store to save checkpoints to.
+    store: checkpoint::Store,
+    /// A key identifying the configuration of the collection.
+    key: Vec<u8>,
+    /// The last path traversed (if any).
+    cursor: Option<PathBuf>,
+}
+
+/// A snapshot of a previous timeline read along the traversal.
+///
+/// Both the traversal and the snapshot are sorted (by the index of the root
+/// and then by path), so deleted paths are found by merging the two sequences
+/// without keeping any of them in memory.
+struct Base {
+    /// A reader of the snapshot (until it is exhausted or fails).
+    reader: Option<snapshot::Reader>,
+    /// Roots of the traversal.
+    roots: Vec<PathBuf>,
+    /// The next snapshot path (if already read) along with its position.
+    next: Option<(Position, Vec<u8>)>,
 }
 
+/// A position within the sorted traversal: an index of the root and a path.
+type Position = (usize, PathBuf);
+
 /// Tries to convert OS-dependent string to raw bytes.
 fn bytes_from_os_str(s: &OsStr) -> std::io::Result<Vec<u8>> {
     cfg_if! {
@@ -121,9 +223,15 @@ fn entry_from_metadata(metadata: &Metadata, path: &Path) -> std::io::Result<Time
                 ino: Some(metadata.ino()),
                 uid: Some(metadata.uid() as i64),
                 gid: Some(metadata.gid() as i64),
-                atime_ns: Some(metadata.atime_nsec() as u64),
-                ctime_ns: Some(metadata.ctime_nsec() as u64),
-                mtime_ns: Some(metadata.mtime_nsec() as u64),
+                atime_ns: nanos(metadata.atime(), metadata.atime_nsec()),
+                mtime_ns: nanos(metadata.mtime(), metadata.mtime_nsec()),
+                ctime_ns: nanos(metadata.ctime(), metadata.ctime_nsec()),
+                // On Linux the standard library obtains birth time through
+                // `statx` (if supported by the kernel).
+                btime_ns: metadata.created().ok().and_then(nanos_since_epoch),
+                attributes: None,
+                deleted: None,
+                sha256: None,
             })
         } else if #[cfg(target_family = "windows")] {
             use std::os::windows::fs::MetadataExt;
@@ -135,9 +243,13 @@ fn entry_from_metadata(metadata: &Metadata, path: &Path) -> std::io::Result<Time
                 ino: None,
                 uid: None,
                 gid: None,
-                atime_ns: Some(metadata.last_access_time()),
-                ctime_ns: Some(metadata.creation_time()),
-                mtime_ns: Some(metadata.last_write_time()),
+                atime_ns: nanos_from_filetime(metadata.last_access_time()),
+                mtime_ns: nanos_from_filetime(metadata.last_write_time()),
+                ctime_ns: nanos_from_filetime(metadata.creation_time()),
+                btime_ns: nanos_from_filetime(metadata.creation_time()),
+                attributes: None,
+                deleted: None,
+                sha256: None,
             })
         } else {
             compile_error!("unsupported OS family");
@@ -145,13 +257,125 @@ fn entry_from_metadata(metadata: &Metadata, path: &Path) -> std::io::Result<Time
     }
 }
 
+/// Converts a Unix timestamp split into seconds and nanoseconds to the total
+/// number of nanoseconds since the epoch.
+///
+/// Timestamps before the epoch (or too far in the future) cannot be represented
+/// and `None` is returned for them.
+#[cfg(target_family = "unix")]
+fn nanos(secs: i64, nsecs: i64) -> Option<u64> {
+    let nanos = secs.checked_mul(1_000_000_000)?.checked_add(nsecs)?;
+    u64::try_from(nanos).ok()
+}
+
+/// Converts system time to the number of nanoseconds since the Unix epoch.
+fn nanos_since_epoch(time: SystemTime) -> Option<u64> {
+    let nanos = time.duration_since(UNIX_EPOCH).ok()?.as_nanos();
+    u64::try_from(nanos).ok()
+}
+
+/// Converts a Windows file time (100-nanosecond intervals since 1601-01-01) to
+/// the number of nanoseconds since the Unix epoch.
+#[cfg(target_family = "windows")]
+fn nanos_from_filetime(filetime: u64) -> Option<u64> {
+    /// A number of 100-nanosecond intervals between 1601-01-01 and 1970-01-01.
+    const EPOCH_OFFSET: u64 = 116_444_736_000_000_000;
+
+    filetime.checked_sub(EPOCH_OFFSET)?.checked_mul(100)
+}
+
+/// Retrieves file attribute flags of the file at `path`.
+///
+/// Obtaining the flags requires opening the file, so it is done only for
+/// regular files and directories (e.g. opening a FIFO could block forever).
+#[cfg(target_os = "linux")]
+fn attributes(metadata: &Metadata, path: &Path) -> Option<u32> {
+    if !metadata.is_file() && !metadata.is_dir() {
+        return None;
+    }
+
+    super::statentry::linux_flags(path).ok()
+}
+
+/// Retrieves file attribute flags of the file at `path`.
+#[cfg(target_os = "macos")]
+fn attributes(metadata: &Metadata, _path: &Path) -> Option<u32> {
+    use std::os::macos::fs::MetadataExt;
+    Some(metadata.st_flags())
+}
+
+/// Retrieves file attribute flags of the file at `path`.
+#[cfg(target_family = "windows")]
+fn attributes(metadata: &Metadata, _path: &Path) -> Option<u32> {
+    use std::os::windows::fs::MetadataExt;
+    Some(metadata.file_attributes())
+}
+
+/// Retrieves file attribute flags of the file at `path`.
+#[cfg(all(target_family = "unix", not(target_os = "linux"), not(target_os = "macos")))]
+fn attributes(_metadata: &Metadata, _path: &Path) -> Option<u32> {
+    None
+}
+
 impl RecurseState {
-    /// Constructs new state that would only traverse filesystems from `device`.
-    fn new(device: u64) -> RecurseState {
+    /// Constructs new state.
+    ///
+    /// Gzchunked blocks are kept below `block_size` bytes (give or take a single
+    /// entry).
+    fn new(block_size: usize, delta: Delta, checkpointing: Option<Checkpointing>,
+           hasher: Option<content::ContentHasher>, attributes: bool) -> RecurseState {
         RecurseState {
-            device,
             ids: Vec::new(),
-            encoder: GzChunkedEncoder::new(GzChunkedCompression::default()),
+            encoder: GzChunkedEncoder::with_block_size(GzChunkedCompression::default(), block_size),
+            entries: 0,
+            bytes: 0,
+            errors: ErrorSummary::new(),
+            delta: delta,
+            checkpointing: checkpointing,
+            root: 0,
+            hasher: hasher,
+            attributes: attributes,
+        }
+    }
+
+    /// Makes the state continue the collection from the given `checkpoint`.
+    fn resume(&mut self, checkpoint: checkpoint::Checkpoint) {
+        self.ids = checkpoint.ids.into_iter().map(ChunkDigest).collect();
+        self.entries = checkpoint.entries;
+        self.bytes = checkpoint.bytes;
+        self.delta.info.unchanged_count = checkpoint.unchanged;
+        self.root = checkpoint.root;
+        if let Some(ref mut checkpointing) = self.checkpointing {
+            checkpointing.cursor = Some(checkpoint.path);
+        }
+    }
+
+    /// Saves the current progress (if the collection is resumable).
+    ///
+    /// Failing to save a checkpoint is not fatal: the collection would simply
+    /// have to start over if interrupted.
+    fn checkpoint(&self) {
+        let checkpointing = match self.checkpointing {
+            Some(ref checkpointing) => checkpointing,
+            None => return,
+        };
+        let path = match checkpointing.cursor {
+            Some(ref path) => path,
+            None => return,
+        };
+
+        let checkpoint = checkpoint::Checkpoint {
+            key: checkpointing.key.clone(),
+            root: self.root,
+            path: path.clone(),
+            ids: self.ids.iter().map(|id| id.0).collect(),
+            entries: self.entries,
+            bytes: self.bytes,
+            unchanged: self.delta.info.unchanged_count,
+        };
+
+        if let Err(error) = checkpointing.store.save(&checkpoint) {
+            warn!("failed to save timeline checkpoint: {}", error);
         }
     }
 
@@ -160,9 +384,13 @@ impl RecurseState {
     where
         S: Session,
     {
+        let started = Instant::now();
         let digest = ChunkDigest(Sha256::digest(block.as_slice()).into());
-        self.ids.push(digest);
+        session.record("digest", started.elapsed());
+
         session.send(session::Sink::TRANSFER_STORE, ChunkResponse { data: block })?;
+        self.ids.push(digest);
+        self.checkpoint();
         session.heartbeat();
         Ok(())
     }
@@ -174,85 +402,561 @@ impl RecurseState {
     {
         let mut entry_data: Vec<u8> = Vec::new();
         prost::Message::encode(&entry, &mut entry_data)?;
+
+        let started = Instant::now();
         self.encoder.write(entry_data.as_slice()).map_err(Error::action)?;
-        if let Some(data) = self.encoder.try_next_chunk().map_err(Error::action)? {
+        let chunk = self.encoder.try_next_chunk().map_err(Error::action)?;
+        session.record("compress", started.elapsed());
+        session.count("entries", 1);
+
+        if let Some(data) = chunk {
             self.send_block(data, session)?;
         }
         Ok(())
     }
 
-    /// Recursively traverses path specified as root, sends gzchunked stat data to session in
+    /// Encodes metadata of the file at `path` and reports the progress.
+    fn process_metadata<S>(&mut self, metadata: &Metadata, path: &Path, session: &mut S)
+        -> session::Result<()>
+    where
+        S: Session,
+    {
+        self.report_deletions(Some(path), session)?;
+
+        let mut entry = entry_from_metadata(metadata, path).map_err(Error::action)?;
+        if let Some(ref path) = entry.path {
+            self.delta.index(path);
+        }
+
+        if self.delta.is_changed(&entry) {
+            if self.attributes {
+                entry.attributes = attributes(metadata, path);
+            }
+            entry.sha256 = self.hash(metadata, path, session);
+            self.process_entry(entry, session)?;
+        } else {
+            self.delta.info.unchanged_count += 1;
+        }
+
+        self.entries += 1;
+        self.bytes += metadata.len();
+        session.progress(|| session::Progress {
+            entries: self.entries,
+            bytes: self.bytes,
+            path: Some(path.to_path_buf()),
+            fraction: None,
+        })
+    }
+
+    /// Computes the digest of the file at `path` (if requested and applicable).
+    ///
+    /// Files that cannot be read are reported as skipped but are otherwise
+    /// collected as usual.
+    fn hash<S>(&mut self, metadata: &Metadata, path: &Path, session: &mut S) -> Option<Vec<u8>>
+    where
+        S: Session,
+    {
+        let hasher = match self.hasher {
+            Some(ref mut hasher) if metadata.is_file() && hasher.accepts(metadata.len()) => hasher,
+            _ => return None,
+        };
+
+        match hasher.hash(path, session) {
+            Ok(digest) => digest,
+            Err(error) => {
+                self.errors.record(WalkError::new(path, error));
+                None
+            }
+        }
+    }
+
+    /// Records a traversal error.
+    fn fail(&mut self, error: WalkError) {
+        self.delta.fail(error.path());
+        self.errors.record(error);
+    }
+
+    /// Reports paths from the base snapshot that precede the traversed `path`
+    /// of the current root as deleted.
+    ///
+    /// If no path is given (i.e. the traversal is over), all the remaining
+    /// paths of the snapshot are reported. Paths at or below ones that could
+    /// not be traversed are not reported, as there is no way to tell whether
+    /// they still exist.
+    fn report_deletions<S>(&mut self, path: Option<&Path>, session: &mut S)
+        -> session::Result<()>
+    where
+        S: Session,
+    {
+        let position = path.map(|path| (self.root, path));
+
+        loop {
+            let path = match self.delta.base {
+                Some(ref mut base) => base.take_before(position),
+                None => None,
+            };
+            let path = match path {
+                Some(path) => path,
+                None => return Ok(()),
+            };
+
+            if self.delta.is_failed(&path) {
+                continue;
+            }
+
+            self.process_entry(TimelineEntry {
+                path: Some(path),
+                deleted: Some(true),
+                ..Default::default()
+            }, session)?;
+            self.delta.info.deleted_count += 1;
+        }
+    }
+
+    /// Traverses the tree under the root with the given `index` and `path`
+    /// with the given `options`, sends gzchunked stat data to session in
     /// process.
-    fn recurse<S>(&mut self, root: &Path, session: &mut S) -> session::Result<()>
+    ///
+    /// Entries up to `skip_until` (inclusive) are assumed to be collected
+    /// already and are skipped. If more than one thread is requested, entries
+    /// are encoded in the order in which the threads find them.
+    fn walk<S>(&mut self, (index, path): (usize, &Path), skip_until: Option<&Path>,
+               options: WalkOptions, threads: usize, session: &mut S) -> session::Result<()>
+    where
+        S: Session,
+    {
+        self.root = index;
+
+        if threads > 1 {
+            let iter = options.threads(threads).walk_parallel(path).map_err(Error::action)?;
+            self.consume(iter.with_errors(), skip_until, session)
+        } else {
+            let iter = options.walk(path).map_err(Error::action)?;
+            self.consume(iter.with_errors(), skip_until, session)
+        }
+    }
+
+    /// Processes all entries yielded by the walker `iter` that follow
+    /// `skip_until` (if given).
+    fn consume<I, S>(&mut self, mut iter: I, skip_until: Option<&Path>, session: &mut S)
+        -> session::Result<()>
     where
+        I: Iterator<Item = Result<Entry, WalkError>>,
         S: Session,
     {
-        let mut path = PathBuf::from(root);
-        let mut dir_iter_stack = Vec::new();
         loop {
-            let metadata = match symlink_metadata(&path) {
-                Ok(metadata) => metadata,
-                Err(_) => continue,
+            let started = Instant::now();
+            let entry = match iter.next() {
+                Some(entry) => entry,
+                None => return Ok(()),
             };
-            let entry = entry_from_metadata(&metadata, &path).map_err(Error::action)?;
-            self.process_entry(entry, session)?;
-            if metadata.is_dir() && dev_from_metadata(&metadata) == self.device {
-                if let Ok(dir_iter) = read_dir(&path) {
-                    dir_iter_stack.push(dir_iter);
+            session.record("stat", started.elapsed());
+
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(error) => {
+                    self.fail(error);
+                    continue;
                 }
-            }
-            let mut new_path = None;
-            while let Some(dir_iter) = dir_iter_stack.last_mut() {
-                if let Some(dir_entry) = dir_iter.next() {
-                    new_path = Some(dir_entry.map_err(Error::action)?.path());
-                    break;
-                } else {
-                    dir_iter_stack.pop();
+            };
+
+            if let Some(last) = skip_until {
+                if entry.path.as_path() <= last {
+                    continue;
                 }
             }
-            if let Some(new_path) = new_path {
-                path = new_path;
-            } else {
-                break;
+            if let Some(ref mut checkpointing) = self.checkpointing {
+                checkpointing.cursor = Some(entry.path.clone());
             }
+
+            self.process_metadata(&entry.metadata, &entry.path, session)?;
         }
-        Ok(())
     }
 
-    /// Sends final pieces of data to the session.
-    fn finish<S: Session>(mut self, session: &mut S) -> session::Result<Vec<ChunkDigest>> {
+    /// Sends final pieces of data (and a summary of skipped paths) to the
+    /// session.
+    fn finish<S: Session>(mut self, session: &mut S) -> session::Result<Response> {
+        let started = Instant::now();
         let final_block = self.encoder.next_chunk().map_err(Error::action)?;
+        session.record("compress", started.elapsed());
+
         self.send_block(final_block, session)?;
-        Ok(self.ids)
+        session.report_errors(self.errors)?;
+
+        let snapshot = match self.delta.index.map(snapshot::Writer::finish) {
+            Some(Ok(digest)) => Some(digest),
+            Some(Err(error)) => {
+                warn!("failed to write timeline snapshot: {}", error);
+                None
+            }
+            None => None,
+        };
+
+        Ok(Response {
+            ids: self.ids,
+            snapshot: snapshot,
+            delta: self.delta.info,
+            resumed: false,
+        })
+    }
+}
+
+impl Delta {
+
+    /// Adds the (raw) `path` to the snapshot index.
+    fn index(&mut self, path: &[u8]) {
+        if let Some(ref mut index) = self.index {
+            if let Err(error) = index.add(path) {
+                warn!("failed to write timeline snapshot: {}", error);
+                self.index = None;
+            }
+        }
+    }
+
+    /// Checks whether the entry changed since the reference timestamp.
+    ///
+    /// Entries with unknown timestamps are always considered changed.
+    fn is_changed(&self, entry: &TimelineEntry) -> bool {
+        let since = match self.info.changed_since {
+            Some(since) => since,
+            None => return true,
+        };
+
+        let is_newer = |time: Option<u64>| time.map_or(true, |time| time > since);
+        is_newer(entry.mtime_ns) || is_newer(entry.ctime_ns)
+    }
+
+    /// Remembers that the `path` could not be traversed (if deletions are
+    /// detected).
+    fn fail(&mut self, path: &Path) {
+        if self.base.is_some() {
+            if let Ok(path) = bytes_from_os_str(path.as_os_str()) {
+                self.failed.insert(path_hash(&path));
+            }
+        }
+    }
+
+    /// Checks whether the (raw) `path` or any of its ancestors failed to be
+    /// traversed.
+    fn is_failed(&self, path: &[u8]) -> bool {
+        if self.failed.is_empty() {
+            return false;
+        }
+
+        PathBuf::from(os_string_from_bytes(path)).ancestors().any(|ancestor| {
+            match bytes_from_os_str(ancestor.as_os_str()) {
+                Ok(ancestor) => self.failed.contains(&path_hash(&ancestor)),
+                Err(_) => false,
+            }
+        })
     }
 }
 
 /// Handles requests for the timeline action.
 pub fn handle<S: Session>(session: &mut S, request: Request) -> session::Result<()> {
-    let root_metadata = symlink_metadata(&request.root).map_err(Error::action)?;
-    let target_device = dev_from_metadata(&root_metadata);
-    let mut state = RecurseState::new(target_device);
+    // Snapshots of different traversal configurations are not comparable, so
+    // each of them is bound to a key identifying its configuration.
+    let key = snapshot_key(&request);
+    let state_dir = session.state_dir().map(Path::to_path_buf);
+
+    let checkpoints = match (&state_dir, &request.resume_token) {
+        (Some(state_dir), Some(token)) => Some(checkpoint::Store::new(state_dir, token)),
+        (None, Some(_)) => {
+            warn!("no state directory to keep timeline checkpoints in");
+            None
+        }
+        _ => None,
+    };
+
+    let checkpoint_key = checkpoint_key(&request, &key);
+    let resumed = match checkpoints {
+        Some(ref checkpoints) => checkpoints.load(&checkpoint_key).unwrap_or_else(|error| {
+            warn!("failed to load timeline checkpoint: {}", error);
+            None
+        }),
+        None => None,
+    };
+
+    // The snapshot index is not checkpointed, so resumed collections can
+    // neither detect deletions nor store a new snapshot.
+    let base = match (&state_dir, &request.snapshot) {
+        _ if resumed.is_some() => None,
+        (Some(state_dir), Some(digest)) => {
+            snapshot::Reader::open(state_dir, digest, &key).unwrap_or_else(|error| {
+                warn!("failed to open timeline snapshot: {}", error);
+                None
+            })
+        }
+        _ => None,
+    };
+
+    let basis = if base.is_some() {
+        Basis::Snapshot
+    } else if request.changed_since.is_some() {
+        Basis::ChangedSince
+    } else {
+        Basis::Full
+    };
 
-    state.recurse(&request.root, session)?;
-    let action_response = Response {
-        ids: state.finish(session)?,
+    let index = match (&state_dir, &resumed) {
+        (Some(state_dir), None) => match snapshot::Writer::create(state_dir, &key) {
+            Ok(index) => Some(index),
+            Err(error) => {
+                warn!("failed to create timeline snapshot: {}", error);
+                None
+            }
+        },
+        _ => None,
+    };
+
+    let delta = Delta {
+        info: DeltaInfo {
+            basis: basis,
+            changed_since: request.changed_since,
+            unchanged_count: 0,
+            deleted_count: 0,
+            snapshot_unavailable: request.snapshot.is_some() && base.is_none(),
+        },
+        index: index,
+        base: base.map(|base| Base::new(base, request.roots.clone())),
+        failed: HashSet::new(),
     };
+
+    // Checkpoints describe the position by a path, which is meaningful only if
+    // the traversal order is deterministic. Deletions are detected by merging
+    // the traversal with the base snapshot, so snapshots have to be written in
+    // order as well. In these cases the traversal is sorted even if it has not
+    // been requested.
+    let sorted = request.sorted || checkpoints.is_some() ||
+        delta.index.is_some() || delta.base.is_some();
+
+    let checkpointing = checkpoints.clone().map(|store| Checkpointing {
+        store: store,
+        key: checkpoint_key,
+        cursor: None,
+    });
+    let hasher = request.hash_max_size.map(|max_size| {
+        content::ContentHasher::new(max_size, request.hash_rate)
+    });
+
+    // Blocks cannot be split further without breaking the gzchunked format, so
+    // they are kept well below the session message size limit.
+    let block_size = std::cmp::min(BLOCK_SIZE, session.max_message_size() / 2);
+    let mut state = RecurseState::new(block_size, delta, checkpointing, hasher, request.attributes);
+
+    let resume_from = resumed.map(|checkpoint| {
+        let position = (checkpoint.root, checkpoint.path.clone());
+        state.resume(checkpoint);
+        position
+    });
+
+    // Other roots are pruned from the traversal of each root, so that entries
+    // of nested roots are not collected twice.
+    let roots = Arc::new(request.roots.iter().cloned().collect::<HashSet<_>>());
+    let exclusions = Arc::new(request.exclusions);
+
+    for (index, root) in request.roots.iter().enumerate() {
+        if exclusions.iter().any(|exclusion| exclusion.matches(root)) {
+            continue;
+        }
+
+        // Roots before the checkpointed one have been collected completely and
+        // entries of the checkpointed one are collected up to the last path.
+        let last = match resume_from {
+            Some((resumed, _)) if resumed > index => continue,
+            Some((resumed, ref path)) if resumed == index => Some(path.clone()),
+            _ => None,
+        };
+
+        let roots = roots.clone();
+        let exclusions = exclusions.clone();
+        let prune_last = last.clone();
+        let options = WalkOptions::new()
+            .cross_device(request.cross_device)
+            .sorted(sorted)
+            .prune(move |entry| {
+                roots.contains(&entry.path) ||
+                exclusions.iter().any(|exclusion| exclusion.matches(&entry.path)) ||
+                // With sorted traversal, directories that precede the last path
+                // and do not contain it have been collected completely.
+                prune_last.as_ref().map_or(false, |last| {
+                    entry.path < *last && !last.starts_with(&entry.path)
+                })
+            });
+
+        state.walk((index, root), last.as_deref(), options, request.threads, session)?;
+    }
+
+    state.report_deletions(None, session)?;
+
+    let mut action_response = state.finish(session)?;
+    action_response.resumed = resume_from.is_some();
     session.reply(action_response)?;
 
+    if let Some(checkpoints) = checkpoints {
+        if let Err(error) = checkpoints.remove() {
+            warn!("failed to remove timeline checkpoint: {}", error);
+        }
+    }
+
     Ok(())
 }
 
+/// Computes a key identifying the traversal configuration of the `request`.
+fn snapshot_key(request: &Request) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    for root in &request.roots {
+        hasher.input(b"root\0");
+        hasher.input(root.to_string_lossy().as_bytes());
+        hasher.input(b"\0");
+    }
+    for exclusion in &request.exclusions {
+        hasher.input(b"exclusion\0");
+        hasher.input(exclusion.to_string().as_bytes());
+        hasher.input(b"\0");
+    }
+    if request.cross_device {
+        hasher.input(b"cross-device");
+    }
+
+    hasher.result().to_vec()
+}
+
+/// Computes a key identifying the entire configuration of the `request`.
+///
+/// Unlike the snapshot key, it also covers options affecting which entries are
+/// collected, so that a checkpoint is never used to resume a different kind of
+/// collection.
+fn checkpoint_key(request: &Request, snapshot_key: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(snapshot_key);
+    if let Some(changed_since) = request.changed_since {
+        hasher.input(b"changed-since\0");
+        hasher.input(&changed_since.to_le_bytes());
+    }
+    if let Some(ref snapshot) = request.snapshot {
+        hasher.input(b"snapshot\0");
+        hasher.input(snapshot);
+    }
+    if let Some(hash_max_size) = request.hash_max_size {
+        hasher.input(b"hash-max-size\0");
+        hasher.input(&hash_max_size.to_le_bytes());
+    }
+    if request.attributes {
+        hasher.input(b"attributes");
+    }
+
+    hasher.result().to_vec()
+}
+
+/// Computes a compact hash of the (raw) `path`.
+fn path_hash(path: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Base {
+
+    /// Creates a new base reading paths from the given snapshot `reader`.
+    fn new(reader: snapshot::Reader, roots: Vec<PathBuf>) -> Base {
+        Base {
+            reader: Some(reader),
+            roots: roots,
+            next: None,
+        }
+    }
+
+    /// Takes the next (raw) snapshot path preceding the traversal `position`.
+    ///
+    /// A path at the position itself still exists, so it is skipped. If no
+    /// position is given, paths are taken until the snapshot is exhausted.
+    fn take_before(&mut self, position: Option<(usize, &Path)>) -> Option<Vec<u8>> {
+        use std::cmp::Ordering;
+
+        if self.next.is_none() {
+            self.next = self.read();
+        }
+
+        let ordering = match (&self.next, position) {
+            (None, _) => return None,
+            (Some(_), None) => Ordering::Less,
+            (Some(((root, path), _)), Some(position)) => {
+                (*root, path.as_path()).cmp(&position)
+            }
+        };
+
+        match ordering {
+            Ordering::Less => self.next.take().map(|(_, path)| path),
+            Ordering::Equal => {
+                self.next = None;
+                None
+            }
+            Ordering::Greater => None,
+        }
+    }
+
+    /// Reads the next path of the snapshot along with its position.
+    fn read(&mut self) -> Option<(Position, Vec<u8>)> {
+        let path = match self.reader.as_mut()?.next() {
+            Some(Ok(path)) => path,
+            Some(Err(error)) => {
+                warn!("failed to read timeline snapshot: {}", error);
+                self.reader = None;
+                return None;
+            }
+            None => {
+                self.reader = None;
+                return None;
+            }
+        };
+
+        let parsed = PathBuf::from(os_string_from_bytes(&path));
+
+        // Nested roots are pruned from traversals of the outer ones, so every
+        // path belongs to the deepest root it is in. Paths outside of all the
+        // roots should not be there, but they are ordered after all others.
+        let root = self.roots.iter().enumerate()
+            .filter(|(_, root)| parsed.starts_with(root))
+            .max_by_key(|(_, root)| root.components().count())
+            .map_or(self.roots.len(), |(index, _)| index);
+
+        Some(((root, parsed), path))
+    }
+}
+
 impl super::Request for Request {
 
-    type Proto = TimelineArgs;
+    const RDF_NAME: Option<&'static str> = Some("TimelineArgs");
 
-    fn from_proto(proto: TimelineArgs) -> Result<Request, ParseError> {
-        match proto.root {
-            Some(root) => Ok(Request {
-                root: PathBuf::from(os_string_from_bytes(root.as_slice())),
-            }),
-            None => Err(ParseError::malformed(MissingFieldError::new("root"))),
+    type Proto = rrg_proto::rrg::TimelineArgs;
+
+    fn from_proto(proto: rrg_proto::rrg::TimelineArgs) -> Result<Request, ParseError> {
+        let roots = proto.root.iter().chain(proto.roots.iter())
+            .map(|root| PathBuf::from(os_string_from_bytes(root.as_slice())))
+            .collect::<Vec<_>>();
+
+        if roots.is_empty() {
+            return Err(ParseError::malformed(MissingFieldError::new("root")));
         }
+
+        let exclusions = proto.exclusions.iter()
+            .map(|exclusion| Pattern::parse(exclusion))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Request {
+            roots: roots,
+            exclusions: exclusions,
+            cross_device: proto.cross_device.unwrap_or(false),
+            threads: proto.threads.unwrap_or(1) as usize,
+            changed_since: proto.changed_since_ns,
+            snapshot: proto.snapshot_digest,
+            resume_token: proto.resume_token.filter(|token| !token.is_empty()),
+            hash_max_size: proto.hash_max_size,
+            hash_rate: proto.hash_rate_limit,
+            sorted: proto.sorted.unwrap_or(false),
+            attributes: proto.collect_attributes.unwrap_or(false),
+        })
     }
 }
 
@@ -264,7 +968,31 @@ impl super::Response for Response {
 
     fn into_proto(self) -> TimelineResult {
         TimelineResult {
-            entry_batch_blob_ids: self.ids.iter().map(|id| id.0.to_vec()).collect()
+            entry_batch_blob_ids: self.ids.iter().map(|id| id.0.to_vec()).collect(),
+            snapshot_digest: self.snapshot,
+            delta: Some(self.delta.into()),
+            resumed: Some(self.resumed),
+        }
+    }
+}
+
+impl From<DeltaInfo> for rrg_proto::rrg::TimelineDelta {
+
+    fn from(delta: DeltaInfo) -> rrg_proto::rrg::TimelineDelta {
+        use rrg_proto::rrg::timeline_delta::Basis as ProtoBasis;
+
+        let basis = match delta.basis {
+            Basis::Full => ProtoBasis::Full,
+            Basis::ChangedSince => ProtoBasis::ChangedSince,
+            Basis::Snapshot => ProtoBasis::Snapshot,
+        };
+
+        rrg_proto::rrg::TimelineDelta {
+            basis: Some(basis as i32),
+            changed_since_ns: delta.changed_since,
+            unchanged_count: Some(delta.unchanged_count),
+            deleted_count: Some(delta.deleted_count),
+            snapshot_unavailable: Some(delta.snapshot_unavailable),
         }
     }
 }
@@ -288,6 +1016,22 @@ mod tests {
     use tempfile::tempdir;
     use crate::gzchunked::GzChunkedDecoder;
 
+    fn request<P: Into<PathBuf>>(root: P) -> Request {
+        Request {
+            roots: vec![root.into()],
+            exclusions: vec![],
+            cross_device: false,
+            threads: 1,
+            changed_since: None,
+            snapshot: None,
+            resume_token: None,
+            hash_max_size: None,
+            hash_rate: None,
+            sorted: false,
+            attributes: false,
+        }
+    }
+
     fn entries_from_session_response(session: &session::test::Fake) -> Vec<TimelineEntry> {
         assert_eq!(session.reply_count(), 1);
         let block_count = session.response_count(session::Sink::TRANSFER_STORE);
@@ -323,7 +1067,7 @@ mod tests {
         let dir_path = dir.path().join("nonexistent_subdir");
 
         let mut session = session::test::Fake::new();
-        assert!(handle(&mut session, Request { root: dir_path }).is_err());
+        assert!(handle(&mut session, request(dir_path)).is_err());
     }
 
     #[test]
@@ -331,7 +1075,7 @@ mod tests {
         let dir = tempdir().unwrap();
 
         let mut session = session::test::Fake::new();
-        assert!(handle(&mut session, Request { root: PathBuf::from(dir.path()) }).is_ok());
+        assert!(handle(&mut session, request(dir.path())).is_ok());
 
         let mut entries = entries_from_session_response(&session);
         entries.sort_by(|a, b| a.path.cmp(&b.path));
@@ -339,6 +1083,216 @@ mod tests {
         assert_eq!(entries[0].path, Some(bytes_from_os_str(dir.path().as_os_str()).unwrap()));
     }
 
+    #[test]
+    fn test_parallel() {
+        let dir = tempdir().unwrap();
+        for name in &["a", "b", "c"] {
+            create_dir(dir.path().join(name)).unwrap();
+            write(dir.path().join(name).join("foo"), b"foo").unwrap();
+        }
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request(dir.path())).is_ok());
+        let mut expected = entries_from_session_response(&session).into_iter()
+            .map(|entry| (entry.path, entry.ino))
+            .collect::<Vec<_>>();
+        expected.sort();
+
+        let mut request = request(dir.path());
+        request.threads = 4;
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request).is_ok());
+        // Access times of directories change when they are listed, so only
+        // paths and inodes are compared.
+        let mut entries = entries_from_session_response(&session).into_iter()
+            .map(|entry| (entry.path, entry.ino))
+            .collect::<Vec<_>>();
+        entries.sort();
+
+        assert_eq!(entries.len(), 7);
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn test_sorted() {
+        let dir = tempdir().unwrap();
+        for name in &["c", "a", "b"] {
+            create_dir(dir.path().join(name)).unwrap();
+            write(dir.path().join(name).join("foo"), b"foo").unwrap();
+            write(dir.path().join(format!("{}.txt", name)), b"foo").unwrap();
+        }
+
+        for &threads in &[1, 4] {
+            let mut request = request(dir.path());
+            request.threads = threads;
+            request.sorted = true;
+
+            let mut session = session::test::Fake::new();
+            assert!(handle(&mut session, request).is_ok());
+
+            let paths = entries_from_session_response(&session).into_iter()
+                .map(|entry| PathBuf::from(os_string_from_bytes(&entry.path.unwrap())))
+                .collect::<Vec<_>>();
+
+            let mut expected = paths.clone();
+            expected.sort();
+
+            assert_eq!(paths.len(), 10);
+            assert_eq!(paths, expected);
+        }
+    }
+
+    #[test]
+    fn test_from_proto_sorted() {
+        use crate::action::Request as _;
+
+        let request = Request::from_proto(rrg_proto::rrg::TimelineArgs {
+            root: Some(b"/foo".to_vec()),
+            ..Default::default()
+        }).unwrap();
+        assert!(!request.sorted);
+
+        let request = Request::from_proto(rrg_proto::rrg::TimelineArgs {
+            root: Some(b"/foo".to_vec()),
+            sorted: Some(true),
+            ..Default::default()
+        }).unwrap();
+        assert!(request.sorted);
+    }
+
+    #[test]
+    fn test_from_proto_roots() {
+        use crate::action::Request as _;
+
+        let request = Request::from_proto(rrg_proto::rrg::TimelineArgs {
+            root: Some(b"/foo".to_vec()),
+            roots: vec![b"/bar".to_vec()],
+            exclusions: vec![String::from("/proc")],
+            ..Default::default()
+        }).unwrap();
+
+        assert_eq!(request.roots, vec![PathBuf::from("/foo"), PathBuf::from("/bar")]);
+        assert_eq!(request.exclusions, vec![Pattern::parse("/proc").unwrap()]);
+        assert!(!request.cross_device);
+    }
+
+    #[test]
+    fn test_from_proto_no_roots() {
+        use crate::action::Request as _;
+
+        let request = Request::from_proto(rrg_proto::rrg::TimelineArgs {
+            exclusions: vec![String::from("/proc")],
+            ..Default::default()
+        });
+
+        assert!(request.is_err());
+    }
+
+    #[test]
+    fn test_multiple_roots() {
+        let dir = tempdir().unwrap();
+        create_dir(dir.path().join("foo")).unwrap();
+        create_dir(dir.path().join("bar")).unwrap();
+        write(dir.path().join("foo").join("abc"), b"abc").unwrap();
+        write(dir.path().join("bar").join("def"), b"def").unwrap();
+        write(dir.path().join("ghi"), b"ghi").unwrap();
+
+        let mut request = request(dir.path().join("foo"));
+        request.roots.push(dir.path().join("bar"));
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request).is_ok());
+
+        let mut entries = entries_from_session_response(&session);
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].path, Some(bytes_from_os_str(dir.path().join("bar").as_os_str()).unwrap()));
+        assert_eq!(entries[3].path, Some(bytes_from_os_str(dir.path().join("foo").join("abc").as_os_str()).unwrap()));
+    }
+
+    #[test]
+    fn test_nested_roots() {
+        let dir = tempdir().unwrap();
+        create_dir(dir.path().join("foo")).unwrap();
+        write(dir.path().join("foo").join("bar"), b"bar").unwrap();
+
+        let mut request = request(dir.path());
+        request.roots.push(dir.path().join("foo"));
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request).is_ok());
+
+        let entries = entries_from_session_response(&session);
+        assert_eq!(entries.len(), 3);
+    }
+
+    #[test]
+    fn test_exclusions() {
+        let dir = tempdir().unwrap();
+        create_dir(dir.path().join("foo")).unwrap();
+        create_dir(dir.path().join("bar")).unwrap();
+        write(dir.path().join("foo").join("abc"), b"abc").unwrap();
+        write(dir.path().join("bar").join("def"), b"def").unwrap();
+
+        let exclusion = format!("{}/f*", dir.path().display());
+
+        let mut request = request(dir.path());
+        request.exclusions.push(Pattern::parse(&exclusion).unwrap());
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request).is_ok());
+
+        let mut entries = entries_from_session_response(&session);
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1].path, Some(bytes_from_os_str(dir.path().join("bar").as_os_str()).unwrap()));
+        assert_eq!(entries[2].path, Some(bytes_from_os_str(dir.path().join("bar").join("def").as_os_str()).unwrap()));
+    }
+
+    #[test]
+    fn test_error_summary() {
+        let dir = tempdir().unwrap();
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request(dir.path())).is_ok());
+
+        let summaries = session.error_summaries();
+        assert_eq!(summaries.len(), 1);
+        assert!(summaries[0].is_empty());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_error_summary_permission_denied() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::fs::{set_permissions, Permissions};
+
+        let dir = tempdir().unwrap();
+        create_dir(dir.path().join("foo")).unwrap();
+        write(dir.path().join("foo").join("bar"), b"bar").unwrap();
+
+        set_permissions(dir.path().join("foo"), Permissions::from_mode(0o000)).unwrap();
+        // Permissions are not enforced for privileged users.
+        if std::fs::read_dir(dir.path().join("foo")).is_ok() {
+            return;
+        }
+
+        let mut session = session::test::Fake::new();
+        let result = handle(&mut session, request(dir.path()));
+
+        set_permissions(dir.path().join("foo"), Permissions::from_mode(0o755)).unwrap();
+
+        assert!(result.is_ok());
+
+        let summaries = session.error_summaries();
+        assert_eq!(summaries.len(), 1);
+
+        let counts = summaries[0].counts().collect::<Vec<_>>();
+        assert_eq!(counts, vec![(std::io::ErrorKind::PermissionDenied, 1)]);
+        assert_eq!(summaries[0].errors()[0].path(), dir.path().join("foo"));
+    }
+
     #[cfg_attr(target_family = "windows", ignore)]
     #[test]
     fn test_file_hardlink() {
@@ -351,7 +1305,7 @@ mod tests {
         hard_link(&test1_path, &test2_path).unwrap();
 
         let mut session = session::test::Fake::new();
-        assert!(handle(&mut session, Request { root: PathBuf::from(dir.path()) }).is_ok());
+        assert!(handle(&mut session, request(dir.path())).is_ok());
 
         let mut entries = entries_from_session_response(&session);
         entries.sort_by(|a, b| a.path.cmp(&b.path));
@@ -374,7 +1328,7 @@ mod tests {
         symlink(&test1_path, &test2_path).unwrap();
 
         let mut session = session::test::Fake::new();
-        assert!(handle(&mut session, Request { root: PathBuf::from(dir.path()) }).is_ok());
+        assert!(handle(&mut session, request(dir.path())).is_ok());
 
         let mut entries = entries_from_session_response(&session);
         entries.sort_by(|a, b| a.path.cmp(&b.path));
@@ -403,7 +1357,7 @@ mod tests {
         symlink("../test3", &test4_path).unwrap();
 
         let mut session = session::test::Fake::new();
-        assert!(handle(&mut session, Request { root: PathBuf::from(dir.path()) }).is_ok());
+        assert!(handle(&mut session, request(dir.path())).is_ok());
 
         let mut entries = entries_from_session_response(&session);
         entries.sort_by(|a, b| a.path.cmp(&b.path));
@@ -414,6 +1368,41 @@ mod tests {
         assert_eq!(entries[4].path, Some(bytes_from_os_str(test4_path.as_os_str()).unwrap()));
     }
 
+    #[test]
+    fn test_profile() {
+        let dir = tempdir().unwrap();
+        write(dir.path().join("foo"), "foo").unwrap();
+        write(dir.path().join("bar"), "bar").unwrap();
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request(dir.path())).is_ok());
+
+        let profile = session.profile();
+        assert_eq!(profile.counter("entries"), 3);
+        assert_eq!(profile.phase("stat").unwrap().count, 3);
+        assert!(profile.phase("compress").is_some());
+        assert!(profile.phase("digest").is_some());
+    }
+
+    #[test]
+    fn test_progress() {
+        let dir = tempdir().unwrap();
+        write(dir.path().join("foo"), "foo").unwrap();
+        write(dir.path().join("bar"), "barbaz").unwrap();
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request(dir.path())).is_ok());
+
+        let updates = session.progress_updates();
+        assert_eq!(updates.len(), 3);
+        assert_eq!(updates[0].entries, 1);
+        assert_eq!(updates[0].path, Some(PathBuf::from(dir.path())));
+
+        let last = updates.last().unwrap();
+        assert_eq!(last.entries, 3);
+        assert!(last.bytes >= 9);
+    }
+
     #[test]
     fn test_weird_unicode_names() {
         let dir = tempdir().unwrap();
@@ -428,7 +1417,7 @@ mod tests {
         write(&path3, "foo").unwrap();
 
         let mut session = session::test::Fake::new();
-        assert!(handle(&mut session, Request { root: PathBuf::from(dir.path()) }).is_ok());
+        assert!(handle(&mut session, request(dir.path())).is_ok());
 
         let mut entries = entries_from_session_response(&session);
         entries.sort_by(|a, b| a.path.cmp(&b.path));
@@ -459,7 +1448,7 @@ mod tests {
         assert!(dir_count >= 64);
 
         let mut session = session::test::Fake::new();
-        assert!(handle(&mut session, Request { root: PathBuf::from(dir.path()) }).is_ok());
+        assert!(handle(&mut session, request(dir.path())).is_ok());
 
         let entries = entries_from_session_response(&session);
         assert_eq!(entries.len(), dir_count + 1);
@@ -486,7 +1475,7 @@ mod tests {
         set_permissions(&readonly_path, Permissions::from_mode(0o444)).unwrap();
 
         let mut session = session::test::Fake::new();
-        assert!(handle(&mut session, Request { root: PathBuf::from(dir.path()) }).is_ok());
+        assert!(handle(&mut session, request(dir.path())).is_ok());
 
         let mut entries = entries_from_session_response(&session);
         entries.sort_by(|a, b| a.path.cmp(&b.path));
@@ -496,4 +1485,565 @@ mod tests {
         // Drop mode bits because symlinks have actual modes on some unix systems.
         assert_eq!(entries[3].mode.unwrap() & 0o120000, 0o120000);
     }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_timestamps_match_stat() {
+        use std::process::Command;
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("foo");
+        write(&path, b"foo").unwrap();
+
+        let output = Command::new("stat")
+            .arg("--format=%.9X %.9Y %.9Z %.9W")
+            .arg(&path)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+
+        // `stat` prints timestamps as `<secs>.<nanos>` and zero for unknown
+        // birth time.
+        let stamps = String::from_utf8(output.stdout).unwrap()
+            .split_whitespace()
+            .map(|stamp| stamp.replace('.', "").parse::<u64>().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(stamps.len(), 4);
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request(&path)).is_ok());
+
+        let entries = entries_from_session_response(&session);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].atime_ns, Some(stamps[0]));
+        assert_eq!(entries[0].mtime_ns, Some(stamps[1]));
+        assert_eq!(entries[0].ctime_ns, Some(stamps[2]));
+        if stamps[3] == 0 {
+            assert_eq!(entries[0].btime_ns, None);
+        } else {
+            assert_eq!(entries[0].btime_ns, Some(stamps[3]));
+        }
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_nanos() {
+        assert_eq!(nanos(0, 0), Some(0));
+        assert_eq!(nanos(1, 5), Some(1_000_000_005));
+        assert_eq!(nanos(1_600_000_000, 123_456_789), Some(1_600_000_000_123_456_789));
+        assert_eq!(nanos(-1, 0), None);
+        assert_eq!(nanos(i64::max_value(), 0), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_attributes() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("foo");
+        write(&path, b"foo").unwrap();
+
+        let mut request = request(&path);
+        request.attributes = true;
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request).is_ok());
+
+        let entries = entries_from_session_response(&session);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].attributes, super::super::statentry::linux_flags(&path).ok());
+    }
+
+    #[test]
+    fn test_attributes_not_requested() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("foo");
+        write(&path, b"foo").unwrap();
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request(&path)).is_ok());
+
+        let entries = entries_from_session_response(&session);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].attributes, None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_attributes_fifo() {
+        use std::process::Command;
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("fifo");
+        assert!(Command::new("mkfifo").arg(&path).status().unwrap().success());
+
+        let mut request = request(&path);
+        request.attributes = true;
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request).is_ok());
+
+        let entries = entries_from_session_response(&session);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].attributes, None);
+    }
+
+    #[test]
+    fn test_from_proto_incremental() {
+        use crate::action::Request as _;
+
+        let request = Request::from_proto(rrg_proto::rrg::TimelineArgs {
+            root: Some(b"/foo".to_vec()),
+            changed_since_ns: Some(1_600_000_000_000_000_000),
+            snapshot_digest: Some(b"digest".to_vec()),
+            ..Default::default()
+        }).unwrap();
+
+        assert_eq!(request.changed_since, Some(1_600_000_000_000_000_000));
+        assert_eq!(request.snapshot, Some(b"digest".to_vec()));
+    }
+
+    #[test]
+    fn test_changed_since() {
+        let dir = tempdir().unwrap();
+        write(dir.path().join("foo"), b"foo").unwrap();
+        write(dir.path().join("bar"), b"bar").unwrap();
+
+        let mut request = request(dir.path());
+        request.changed_since = Some(u64::max_value());
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request).is_ok());
+
+        let entries = entries_from_session_response(&session);
+        assert!(entries.is_empty());
+
+        let delta = &session.reply::<Response>(0).delta;
+        assert_eq!(delta.basis, Basis::ChangedSince);
+        assert_eq!(delta.unchanged_count, 3);
+        assert_eq!(delta.deleted_count, 0);
+    }
+
+    #[test]
+    fn test_changed_since_epoch() {
+        let dir = tempdir().unwrap();
+        write(dir.path().join("foo"), b"foo").unwrap();
+
+        let mut request = request(dir.path());
+        request.changed_since = Some(0);
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request).is_ok());
+
+        let entries = entries_from_session_response(&session);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(session.reply::<Response>(0).delta.unchanged_count, 0);
+    }
+
+    #[test]
+    fn test_no_state_dir() {
+        let dir = tempdir().unwrap();
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request(dir.path())).is_ok());
+
+        let response = session.reply::<Response>(0);
+        assert_eq!(response.snapshot, None);
+        assert_eq!(response.delta.basis, Basis::Full);
+        assert!(!response.delta.snapshot_unavailable);
+    }
+
+    #[test]
+    fn test_snapshot_deletions() {
+        let state_dir = tempdir().unwrap();
+        let dir = tempdir().unwrap();
+        create_dir(dir.path().join("foo")).unwrap();
+        write(dir.path().join("foo").join("bar"), b"bar").unwrap();
+        write(dir.path().join("baz"), b"baz").unwrap();
+
+        let mut session = session::test::Fake::new().with_state_dir(state_dir.path());
+        assert!(handle(&mut session, request(dir.path())).is_ok());
+
+        let digest = session.reply::<Response>(0).snapshot.clone().unwrap();
+
+        std::fs::remove_file(dir.path().join("foo").join("bar")).unwrap();
+        std::fs::remove_dir(dir.path().join("foo")).unwrap();
+
+        let mut request = request(dir.path());
+        request.changed_since = Some(u64::max_value());
+        request.snapshot = Some(digest);
+
+        let mut session = session::test::Fake::new().with_state_dir(state_dir.path());
+        assert!(handle(&mut session, request).is_ok());
+
+        let mut entries = entries_from_session_response(&session);
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path, Some(bytes_from_os_str(dir.path().join("foo").as_os_str()).unwrap()));
+        assert_eq!(entries[0].deleted, Some(true));
+        assert_eq!(entries[0].mode, None);
+        assert_eq!(entries[1].path, Some(bytes_from_os_str(dir.path().join("foo").join("bar").as_os_str()).unwrap()));
+        assert_eq!(entries[1].deleted, Some(true));
+
+        let response = session.reply::<Response>(0);
+        assert!(response.snapshot.is_some());
+        assert_eq!(response.delta.basis, Basis::Snapshot);
+        assert_eq!(response.delta.unchanged_count, 2);
+        assert_eq!(response.delta.deleted_count, 2);
+        assert!(!response.delta.snapshot_unavailable);
+    }
+
+    #[test]
+    fn test_snapshot_deletions_nested_roots() {
+        let dir = tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+
+        for &threads in &[1, 4] {
+            let state_dir = tempdir().unwrap();
+            for name in &["a", "b", "b/x"] {
+                create_dir(path(name)).unwrap();
+            }
+            for name in &["a/1", "a/2", "b/x/1", "b/y", "c"] {
+                write(path(name), b"foo").unwrap();
+            }
+
+            let nested = || Request {
+                roots: vec![dir.path().to_path_buf(), path("b")],
+                threads: threads,
+                ..request(dir.path())
+            };
+
+            let mut session = session::test::Fake::new().with_state_dir(state_dir.path());
+            assert!(handle(&mut session, nested()).is_ok());
+
+            std::fs::remove_file(path("a/1")).unwrap();
+            std::fs::remove_dir_all(path("b/x")).unwrap();
+            std::fs::remove_file(path("c")).unwrap();
+            write(path("a/0"), b"foo").unwrap();
+
+            let mut request = nested();
+            request.snapshot = session.reply::<Response>(0).snapshot.clone();
+
+            let mut session = session::test::Fake::new().with_state_dir(state_dir.path());
+            assert!(handle(&mut session, request).is_ok());
+
+            let entries = entries_from_session_response(&session);
+            let mut deleted = entries.iter()
+                .filter(|entry| entry.deleted == Some(true))
+                .map(|entry| PathBuf::from(os_string_from_bytes(entry.path.as_ref().unwrap())))
+                .collect::<Vec<_>>();
+            deleted.sort();
+
+            assert_eq!(deleted, vec![path("a/1"), path("b/x"), path("b/x/1"), path("c")]);
+            assert_eq!(entries.len(), 10);
+            assert_eq!(session.reply::<Response>(0).delta.deleted_count, 4);
+
+            std::fs::remove_dir_all(path("a")).unwrap();
+            std::fs::remove_dir_all(path("b")).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_snapshot_other_roots() {
+        let state_dir = tempdir().unwrap();
+        let dir = tempdir().unwrap();
+        create_dir(dir.path().join("foo")).unwrap();
+        create_dir(dir.path().join("bar")).unwrap();
+
+        let mut session = session::test::Fake::new().with_state_dir(state_dir.path());
+        assert!(handle(&mut session, request(dir.path().join("foo"))).is_ok());
+
+        let digest = session.reply::<Response>(0).snapshot.clone().unwrap();
+
+        let mut request = request(dir.path().join("bar"));
+        request.snapshot = Some(digest);
+
+        let mut session = session::test::Fake::new().with_state_dir(state_dir.path());
+        assert!(handle(&mut session, request).is_ok());
+
+        let entries = entries_from_session_response(&session);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].deleted, None);
+
+        let delta = &session.reply::<Response>(0).delta;
+        assert_eq!(delta.basis, Basis::Full);
+        assert!(delta.snapshot_unavailable);
+    }
+
+    #[test]
+    fn test_snapshot_unavailable() {
+        let state_dir = tempdir().unwrap();
+        let dir = tempdir().unwrap();
+
+        let mut request = request(dir.path());
+        request.changed_since = Some(0);
+        request.snapshot = Some(b"foo".to_vec());
+
+        let mut session = session::test::Fake::new().with_state_dir(state_dir.path());
+        assert!(handle(&mut session, request).is_ok());
+
+        let response = session.reply::<Response>(0);
+        assert!(response.snapshot.is_some());
+        assert_eq!(response.delta.basis, Basis::ChangedSince);
+        assert!(response.delta.snapshot_unavailable);
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_snapshot_unreadable_not_deleted() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::fs::{set_permissions, Permissions};
+
+        let state_dir = tempdir().unwrap();
+        let dir = tempdir().unwrap();
+        create_dir(dir.path().join("foo")).unwrap();
+        write(dir.path().join("foo").join("bar"), b"bar").unwrap();
+
+        let mut session = session::test::Fake::new().with_state_dir(state_dir.path());
+        assert!(handle(&mut session, request(dir.path())).is_ok());
+
+        let digest = session.reply::<Response>(0).snapshot.clone().unwrap();
+
+        set_permissions(dir.path().join("foo"), Permissions::from_mode(0o000)).unwrap();
+        // Permissions are not enforced for privileged users.
+        if std::fs::read_dir(dir.path().join("foo")).is_ok() {
+            return;
+        }
+
+        let mut request = request(dir.path());
+        request.snapshot = Some(digest);
+
+        let mut session = session::test::Fake::new().with_state_dir(state_dir.path());
+        assert!(handle(&mut session, request).is_ok());
+
+        set_permissions(dir.path().join("foo"), Permissions::from_mode(0o755)).unwrap();
+
+        let entries = entries_from_session_response(&session);
+        assert!(entries.iter().all(|entry| entry.deleted.is_none()));
+        assert_eq!(session.reply::<Response>(0).delta.deleted_count, 0);
+    }
+
+    /// Creates a tree of files with poorly compressible names under `root`.
+    fn populate(root: &Path, dirs: usize, files: usize) {
+        for i in 0..dirs {
+            let dir = root.join(format!("dir{}", i));
+            create_dir(&dir).unwrap();
+            for j in 0..files {
+                let digest = Sha256::digest(format!("{}:{}", i, j).as_bytes());
+                let name = digest.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+                write(dir.join(name), b"").unwrap();
+            }
+        }
+    }
+
+    /// Decodes entries from blocks with `ids` sent through any of `sessions`.
+    fn entries_from_blocks(sessions: &[&session::test::Fake], ids: &[ChunkDigest]) -> Vec<TimelineEntry> {
+        let mut blocks = std::collections::HashMap::new();
+        for session in sessions {
+            for block in session.responses::<ChunkResponse>(session::Sink::TRANSFER_STORE) {
+                let digest: [u8; 32] = Sha256::digest(&block.data).into();
+                blocks.insert(digest, block.data.clone());
+            }
+        }
+
+        let mut decoder = GzChunkedDecoder::new();
+        let mut entries = Vec::new();
+        for id in ids {
+            decoder.write(blocks[&id.0].as_slice()).unwrap();
+            while let Some(data) = decoder.try_next_data() {
+                entries.push(prost::Message::decode(data.as_slice()).unwrap());
+            }
+        }
+
+        entries
+    }
+
+    fn check_resume(threads: usize) {
+        let state_dir = tempdir().unwrap();
+        let dir = tempdir().unwrap();
+        populate(dir.path(), 4, 32);
+
+        let resumable = || Request {
+            threads: threads,
+            resume_token: Some(b"token".to_vec()),
+            ..request(dir.path())
+        };
+
+        let mut interrupted = session::test::Fake::new()
+            .with_state_dir(state_dir.path())
+            .with_max_message_size(1024)
+            .with_response_limit(4);
+        assert!(handle(&mut interrupted, resumable()).is_err());
+        assert_eq!(interrupted.response_count(session::Sink::TRANSFER_STORE), 4);
+
+        let mut resumed = session::test::Fake::new()
+            .with_state_dir(state_dir.path())
+            .with_max_message_size(1024);
+        assert!(handle(&mut resumed, resumable()).is_ok());
+
+        let response = resumed.reply::<Response>(0);
+        assert!(response.resumed);
+
+        // Blocks sent before the interruption are part of the final result.
+        let interrupted_ids = interrupted
+            .responses::<ChunkResponse>(session::Sink::TRANSFER_STORE)
+            .map(|block| ChunkDigest(Sha256::digest(&block.data).into()))
+            .collect::<Vec<_>>();
+        assert_eq!(&response.ids[..4], &interrupted_ids[..]);
+
+        let entries = entries_from_blocks(&[&interrupted, &resumed], &response.ids);
+        let paths = entries.iter()
+            .map(|entry| entry.path.clone().unwrap())
+            .collect::<HashSet<_>>();
+        assert_eq!(entries.len(), 1 + 4 + 4 * 32);
+        assert_eq!(paths.len(), entries.len());
+
+        // The checkpoint is no longer needed once the collection is complete.
+        let checkpoints = std::fs::read_dir(state_dir.path().join("timeline-checkpoints")).unwrap();
+        assert_eq!(checkpoints.count(), 0);
+    }
+
+    #[test]
+    fn test_resume_sequential() {
+        check_resume(1);
+    }
+
+    #[test]
+    fn test_resume_parallel() {
+        check_resume(4);
+    }
+
+    #[test]
+    fn test_resume_other_request() {
+        let state_dir = tempdir().unwrap();
+        let dir = tempdir().unwrap();
+        populate(dir.path(), 2, 32);
+
+        let mut first = request(dir.path());
+        first.resume_token = Some(b"token".to_vec());
+
+        let mut interrupted = session::test::Fake::new()
+            .with_state_dir(state_dir.path())
+            .with_max_message_size(1024)
+            .with_response_limit(2);
+        assert!(handle(&mut interrupted, first).is_err());
+
+        // The same token used with different arguments starts from scratch.
+        let mut second = request(dir.path());
+        second.resume_token = Some(b"token".to_vec());
+        second.changed_since = Some(0);
+
+        let mut session = session::test::Fake::new()
+            .with_state_dir(state_dir.path())
+            .with_max_message_size(1024);
+        assert!(handle(&mut session, second).is_ok());
+
+        let response = session.reply::<Response>(0);
+        assert!(!response.resumed);
+
+        let entries = entries_from_blocks(&[&session], &response.ids);
+        assert_eq!(entries.len(), 1 + 2 + 2 * 32);
+    }
+
+    #[test]
+    fn test_resume_token_without_state_dir() {
+        let dir = tempdir().unwrap();
+        write(dir.path().join("foo"), b"foo").unwrap();
+
+        let mut request = request(dir.path());
+        request.resume_token = Some(b"token".to_vec());
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request).is_ok());
+
+        let entries = entries_from_session_response(&session);
+        assert_eq!(entries.len(), 2);
+        assert!(!session.reply::<Response>(0).resumed);
+    }
+
+    #[test]
+    fn test_from_proto_hash() {
+        use crate::action::Request as _;
+
+        let request = Request::from_proto(rrg_proto::rrg::TimelineArgs {
+            root: Some(b"/foo".to_vec()),
+            hash_max_size: Some(1024),
+            hash_rate_limit: Some(4096),
+            ..Default::default()
+        }).unwrap();
+
+        assert_eq!(request.hash_max_size, Some(1024));
+        assert_eq!(request.hash_rate, Some(4096));
+    }
+
+    #[test]
+    fn test_from_proto_attributes() {
+        use crate::action::Request as _;
+
+        let request = Request::from_proto(rrg_proto::rrg::TimelineArgs {
+            root: Some(b"/foo".to_vec()),
+            ..Default::default()
+        }).unwrap();
+        assert!(!request.attributes);
+
+        let request = Request::from_proto(rrg_proto::rrg::TimelineArgs {
+            root: Some(b"/foo".to_vec()),
+            collect_attributes: Some(true),
+            ..Default::default()
+        }).unwrap();
+        assert!(request.attributes);
+    }
+
+    #[test]
+    fn test_hash() {
+        let dir = tempdir().unwrap();
+        create_dir(dir.path().join("foo")).unwrap();
+        write(dir.path().join("foo").join("bar"), b"bar").unwrap();
+        write(dir.path().join("foo").join("baz"), b"bazbazbaz").unwrap();
+
+        let mut request = request(dir.path());
+        request.hash_max_size = Some(4);
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request).is_ok());
+
+        let mut entries = entries_from_session_response(&session);
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].sha256, None);
+        assert_eq!(entries[1].sha256, None);
+        assert_eq!(entries[2].sha256, Some(Sha256::digest(b"bar").to_vec()));
+        assert_eq!(entries[3].sha256, None);
+    }
+
+    #[test]
+    fn test_hash_not_requested() {
+        let dir = tempdir().unwrap();
+        write(dir.path().join("foo"), b"foo").unwrap();
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request(dir.path())).is_ok());
+
+        let entries = entries_from_session_response(&session);
+        assert!(entries.iter().all(|entry| entry.sha256.is_none()));
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_hash_symlink() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempdir().unwrap();
+        write(dir.path().join("foo"), b"foo").unwrap();
+        symlink(dir.path().join("foo"), dir.path().join("bar")).unwrap();
+
+        let mut request = request(dir.path().join("bar"));
+        request.hash_max_size = Some(1024);
+
+        let mut session = session::test::Fake::new();
+        assert!(handle(&mut session, request).is_ok());
+
+        let entries = entries_from_session_response(&session);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].sha256, None);
+    }
 }
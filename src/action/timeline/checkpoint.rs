@@ -0,0 +1,241 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Checkpoints of timeline collections for resuming them after interruption.
+//!
+//! Timelines of big volumes can take hours and may be interrupted by an agent
+//! restart or by hitting one of the request limits. If the request specifies a
+//! resume token, the progress is checkpointed in the state directory after each
+//! sent block, so that a subsequent request with the same token can continue
+//! where the previous one stopped.
+//!
+//! Checkpoints are taken right after a block is sent. At this point the
+//! gzchunked encoder has no buffered data, so no encoder state other than the
+//! digests of the sent blocks has to be persisted. Because the traversal is
+//! sorted, the position within the filesystem is described by the last path
+//! included in these blocks.
+
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+use super::{bytes_from_os_str, os_string_from_bytes};
+
+/// A name of the directory (within the state directory) with the checkpoints.
+const DIR_NAME: &'static str = "timeline-checkpoints";
+
+/// A checkpoint of the timeline collection.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checkpoint {
+    /// A key identifying the configuration of the collection.
+    pub key: Vec<u8>,
+    /// An index of the root being traversed.
+    pub root: usize,
+    /// The last path (within the root being traversed) included in sent blocks.
+    pub path: PathBuf,
+    /// Digests of all blocks sent so far.
+    pub ids: Vec<[u8; 32]>,
+    /// A number of entries processed so far.
+    pub entries: u64,
+    /// A total size of files processed so far.
+    pub bytes: u64,
+    /// A number of entries skipped so far because they did not change.
+    pub unchanged: u64,
+}
+
+/// A place where checkpoints of a particular collection are stored.
+#[derive(Clone, Debug)]
+pub struct Store {
+    /// A path to the checkpoint file.
+    path: PathBuf,
+}
+
+impl Store {
+
+    /// Creates a store for the collection with `token` within `state_dir`.
+    pub fn new(state_dir: &Path, token: &[u8]) -> Store {
+        let name = token.iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+
+        Store {
+            path: state_dir.join(DIR_NAME).join(name),
+        }
+    }
+
+    /// Loads the latest checkpoint of the collection.
+    ///
+    /// If there is no checkpoint or it has been taken for a collection with a
+    /// key other than `key` (i.e. the token has been reused for a different
+    /// request), `None` is returned.
+    pub fn load(&self, key: &[u8]) -> Result<Option<Checkpoint>> {
+        let data = match std::fs::read(&self.path) {
+            Ok(data) => data,
+            Err(ref error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+
+        let proto: rrg_proto::rrg::TimelineCheckpoint = prost::Message::decode(&data[..])
+            .map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+
+        let checkpoint = Checkpoint::try_from(proto)?;
+        if checkpoint.key.as_slice() != key {
+            return Ok(None);
+        }
+
+        Ok(Some(checkpoint))
+    }
+
+    /// Replaces the stored checkpoint with the given one.
+    ///
+    /// The checkpoint is written to a temporary file first, so an interruption
+    /// while saving it never leaves a corrupted checkpoint behind.
+    pub fn save(&self, checkpoint: &Checkpoint) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut data = Vec::new();
+        prost::Message::encode(&checkpoint.to_proto()?, &mut data)
+            .map_err(|error| Error::new(ErrorKind::Other, error))?;
+
+        let temp_path = self.path.with_extension("tmp");
+        std::fs::write(&temp_path, data)?;
+        std::fs::rename(&temp_path, &self.path)
+    }
+
+    /// Removes the stored checkpoint (if any).
+    pub fn remove(&self) -> Result<()> {
+        match std::fs::remove_file(&self.path) {
+            Err(ref error) if error.kind() == ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+impl Checkpoint {
+
+    /// Converts the checkpoint to its proto representation.
+    fn to_proto(&self) -> Result<rrg_proto::rrg::TimelineCheckpoint> {
+        Ok(rrg_proto::rrg::TimelineCheckpoint {
+            key: Some(self.key.clone()),
+            root_index: Some(self.root as u64),
+            last_path: Some(bytes_from_os_str(self.path.as_os_str())?),
+            entry_batch_blob_ids: self.ids.iter().map(|id| id.to_vec()).collect(),
+            entry_count: Some(self.entries),
+            byte_count: Some(self.bytes),
+            unchanged_count: Some(self.unchanged),
+        })
+    }
+}
+
+impl TryFrom<rrg_proto::rrg::TimelineCheckpoint> for Checkpoint {
+
+    type Error = std::io::Error;
+
+    fn try_from(proto: rrg_proto::rrg::TimelineCheckpoint) -> Result<Checkpoint> {
+        let ids = proto.entry_batch_blob_ids.iter()
+            .map(|id| <[u8; 32]>::try_from(id.as_slice()))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+
+        let path = match proto.last_path {
+            Some(path) => PathBuf::from(os_string_from_bytes(&path)),
+            None => return Err(Error::new(ErrorKind::InvalidData, "missing path")),
+        };
+
+        Ok(Checkpoint {
+            key: proto.key.unwrap_or_default(),
+            root: proto.root_index.unwrap_or(0) as usize,
+            path: path,
+            ids: ids,
+            entries: proto.entry_count.unwrap_or(0),
+            bytes: proto.byte_count.unwrap_or(0),
+            unchanged: proto.unchanged_count.unwrap_or(0),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn checkpoint() -> Checkpoint {
+        Checkpoint {
+            key: b"key".to_vec(),
+            root: 1,
+            path: PathBuf::from("/foo/bar"),
+            ids: vec![[0; 32], [1; 32]],
+            entries: 42,
+            bytes: 1337,
+            unchanged: 7,
+        }
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let store = Store::new(tempdir.path(), b"token");
+        store.save(&checkpoint()).unwrap();
+
+        assert_eq!(store.load(b"key").unwrap(), Some(checkpoint()));
+    }
+
+    #[test]
+    fn test_load_missing() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let store = Store::new(tempdir.path(), b"token");
+        assert_eq!(store.load(b"key").unwrap(), None);
+    }
+
+    #[test]
+    fn test_load_key_mismatch() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let store = Store::new(tempdir.path(), b"token");
+        store.save(&checkpoint()).unwrap();
+
+        assert_eq!(store.load(b"other").unwrap(), None);
+    }
+
+    #[test]
+    fn test_save_overwrites() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let store = Store::new(tempdir.path(), b"token");
+        store.save(&checkpoint()).unwrap();
+
+        let mut newer = checkpoint();
+        newer.ids.push([2; 32]);
+        store.save(&newer).unwrap();
+
+        assert_eq!(store.load(b"key").unwrap(), Some(newer));
+    }
+
+    #[test]
+    fn test_remove() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let store = Store::new(tempdir.path(), b"token");
+        store.save(&checkpoint()).unwrap();
+        store.remove().unwrap();
+
+        assert_eq!(store.load(b"key").unwrap(), None);
+        assert!(store.remove().is_ok());
+    }
+
+    #[test]
+    fn test_tokens_separate() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        Store::new(tempdir.path(), b"foo").save(&checkpoint()).unwrap();
+
+        let store = Store::new(tempdir.path(), b"bar");
+        assert_eq!(store.load(b"key").unwrap(), None);
+    }
+}
@@ -0,0 +1,196 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Utilities for hashing file contents while collecting a timeline.
+//!
+//! Timelines cover entire filesystems, so reading all the files at full speed
+//! could easily starve other processes of I/O. Because of this, hashing is
+//! limited to files below a configured size and reads can be rate-limited.
+
+use std::fs::File;
+use std::io::{Read, Result};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+
+use crate::session::{Session, Throttle};
+
+/// A size of the buffer used for reading file contents.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// An object for computing (rate-limited) digests of file contents.
+pub struct ContentHasher {
+    /// A maximum size of files to hash.
+    max_size: u64,
+    /// A limiter of the read rate (if requested).
+    throttle: Option<Throttle>,
+    /// A buffer for file contents.
+    buffer: Vec<u8>,
+}
+
+impl ContentHasher {
+
+    /// Creates a hasher of files of at most `max_size` bytes.
+    ///
+    /// If `rate` is specified, files are read at most `rate` bytes per second
+    /// (with bursts of no more than a single buffer).
+    pub fn new(max_size: u64, rate: Option<u64>) -> ContentHasher {
+        ContentHasher {
+            max_size: max_size,
+            throttle: rate.map(|rate| Throttle::new(rate, BUFFER_SIZE as u64)),
+            buffer: vec![0; BUFFER_SIZE],
+        }
+    }
+
+    /// Checks whether a file of the given `size` should be hashed.
+    pub fn accepts(&self, size: u64) -> bool {
+        size <= self.max_size
+    }
+
+    /// Computes a SHA-256 digest of the file at `path`.
+    ///
+    /// If the file turns out to be bigger than the size limit (e.g. it grew
+    /// after its metadata was collected), `None` is returned.
+    pub fn hash<S: Session>(&mut self, path: &Path, session: &mut S) -> Result<Option<Vec<u8>>> {
+        let started = Instant::now();
+        let mut file = open(path)?.take(self.max_size.saturating_add(1));
+
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+
+        loop {
+            let count = file.read(&mut self.buffer[..])?;
+            if count == 0 {
+                break;
+            }
+
+            hasher.input(&self.buffer[..count]);
+            size += count as u64;
+            self.throttle(count, session);
+        }
+        session.record("hash", started.elapsed());
+
+        if size > self.max_size {
+            return Ok(None);
+        }
+
+        Ok(Some(hasher.result().to_vec()))
+    }
+
+    /// Waits until reading more is allowed after `count` bytes have been read.
+    fn throttle<S: Session>(&mut self, count: usize, session: &mut S) {
+        let delay = match self.throttle {
+            Some(ref mut throttle) => throttle.consume(count as u64, Instant::now()),
+            None => return,
+        };
+
+        if delay > Duration::from_secs(0) {
+            std::thread::sleep(delay);
+            session.heartbeat();
+        }
+    }
+}
+
+/// Opens the file at `path` for reading without updating its access time.
+///
+/// Timelines report access times, so they should not be affected by the act of
+/// collecting a timeline. Not updating the access time is allowed only to the
+/// file owner, so for other files we fall back to opening them normally.
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64",
+                                   target_arch = "arm", target_arch = "aarch64")))]
+fn open(path: &Path) -> Result<File> {
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    /// The `O_NOATIME` flag value on the supported architectures.
+    const O_NOATIME: i32 = 0o1000000;
+
+    match OpenOptions::new().read(true).custom_flags(O_NOATIME).open(path) {
+        Ok(file) => Ok(file),
+        Err(_) => File::open(path),
+    }
+}
+
+/// Opens the file at `path` for reading.
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64",
+                                       target_arch = "arm", target_arch = "aarch64"))))]
+fn open(path: &Path) -> Result<File> {
+    File::open(path)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_hash() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("foo");
+        std::fs::write(&path, b"foobar").unwrap();
+
+        let mut session = crate::session::test::Fake::new();
+        let mut hasher = ContentHasher::new(1024, None);
+
+        let digest = hasher.hash(&path, &mut session).unwrap();
+        assert_eq!(digest, Some(Sha256::digest(b"foobar").to_vec()));
+    }
+
+    #[test]
+    fn test_hash_empty() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("foo");
+        std::fs::write(&path, b"").unwrap();
+
+        let mut session = crate::session::test::Fake::new();
+        let mut hasher = ContentHasher::new(0, None);
+
+        let digest = hasher.hash(&path, &mut session).unwrap();
+        assert_eq!(digest, Some(Sha256::digest(b"").to_vec()));
+    }
+
+    #[test]
+    fn test_hash_too_big() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("foo");
+        std::fs::write(&path, b"foobar").unwrap();
+
+        let mut session = crate::session::test::Fake::new();
+        let mut hasher = ContentHasher::new(5, None);
+
+        assert_eq!(hasher.hash(&path, &mut session).unwrap(), None);
+        assert!(!hasher.accepts(6));
+        assert!(hasher.accepts(5));
+    }
+
+    #[test]
+    fn test_hash_non_existent() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let mut session = crate::session::test::Fake::new();
+        let mut hasher = ContentHasher::new(1024, None);
+
+        assert!(hasher.hash(&tempdir.path().join("foo"), &mut session).is_err());
+    }
+
+    #[test]
+    fn test_hash_rate_limit() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("foo");
+        std::fs::write(&path, vec![0; 4 * BUFFER_SIZE]).unwrap();
+
+        let mut session = crate::session::test::Fake::new();
+        let mut hasher = ContentHasher::new(u64::max_value(), Some(1024 * 1024));
+
+        let started = Instant::now();
+        assert!(hasher.hash(&path, &mut session).unwrap().is_some());
+
+        // The first buffer is read right away, the remaining three have to wait
+        // for the bucket to refill.
+        let expected = Duration::from_secs_f64((3 * BUFFER_SIZE) as f64 / (1024.0 * 1024.0));
+        assert!(started.elapsed() >= expected.mul_f64(0.9));
+    }
+}
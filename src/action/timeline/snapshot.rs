@@ -0,0 +1,307 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Compact on-disk indices of paths collected by previous timelines.
+//!
+//! Incremental timelines send only entries that changed since the reference
+//! timestamp. Deleted files cannot be detected this way, so every timeline
+//! leaves an index of all the paths it has seen in the state directory. The
+//! next timeline can then report paths that are in the index but are gone
+//! from the filesystem.
+//!
+//! An index is a gzipped sequence of length-prefixed records. The first record
+//! is a key identifying the traversal configuration (indices of different roots
+//! are not comparable) and all following ones are raw paths in the (sorted)
+//! order of the traversal, so that the next timeline can compare the index with
+//! the filesystem in a single pass. The index file is named after the SHA-256
+//! digest of its (uncompressed) content.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::warn;
+use sha2::{Digest, Sha256};
+
+/// A name of the directory (within the state directory) with the indices.
+const DIR_NAME: &'static str = "timeline";
+
+/// A maximum number of indices kept in the state directory.
+///
+/// Indices are usually used only as a base of the very next timeline, so only
+/// a few most recent ones are kept around.
+const MAX_SNAPSHOTS: usize = 4;
+
+/// A counter used to give unique names to indices being written.
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A writer of the index of paths collected by a timeline.
+pub struct Writer {
+    /// A directory the index is written to.
+    dir: PathBuf,
+    /// A temporary path of the index (until it is complete).
+    path: PathBuf,
+    /// A compressing stream writing to the index file (until it is complete).
+    file: Option<GzEncoder<BufWriter<File>>>,
+    /// A hasher of the uncompressed index content.
+    hasher: Sha256,
+}
+
+impl Writer {
+
+    /// Creates a new index in the given `state_dir` for traversal with `key`.
+    pub fn create(state_dir: &Path, key: &[u8]) -> Result<Writer> {
+        let dir = state_dir.join(DIR_NAME);
+        std::fs::create_dir_all(&dir)?;
+
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        let path = dir.join(format!(".tmp-{}-{}", std::process::id(), id));
+        let file = GzEncoder::new(BufWriter::new(File::create(&path)?), Compression::fast());
+
+        let mut writer = Writer {
+            dir: dir,
+            path: path,
+            file: Some(file),
+            hasher: Sha256::new(),
+        };
+        writer.write(key)?;
+
+        Ok(writer)
+    }
+
+    /// Adds the given (raw) `path` to the index.
+    pub fn add(&mut self, path: &[u8]) -> Result<()> {
+        self.write(path)
+    }
+
+    /// Completes the index and returns its digest.
+    ///
+    /// Older indices above the retention limit are removed from the state
+    /// directory.
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        if let Some(file) = self.file.take() {
+            file.finish()?.flush()?;
+        }
+
+        let digest = self.hasher.clone().result().to_vec();
+        let path = self.dir.join(hex(&digest));
+        std::fs::rename(&self.path, &path)?;
+
+        prune(&self.dir, &path, MAX_SNAPSHOTS);
+
+        Ok(digest)
+    }
+
+    /// Writes a single length-prefixed record to the index.
+    fn write(&mut self, record: &[u8]) -> Result<()> {
+        let mut len = Vec::with_capacity(4);
+        len.write_u32::<LittleEndian>(record.len() as u32)?;
+
+        let file = match self.file {
+            Some(ref mut file) => file,
+            None => return Err(ErrorKind::BrokenPipe.into()),
+        };
+
+        self.hasher.input(&len);
+        self.hasher.input(record);
+        file.write_all(&len)?;
+        file.write_all(record)?;
+
+        Ok(())
+    }
+}
+
+impl Drop for Writer {
+
+    fn drop(&mut self) {
+        // Indices that have not been completed (e.g. because the timeline was
+        // interrupted) are useless, so they are not left behind.
+        if self.file.take().is_some() {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// A reader of paths stored in an existing index.
+pub struct Reader {
+    /// A decompressing stream reading from the index file.
+    file: GzDecoder<BufReader<File>>,
+}
+
+impl Reader {
+
+    /// Opens an index with the given `digest` from `state_dir`.
+    ///
+    /// If the index does not exist or has been created for traversal with a
+    /// key other than `key`, `None` is returned.
+    pub fn open(state_dir: &Path, digest: &[u8], key: &[u8]) -> Result<Option<Reader>> {
+        let path = state_dir.join(DIR_NAME).join(hex(digest));
+
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(ref error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+
+        let mut reader = Reader {
+            file: GzDecoder::new(BufReader::new(file)),
+        };
+
+        match reader.read()? {
+            Some(ref stored) if stored.as_slice() == key => Ok(Some(reader)),
+            _ => {
+                warn!("timeline snapshot '{}' does not match the request", hex(digest));
+                Ok(None)
+            }
+        }
+    }
+
+    /// Reads a single length-prefixed record from the index.
+    fn read(&mut self) -> Result<Option<Vec<u8>>> {
+        let len = match self.file.read_u32::<LittleEndian>() {
+            Ok(len) => len,
+            Err(ref error) if error.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(error) => return Err(error),
+        };
+
+        let mut record = vec![0; len as usize];
+        self.file.read_exact(&mut record)?;
+
+        Ok(Some(record))
+    }
+}
+
+impl Iterator for Reader {
+
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Result<Vec<u8>>> {
+        self.read().transpose()
+    }
+}
+
+/// Removes all but `keep` most recently modified indices in `dir`.
+///
+/// The `current` index is always kept (and counts towards the limit).
+fn prune(dir: &Path, current: &Path, keep: usize) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(error) => {
+            warn!("failed to list timeline snapshots: {}", error);
+            return;
+        }
+    };
+
+    let mut snapshots = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+        .filter_map(|entry| {
+            let modified = entry.metadata().and_then(|metadata| metadata.modified());
+            modified.ok().map(|modified| (modified, entry.path()))
+        })
+        .filter(|(_, path)| path != current)
+        .collect::<Vec<_>>();
+
+    snapshots.sort_by(|lhs, rhs| rhs.0.cmp(&lhs.0));
+
+    for (_, path) in snapshots.into_iter().skip(keep.saturating_sub(1)) {
+        if let Err(error) = std::fs::remove_file(&path) {
+            warn!("failed to remove timeline snapshot '{}': {}", path.display(), error);
+        }
+    }
+}
+
+/// Formats the given bytes as a lowercase hexadecimal string.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_write_and_read() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let mut writer = Writer::create(tempdir.path(), b"key").unwrap();
+        writer.add(b"/foo").unwrap();
+        writer.add(b"/foo/bar").unwrap();
+        writer.add(b"").unwrap();
+        let digest = writer.finish().unwrap();
+
+        let reader = Reader::open(tempdir.path(), &digest, b"key").unwrap().unwrap();
+        let paths = reader.collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(paths, vec![b"/foo".to_vec(), b"/foo/bar".to_vec(), b"".to_vec()]);
+    }
+
+    #[test]
+    fn test_digest_depends_on_content() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let mut writer = Writer::create(tempdir.path(), b"key").unwrap();
+        writer.add(b"/foo").unwrap();
+        let digest_foo = writer.finish().unwrap();
+
+        let mut writer = Writer::create(tempdir.path(), b"key").unwrap();
+        writer.add(b"/bar").unwrap();
+        let digest_bar = writer.finish().unwrap();
+
+        assert_ne!(digest_foo, digest_bar);
+    }
+
+    #[test]
+    fn test_open_missing() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let reader = Reader::open(tempdir.path(), b"\x00\x01", b"key").unwrap();
+        assert!(reader.is_none());
+    }
+
+    #[test]
+    fn test_open_key_mismatch() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let writer = Writer::create(tempdir.path(), b"foo").unwrap();
+        let digest = writer.finish().unwrap();
+
+        let reader = Reader::open(tempdir.path(), &digest, b"bar").unwrap();
+        assert!(reader.is_none());
+    }
+
+    #[test]
+    fn test_abandoned() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let mut writer = Writer::create(tempdir.path(), b"key").unwrap();
+        writer.add(b"/foo").unwrap();
+        drop(writer);
+
+        let count = std::fs::read_dir(tempdir.path().join(DIR_NAME)).unwrap().count();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_prune() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let mut digest = vec![];
+        for i in 0..(MAX_SNAPSHOTS + 2) {
+            let mut writer = Writer::create(tempdir.path(), b"key").unwrap();
+            writer.add(format!("/{}", i).as_bytes()).unwrap();
+            digest = writer.finish().unwrap();
+        }
+
+        let count = std::fs::read_dir(tempdir.path().join(DIR_NAME)).unwrap().count();
+        assert_eq!(count, MAX_SNAPSHOTS);
+
+        let reader = Reader::open(tempdir.path(), &digest, b"key").unwrap();
+        assert!(reader.is_some());
+    }
+}
@@ -0,0 +1,250 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Utilities for estimating the skew of the agent clock.
+//!
+//! Timestamps collected by the agent (e.g. in the timeline) are only useful if
+//! it is known how far the local clock is from the server one. Every message
+//! sent by the server carries the time it was issued at, so the agent compares
+//! it with its own clock to get a sample of the difference.
+//!
+//! Each sample is biased by the time it took the message to be delivered, and
+//! this delay is far from negligible: Fleetspeak can hold messages for hours if
+//! the agent is offline. Because the delay can only make samples smaller, the
+//! estimate is the greatest of the recent samples, i.e. the one of the message
+//! delivered the fastest. Delayed messages are thus ignored unless all of the
+//! recent ones were delayed.
+//!
+//! The estimate is persisted in the state directory (if there is one), so that
+//! it is known right after the agent starts, before any server message arrives.
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use lazy_static::lazy_static;
+use log::error;
+
+/// A number of recent samples the estimate is based on.
+const WINDOW_SIZE: usize = 16;
+
+/// A name of the file within the state directory with the persisted estimate.
+const ESTIMATE_FILE_NAME: &'static str = "clock";
+
+lazy_static! {
+    /// The current clock skew estimate (if any message has been observed).
+    static ref ESTIMATE: Mutex<Option<Estimate>> = Mutex::new(None);
+
+    /// A path to the file to persist the estimate in (if enabled).
+    static ref ESTIMATE_FILE: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+/// An estimate of the clock skew based on a window of recent samples.
+#[derive(Clone, Debug)]
+struct Estimate {
+    /// Recent samples (in microseconds), the oldest ones first.
+    samples: VecDeque<i64>,
+}
+
+impl Estimate {
+
+    /// Creates a new estimate based on a single `sample` (in microseconds).
+    fn new(sample: i64) -> Estimate {
+        let mut samples = VecDeque::with_capacity(WINDOW_SIZE);
+        samples.push_back(sample);
+
+        Estimate {
+            samples: samples,
+        }
+    }
+
+    /// Incorporates another `sample` (in microseconds) into the estimate.
+    ///
+    /// Once the window is full, the oldest sample is discarded.
+    fn update(&mut self, sample: i64) {
+        if self.samples.len() == WINDOW_SIZE {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    /// Returns the estimated skew (in microseconds).
+    fn skew(&self) -> i64 {
+        // The window always has at least one sample, so the default value is
+        // never used.
+        self.samples.iter().copied().max().unwrap_or(0)
+    }
+}
+
+/// Initializes the estimate with the one persisted in the `state_dir`.
+///
+/// Subsequent estimates are persisted there as well. If no state directory is
+/// given, the estimate is only kept in memory.
+pub fn init(state_dir: Option<&Path>) {
+    let path = match state_dir {
+        Some(state_dir) => state_dir.join(ESTIMATE_FILE_NAME),
+        None => return,
+    };
+
+    match read_estimate(&path) {
+        Ok(Some(skew)) => *lock(&ESTIMATE) = Some(Estimate::new(skew)),
+        Ok(None) => (),
+        Err(error) => {
+            error!("failed to read the clock skew estimate '{}': {}",
+                   path.display(), error);
+        }
+    }
+
+    *lock(&ESTIMATE_FILE) = Some(path);
+}
+
+/// Records that a server message issued at `server_micros` has just arrived.
+///
+/// The timestamp is expressed in microseconds since the epoch, as used in GRR
+/// messages.
+pub fn observe(server_micros: u64) {
+    let local_micros = match rrg_proto::micros(SystemTime::now()) {
+        Ok(local_micros) => local_micros,
+        Err(error) => {
+            error!("failed to obtain the local time: {}", error);
+            return;
+        }
+    };
+
+    let sample = server_micros as i64 - local_micros as i64;
+
+    // Most samples do not change the estimate, so it is persisted only when it
+    // does rather than on every message.
+    let skew = match record(&mut lock(&ESTIMATE), sample) {
+        Some(skew) => skew,
+        None => return,
+    };
+
+    if let Some(path) = lock(&ESTIMATE_FILE).as_ref() {
+        if let Err(error) = std::fs::write(path, skew.to_string()) {
+            error!("failed to persist the clock skew estimate '{}': {}",
+                   path.display(), error);
+        }
+    }
+}
+
+/// Incorporates the `sample` into the `estimate` (creating it if necessary).
+///
+/// The new skew is returned only if it differs from the previous one.
+fn record(estimate: &mut Option<Estimate>, sample: i64) -> Option<i64> {
+    let old_skew = estimate.as_ref().map(Estimate::skew);
+    match *estimate {
+        Some(ref mut estimate) => estimate.update(sample),
+        None => *estimate = Some(Estimate::new(sample)),
+    }
+
+    let new_skew = estimate.as_ref().map(Estimate::skew);
+    if new_skew == old_skew {
+        return None;
+    }
+
+    new_skew
+}
+
+/// Returns the estimated clock skew in microseconds (if known).
+///
+/// The skew is positive if the agent clock is behind the server one and
+/// negative if it is ahead of it.
+pub fn skew_micros() -> Option<i64> {
+    lock(&ESTIMATE).as_ref().map(Estimate::skew)
+}
+
+/// Reads the estimate persisted in the file at `path` (if it exists).
+fn read_estimate(path: &Path) -> std::io::Result<Option<i64>> {
+    use std::io::{Error, ErrorKind};
+
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(ref error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error),
+    };
+
+    let skew = content.trim().parse()
+        .map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+
+    Ok(Some(skew))
+}
+
+/// Locks the given `mutex`, ignoring poisoning.
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_estimate_single_sample() {
+        let estimate = Estimate::new(-1_000_000);
+        assert_eq!(estimate.skew(), -1_000_000);
+    }
+
+    #[test]
+    fn test_estimate_least_delayed() {
+        let mut estimate = Estimate::new(4_000_000);
+        estimate.update(5_000_000);
+        estimate.update(4_500_000);
+
+        assert_eq!(estimate.skew(), 5_000_000);
+    }
+
+    #[test]
+    fn test_estimate_rejects_delayed() {
+        let mut estimate = Estimate::new(1_000_000);
+        // A message held by Fleetspeak for an hour.
+        estimate.update(1_000_000 - 3_600_000_000);
+
+        assert_eq!(estimate.skew(), 1_000_000);
+    }
+
+    #[test]
+    fn test_estimate_forgets_old_samples() {
+        let mut estimate = Estimate::new(5_000_000);
+        for _ in 0..WINDOW_SIZE {
+            estimate.update(-5_000_000);
+        }
+
+        assert_eq!(estimate.skew(), -5_000_000);
+    }
+
+    #[test]
+    fn test_record_reports_changes_only() {
+        let mut estimate = None;
+        assert_eq!(record(&mut estimate, 1_000_000), Some(1_000_000));
+        assert_eq!(record(&mut estimate, 500_000), None);
+        assert_eq!(record(&mut estimate, 1_000_000), None);
+        assert_eq!(record(&mut estimate, 2_000_000), Some(2_000_000));
+    }
+
+    #[test]
+    fn test_read_estimate() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join(ESTIMATE_FILE_NAME);
+        assert_eq!(read_estimate(&path).unwrap(), None);
+
+        std::fs::write(&path, "-42").unwrap();
+        assert_eq!(read_estimate(&path).unwrap(), Some(-42));
+
+        std::fs::write(&path, "foo").unwrap();
+        assert!(read_estimate(&path).is_err());
+    }
+
+    #[test]
+    fn test_observe() {
+        let now = rrg_proto::micros(SystemTime::now()).unwrap();
+        observe(now + 3_600_000_000);
+
+        let skew = skew_micros().unwrap();
+        assert!(skew > 0);
+    }
+}
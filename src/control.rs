@@ -0,0 +1,227 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Utilities for handling agent control commands.
+//!
+//! Apart from action requests issued by flows, the agent accepts a handful of
+//! agent-level commands (such as changing the log verbosity). These are sent
+//! through a dedicated Fleetspeak service and are handled outside of the session
+//! pipeline: they are not associated with any flow, are not journaled and do
+//! not report any status other than their own response.
+//!
+//! Because Fleetspeak packets are decoded before their service is known, the
+//! commands are wrapped in GRR messages (with the command in the arguments) just
+//! like ordinary requests.
+
+use log::{error, info, LevelFilter};
+
+use rrg_proto::rrg::control_request::Command as CommandProto;
+
+use crate::logging;
+use crate::message;
+use crate::metrics;
+use crate::opts::{Opts, Verbosity};
+use crate::session::{MissingFieldError, ParseError};
+
+/// A name of the Fleetspeak service carrying control commands.
+pub const SERVICE_NAME: &'static str = "RRGControl";
+
+/// A kind of Fleetspeak packets with control commands.
+pub const REQUEST_KIND: &'static str = "ControlRequest";
+
+/// A kind of Fleetspeak packets with results of control commands.
+pub const RESPONSE_KIND: &'static str = "ControlResponse";
+
+/// An agent-level command.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Reloads the log verbosity (the same way as on `SIGHUP`).
+    ReloadLogVerbosity,
+    /// Changes the log verbosity to the given level.
+    SetLogLevel(LevelFilter),
+    /// Reports agent statistics.
+    GetStats,
+    /// Restarts the agent.
+    Restart,
+}
+
+impl Command {
+
+    /// Parses a command from the raw control request `proto`.
+    pub fn from_proto(proto: rrg_proto::rrg::ControlRequest) -> Result<Command, ParseError> {
+        let command = proto.command
+            .ok_or(MissingFieldError::new("command"))?;
+
+        match CommandProto::from_i32(command) {
+            Some(CommandProto::ReloadLogVerbosity) => Ok(Command::ReloadLogVerbosity),
+            Some(CommandProto::SetLogLevel) => {
+                let level = proto.log_level
+                    .ok_or(MissingFieldError::new("log level"))?;
+
+                let verbosity = level.parse::<Verbosity>()
+                    .map_err(ParseError::malformed)?;
+
+                Ok(Command::SetLogLevel(verbosity.level()))
+            }
+            Some(CommandProto::GetStats) => Ok(Command::GetStats),
+            Some(CommandProto::Restart) => Ok(Command::Restart),
+            Some(CommandProto::Unknown) | None => {
+                Err(ParseError::malformed(format!("unknown command: {}", command)))
+            }
+        }
+    }
+
+    /// Yields a raw proto value corresponding to this command.
+    fn proto(&self) -> CommandProto {
+        match *self {
+            Command::ReloadLogVerbosity => CommandProto::ReloadLogVerbosity,
+            Command::SetLogLevel(_) => CommandProto::SetLogLevel,
+            Command::GetStats => CommandProto::GetStats,
+            Command::Restart => CommandProto::Restart,
+        }
+    }
+}
+
+/// An action the agent has to take once a control command has been handled.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    /// The agent carries on as usual.
+    Continue,
+    /// The agent has to restart.
+    Restart,
+}
+
+/// Processes the given control `message`, handling all errors.
+///
+/// The result of the command is sent back through the control service. Note
+/// that the restart command is not executed here: the caller is responsible for
+/// dealing with pending requests and terminating the process (the agent is
+/// expected to be started again by Fleetspeak). Since messages are processed one
+/// at a time, no action is running at this point.
+pub fn handle(message: rrg_proto::GrrMessage, opts: &Opts) -> Outcome {
+    let command = match parse(message, opts.max_message_size) {
+        Ok(command) => command,
+        Err(error) => {
+            error!("failed to parse the control message: {}", error);
+            return Outcome::Continue;
+        }
+    };
+
+    info!("requested to execute the {:?} control command", command);
+
+    let response = execute(&command, opts);
+    if let Err(error) = message::send_control(response) {
+        error!("failed to send the control response: {}", error);
+    }
+
+    match command {
+        Command::Restart => Outcome::Restart,
+        _ => Outcome::Continue,
+    }
+}
+
+/// Extracts the control command from the given GRR `message`.
+///
+/// Compressed arguments bigger than `max_size` bytes once decompressed are
+/// rejected.
+fn parse(message: rrg_proto::GrrMessage, max_size: usize) -> Result<Command, ParseError> {
+    let message = message::decompress(message, max_size)
+        .map_err(ParseError::malformed)?;
+
+    let args = message.args
+        .ok_or(MissingFieldError::new("arguments"))?;
+
+    let proto = prost::Message::decode(&args[..])?;
+    Command::from_proto(proto)
+}
+
+/// Executes the given `command` and yields the response to send back.
+///
+/// The restart command is not actually executed here, as the response has to
+/// be sent before the process terminates.
+fn execute(command: &Command, opts: &Opts) -> rrg_proto::rrg::ControlResponse {
+    let mut response = rrg_proto::rrg::ControlResponse {
+        command: Some(command.proto().into()),
+        ..Default::default()
+    };
+
+    match *command {
+        Command::ReloadLogVerbosity => {
+            logging::reload(opts.log_verbosity, opts.log_verbosity_file.as_deref());
+        }
+        Command::SetLogLevel(level) => {
+            logging::set_level(level, None);
+        }
+        Command::GetStats => {
+            response.stats = Some(metrics::render());
+        }
+        Command::Restart => (),
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn request(command: CommandProto) -> rrg_proto::rrg::ControlRequest {
+        rrg_proto::rrg::ControlRequest {
+            command: Some(command.into()),
+            log_level: None,
+        }
+    }
+
+    #[test]
+    fn test_from_proto_simple() {
+        let command = Command::from_proto(request(CommandProto::Restart)).unwrap();
+        assert_eq!(command, Command::Restart);
+    }
+
+    #[test]
+    fn test_from_proto_log_level() {
+        let mut proto = request(CommandProto::SetLogLevel);
+        proto.log_level = Some(String::from("debug"));
+
+        let command = Command::from_proto(proto).unwrap();
+        assert_eq!(command, Command::SetLogLevel(LevelFilter::Debug));
+    }
+
+    #[test]
+    fn test_from_proto_log_level_missing() {
+        let proto = request(CommandProto::SetLogLevel);
+        assert!(Command::from_proto(proto).is_err());
+    }
+
+    #[test]
+    fn test_from_proto_unknown() {
+        assert!(Command::from_proto(request(CommandProto::Unknown)).is_err());
+
+        let proto = rrg_proto::rrg::ControlRequest {
+            command: Some(1337),
+            log_level: None,
+        };
+        assert!(Command::from_proto(proto).is_err());
+    }
+
+    #[test]
+    fn test_parse_message() {
+        let mut args = Vec::new();
+        prost::Message::encode(&request(CommandProto::GetStats), &mut args).unwrap();
+
+        let message = rrg_proto::GrrMessage {
+            args: Some(args),
+            ..Default::default()
+        };
+
+        assert_eq!(parse(message, 1024).unwrap(), Command::GetStats);
+    }
+
+    #[test]
+    fn test_parse_message_without_args() {
+        assert!(parse(rrg_proto::GrrMessage::default(), 1024).is_err());
+    }
+}
@@ -0,0 +1,309 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Utilities for reporting agent crashes.
+//!
+//! When the agent panics, the server would normally notice only that the client
+//! went silent. To avoid this, a panic hook tries to send the crash details to
+//! a dedicated sink before the agent exits. Because the Fleetspeak connection
+//! might be the very reason of the crash, the details are also persisted as a
+//! crash marker in the state directory and reported in the startup information
+//! once the agent starts again.
+//!
+//! Only panics of the main thread terminate the agent. Panics of other threads
+//! (e.g. filesystem walkers) end just these threads and it is up to their owners
+//! to deal with them, so they are not reported as crashes.
+
+use std::convert::TryInto;
+use std::panic::PanicInfo;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use lazy_static::lazy_static;
+use log::error;
+
+use crate::message;
+use crate::metadata::Metadata;
+use crate::session::Sink;
+
+/// A name of the crash marker file within the state directory.
+const MARKER_FILE_NAME: &'static str = "crash";
+
+lazy_static! {
+    /// An action that is being currently executed (if any).
+    static ref ACTION: Mutex<Option<Action>> = Mutex::new(None);
+
+    /// A crash that ended the previous run of the agent (if any).
+    static ref PREVIOUS: Mutex<Option<rrg_proto::ClientCrash>> = Mutex::new(None);
+}
+
+/// Information about the action being executed.
+#[derive(Clone, Debug)]
+struct Action {
+    /// A name of the action.
+    name: String,
+    /// A server-issued session identifier of the action request.
+    session_id: String,
+}
+
+/// Details of an agent crash.
+#[derive(Debug)]
+pub struct Crash {
+    /// A message the agent panicked with (including its location).
+    pub message: String,
+    /// A backtrace of the panicking thread.
+    pub backtrace: String,
+    /// A version of the crashed agent.
+    pub version: String,
+    /// A name of the action that was being executed (if any).
+    pub action: Option<String>,
+    /// A session identifier of the action that was being executed (if any).
+    pub session_id: Option<String>,
+    /// A moment at which the crash happened.
+    pub timestamp: SystemTime,
+}
+
+impl Crash {
+
+    /// Collects crash details from the given panic `info`.
+    fn from_panic(info: &PanicInfo) -> Crash {
+        let message = panic_message(info.payload());
+        let message = match info.location() {
+            Some(location) => format!("{} (at {})", message, location),
+            None => message,
+        };
+
+        // We must not block (or panic) in the panic hook, so if the lock is not
+        // available right away we rather lose the information about the action.
+        let action = match ACTION.try_lock() {
+            Ok(action) => action.clone(),
+            Err(_) => None,
+        };
+
+        Crash {
+            message: message,
+            backtrace: format!("{:?}", backtrace::Backtrace::new()),
+            version: String::from(env!("CARGO_PKG_VERSION")),
+            action: action.as_ref().map(|action| action.name.clone()),
+            session_id: action.map(|action| action.session_id),
+            timestamp: SystemTime::now(),
+        }
+    }
+}
+
+/// A response type for crash reports.
+pub struct Response {
+    /// Details of the crash to report.
+    crash: Crash,
+}
+
+impl crate::action::Response for Response {
+
+    const RDF_NAME: Option<&'static str> = Some("ClientCrash");
+
+    type Proto = rrg_proto::ClientCrash;
+
+    fn into_proto(self) -> rrg_proto::ClientCrash {
+        let message = match self.crash.action {
+            Some(ref action) => {
+                format!("{} [version {}, action '{}']",
+                        self.crash.message, self.crash.version, action)
+            }
+            None => {
+                format!("{} [version {}]", self.crash.message, self.crash.version)
+            }
+        };
+
+        rrg_proto::ClientCrash {
+            session_id: self.crash.session_id,
+            client_info: Some(Metadata::from_cargo().into()),
+            timestamp: rrg_proto::micros(self.crash.timestamp).ok(),
+            crash_type: Some(String::from("panic")),
+            crash_message: Some(message),
+            backtrace: Some(self.crash.backtrace),
+            ..Default::default()
+        }
+    }
+}
+
+/// Installs the crash reporting panic hook.
+///
+/// If `state_dir` is specified, the hook persists a crash marker there. The
+/// marker left by the previous run of the agent (if any) is consumed and made
+/// available through the [`previous`] function.
+///
+/// This function should be called as early as possible, so that the hook is
+/// in place even if the agent initialization fails.
+///
+/// [`previous`]: fn.previous.html
+pub fn init(state_dir: Option<&Path>) {
+    let marker = state_dir.map(|state_dir| state_dir.join(MARKER_FILE_NAME));
+
+    if let Some(ref marker) = marker {
+        match read_marker(marker) {
+            Ok(crash) => *lock(&PREVIOUS) = crash,
+            Err(error) => {
+                error!("failed to read the crash marker '{}': {}",
+                       marker.display(), error);
+            }
+        }
+    }
+
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if is_fatal(&std::thread::current()) {
+            report(Crash::from_panic(info), marker.as_ref());
+        }
+        default_hook(info);
+    }));
+}
+
+/// Extracts a human-readable message from the given panic `payload`.
+pub fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        String::from(*message)
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("unknown panic payload")
+    }
+}
+
+/// Checks whether a panic of the given `thread` terminates the agent.
+fn is_fatal(thread: &std::thread::Thread) -> bool {
+    thread.name() == Some("main")
+}
+
+/// Retrieves the crash that ended the previous run of the agent (if any).
+pub fn previous() -> Option<rrg_proto::ClientCrash> {
+    lock(&PREVIOUS).clone()
+}
+
+/// Marks the given action as being currently executed.
+///
+/// It should be called with `None` once the action execution is finished.
+pub fn set_action(action: Option<(&str, &str)>) {
+    *lock(&ACTION) = action.map(|(name, session_id)| Action {
+        name: String::from(name),
+        session_id: String::from(session_id),
+    });
+}
+
+/// Persists the `crash` in the `marker` file and sends it to the server.
+///
+/// Note that this function is called from within the panic hook, so it must not
+/// panic itself. All the errors are merely logged.
+fn report(crash: Crash, marker: Option<&PathBuf>) {
+    let message: rrg_proto::GrrMessage = match Sink::CRASH.wrap(Response { crash }).try_into() {
+        Ok(message) => message,
+        Err(error) => {
+            error!("failed to encode the crash report: {}", error);
+            return;
+        }
+    };
+
+    // The marker is written first, because it is more likely to succeed than
+    // delivering the message through the (possibly broken) Fleetspeak pipe.
+    if let Some(marker) = marker {
+        if let Err(error) = write_marker(marker, &message) {
+            error!("failed to write the crash marker '{}': {}",
+                   marker.display(), error);
+        }
+    }
+
+    if let Err(error) = message::try_send(message) {
+        error!("failed to send the crash report: {}", error);
+    }
+}
+
+/// Writes the crash details carried by the `message` to the `marker` file.
+fn write_marker(marker: &Path, message: &rrg_proto::GrrMessage) -> std::io::Result<()> {
+    let data = message.args.as_ref().map_or(&[][..], Vec::as_slice);
+    std::fs::write(marker, data)
+}
+
+/// Reads and removes the crash `marker` file (if it exists).
+fn read_marker(marker: &Path) -> std::io::Result<Option<rrg_proto::ClientCrash>> {
+    let data = match std::fs::read(marker) {
+        Ok(data) => data,
+        Err(ref error) if error.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(None);
+        }
+        Err(error) => return Err(error),
+    };
+    std::fs::remove_file(marker)?;
+
+    let crash = prost::Message::decode(&data[..])
+        .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))?;
+
+    Ok(Some(crash))
+}
+
+/// Locks the given `mutex`, ignoring lock poisoning.
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_read_marker_non_existing() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let marker = tempdir.path().join(MARKER_FILE_NAME);
+
+        assert!(read_marker(&marker).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_write_and_read_marker() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let marker = tempdir.path().join(MARKER_FILE_NAME);
+
+        let crash = Crash {
+            message: String::from("foo"),
+            backtrace: String::from("bar"),
+            version: String::from("1.2.3"),
+            action: Some(String::from("Timeline")),
+            session_id: Some(String::from("F:ABC")),
+            timestamp: SystemTime::now(),
+        };
+
+        let message: rrg_proto::GrrMessage = Sink::CRASH.wrap(Response { crash })
+            .try_into().unwrap();
+        write_marker(&marker, &message).unwrap();
+
+        let crash = read_marker(&marker).unwrap().unwrap();
+        assert_eq!(crash.session_id, Some(String::from("F:ABC")));
+        assert_eq!(crash.crash_type, Some(String::from("panic")));
+        assert_eq!(crash.backtrace, Some(String::from("bar")));
+
+        let message = crash.crash_message.unwrap();
+        assert!(message.contains("foo"));
+        assert!(message.contains("1.2.3"));
+        assert!(message.contains("Timeline"));
+
+        // The marker should be consumed after reading.
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn test_is_fatal_other_thread() {
+        let thread = std::thread::spawn(std::thread::current).join().unwrap();
+        assert!(!is_fatal(&thread));
+    }
+
+    #[test]
+    fn test_read_marker_malformed() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let marker = tempdir.path().join(MARKER_FILE_NAME);
+        std::fs::write(&marker, b"\xff\xff\xff").unwrap();
+
+        assert!(read_marker(&marker).is_err());
+    }
+}
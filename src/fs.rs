@@ -9,20 +9,238 @@
 //! standard `std::fs` module. All functions are portable and should work on all
 //! supported platforms (perhaps with limited capabilities).
 
+mod parallel;
+mod pattern;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
 use std::fs::Metadata;
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use log::warn;
 
+pub use self::parallel::ParallelWalk;
+pub use self::pattern::{Expand, Pattern, PatternError};
+
 /// A path to a filesystem item and associated metadata.
 ///
 /// This type is very similar to standard `DirEntry` but its `metadata` property
 /// is guaranteed to always be there.
+#[derive(Debug)]
 pub struct Entry {
     /// A path to the filesystem item.
     pub path: PathBuf,
     /// Metadata associated with the item.
     pub metadata: Metadata,
+    /// A number of components between the traversal root and the item.
+    ///
+    /// The root itself has depth 0, its immediate children have depth 1 and so
+    /// on. Entries yielded by [`list_dir`] always have depth 1.
+    ///
+    /// [`list_dir`]: fn.list_dir.html
+    pub depth: usize,
+}
+
+/// Options that control how [`WalkDir`] and [`ParallelWalk`] traverse the
+/// filesystem.
+///
+/// The default options match the behaviour of the [`walk_dir`] function: the
+/// traversal has no depth limit, does not cross device boundaries, does not
+/// follow symlinks, does not prune anything and yields entries in the order in
+/// which the operating system lists them.
+///
+/// # Examples
+///
+/// ```no_run
+/// use rrg::fs::WalkOptions;
+///
+/// let iter = WalkOptions::new()
+///     .max_depth(2)
+///     .prune(|entry| entry.path.ends_with("proc"))
+///     .sorted(true)
+///     .walk("/")
+///     .unwrap();
+///
+/// for entry in iter {
+///     assert!(entry.depth <= 2);
+/// }
+/// ```
+///
+/// [`WalkDir`]: struct.WalkDir.html
+/// [`ParallelWalk`]: struct.ParallelWalk.html
+/// [`walk_dir`]: fn.walk_dir.html
+pub struct WalkOptions {
+    max_depth: Option<usize>,
+    cross_device: bool,
+    follow_links: bool,
+    prune: Option<Arc<dyn Fn(&Entry) -> bool + Send + Sync>>,
+    sorted: bool,
+    threads: usize,
+}
+
+impl WalkOptions {
+
+    /// Creates default traversal options.
+    pub fn new() -> WalkOptions {
+        WalkOptions {
+            max_depth: None,
+            cross_device: false,
+            follow_links: false,
+            prune: None,
+            sorted: false,
+            threads: parallel::DEFAULT_THREADS,
+        }
+    }
+
+    /// Limits the traversal to entries at most `depth` levels below the root.
+    pub fn max_depth(mut self, depth: usize) -> WalkOptions {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Allows the traversal to enter directories mounted on other devices.
+    pub fn cross_device(mut self, cross_device: bool) -> WalkOptions {
+        self.cross_device = cross_device;
+        self
+    }
+
+    /// Makes the traversal follow symlinks.
+    ///
+    /// Entries for followed symlinks have metadata of their targets attached
+    /// (unless the symlink is broken). To avoid cycles, every directory (as
+    /// identified by its device and inode number) is entered at most once.
+    pub fn follow_links(mut self, follow_links: bool) -> WalkOptions {
+        self.follow_links = follow_links;
+        self
+    }
+
+    /// Excludes entries satisfying the `predicate` from the traversal.
+    ///
+    /// Pruned entries are not yielded and, if they are directories, none of
+    /// their descendants are visited. The predicate is never applied to the
+    /// root itself.
+    pub fn prune<F>(mut self, predicate: F) -> WalkOptions
+    where
+        F: Fn(&Entry) -> bool + Send + Sync + 'static,
+    {
+        self.prune = Some(Arc::new(predicate));
+        self
+    }
+
+    /// Makes the traversal yield children of every directory sorted by name.
+    ///
+    /// This makes the order of yielded entries deterministic at the expense of
+    /// collecting all the children of a directory before yielding any of them.
+    pub fn sorted(mut self, sorted: bool) -> WalkOptions {
+        self.sorted = sorted;
+        self
+    }
+
+    /// Sets the number of threads used by the parallel traversal.
+    ///
+    /// This option has no effect on the traversal with the [`walk`] method.
+    ///
+    /// [`walk`]: struct.WalkOptions.html#method.walk
+    pub fn threads(mut self, threads: usize) -> WalkOptions {
+        self.threads = std::cmp::max(threads, 1);
+        self
+    }
+
+    /// Returns a deep iterator over entries within the `root` directory.
+    ///
+    /// See the [`walk_dir`] function for more details.
+    ///
+    /// [`walk_dir`]: fn.walk_dir.html
+    pub fn walk<P: AsRef<Path>>(self, root: P) -> std::io::Result<WalkDir> {
+        let root = self.root(root.as_ref())?;
+
+        let mut walk = WalkDir {
+            root: None,
+            error: None,
+            pending: vec![],
+            options: self,
+            visited: HashSet::new(),
+            dev: device(&root.metadata),
+        };
+
+        // Unlike with other directories, failure to list the root is an error.
+        // Roots that are not directories are yielded on their own.
+        if root.metadata.is_dir() {
+            let iter = list_dir(&root.path)?;
+            if walk.descends(&root) {
+                let children = walk.children(iter);
+                walk.pending.push(children);
+            }
+        }
+        walk.root = Some(root);
+
+        Ok(walk)
+    }
+}
+
+impl WalkOptions {
+
+    /// Collects the entry of the traversal root at `path`.
+    fn root(&self, path: &Path) -> std::io::Result<Entry> {
+        let metadata = if self.follow_links {
+            std::fs::metadata(path)?
+        } else {
+            std::fs::symlink_metadata(path)?
+        };
+
+        Ok(Entry {
+            path: path.to_path_buf(),
+            metadata: metadata,
+            depth: 0,
+        })
+    }
+
+    /// Replaces metadata of a symlink with metadata of its target if requested.
+    fn resolve(&self, entry: &mut Entry) {
+        if !self.follow_links || !entry.metadata.file_type().is_symlink() {
+            return;
+        }
+
+        // Broken symlinks are yielded with the metadata of the link itself.
+        if let Ok(metadata) = std::fs::metadata(&entry.path) {
+            entry.metadata = metadata;
+        }
+    }
+
+    fn prunes(&self, entry: &Entry) -> bool {
+        match self.prune {
+            Some(ref predicate) => predicate(entry),
+            None => false,
+        }
+    }
+
+    /// Checks whether the traversal of a tree on the `dev` device should enter
+    /// the given entry.
+    ///
+    /// Note that this method does not check for cycles, this has to be done
+    /// separately if symlinks are followed.
+    fn enters(&self, entry: &Entry, dev: u64) -> bool {
+        if !entry.metadata.is_dir() {
+            return false;
+        }
+
+        if let Some(max_depth) = self.max_depth {
+            if entry.depth >= max_depth {
+                return false;
+            }
+        }
+
+        self.cross_device || device(&entry.metadata) == dev
+    }
+}
+
+impl Default for WalkOptions {
+
+    fn default() -> WalkOptions {
+        WalkOptions::new()
+    }
 }
 
 /// Returns a deep iterator over entries within a directory.
@@ -32,7 +250,8 @@ pub struct Entry {
 ///
 /// Note that symlinked folders or directories mounted to a different device
 /// than the root will not be recursively searched. This is done to avoid cycles
-/// and undesired traversal of network filesystems (which can be very flow).
+/// and undesired traversal of network filesystems (which can be very flow). Use
+/// [`WalkOptions`] to customize this behaviour.
 ///
 /// # Errors
 ///
@@ -53,21 +272,10 @@ pub struct Entry {
 /// assert!(items.contains(&PathBuf::from("/usr/bin")));
 /// assert!(items.contains(&PathBuf::from("/usr/lib")));
 /// ```
+///
+/// [`WalkOptions`]: struct.WalkOptions.html
 pub fn walk_dir<P: AsRef<Path>>(root: P) -> std::io::Result<WalkDir> {
-    let metadata = std::fs::symlink_metadata(&root)?;
-    let pending = vec!(list_dir(&root)?);
-
-    #[cfg(target_family = "unix")]
-    let dev = std::os::unix::fs::MetadataExt::dev(&metadata);
-
-    Ok(WalkDir {
-        root: Some(Entry {
-            path: root.as_ref().to_path_buf(),
-            metadata: metadata,
-        }),
-        pending: pending,
-        #[cfg(target_family = "unix")] dev: dev,
-    })
+    WalkOptions::new().walk(root)
 }
 
 /// Returns a shallow iterator over entries within a directory.
@@ -95,46 +303,72 @@ pub fn walk_dir<P: AsRef<Path>>(root: P) -> std::io::Result<WalkDir> {
 /// assert!(items.contains(&PathBuf::from("/tmp")));
 /// ```
 pub fn list_dir<P: AsRef<Path>>(path: P) -> std::io::Result<ListDir> {
-    let iter = std::fs::read_dir(path)?;
+    let iter = std::fs::read_dir(&path)?;
 
     Ok(ListDir {
         iter: iter,
+        path: path.as_ref().to_path_buf(),
+        depth: 1,
     })
 }
 
 /// Iterator over entries in all subdirectories.
 ///
 /// This iterator will recursively descent to all subdirectories and yield
-/// entries for every file encountered along the way. However, during the
+/// entries for every file encountered along the way. By default, during the
 /// traversal it will not cross device boundaries and enter symlinked
-/// directories.
+/// directories (see [`WalkOptions`] for ways to change that).
 ///
-/// Note that this iterator always returns an entry. All errors are simply
-/// swallowed.
+/// Note that this iterator always returns an entry. All errors are logged and
+/// swallowed. Use the [`with_errors`] method to have them yielded instead.
 ///
-/// The iterator can be constructed with the [`walk_dir`] function.
+/// The iterator can be constructed with the [`walk_dir`] function or with the
+/// [`WalkOptions::walk`] method.
 ///
 /// [`walk_dir`]: fn.walk_dir.html
+/// [`with_errors`]: struct.WalkDir.html#method.with_errors
+/// [`WalkOptions`]: struct.WalkOptions.html
+/// [`WalkOptions::walk`]: struct.WalkOptions.html#method.walk
 pub struct WalkDir {
     root: Option<Entry>,
-    pending: Vec<ListDir>,
-    #[cfg(target_family = "unix")] dev: u64,
+    error: Option<WalkError>,
+    pending: Vec<Children>,
+    options: WalkOptions,
+    visited: HashSet<(u64, u64)>,
+    dev: u64,
+}
+
+/// Iterator over children of a single directory visited by [`WalkDir`].
+///
+/// [`WalkDir`]: struct.WalkDir.html
+enum Children {
+    /// Children in the order in which the operating system lists them.
+    Unsorted(ListDir),
+    /// Children (and errors encountered when listing them) sorted by paths.
+    Sorted(std::vec::IntoIter<Result<Entry, WalkError>>),
 }
 
 impl WalkDir {
 
-    fn push(&mut self, entry: &Entry) {
-        match list_dir(&entry.path) {
-            Ok(iter) => {
-                self.pending.push(iter);
-            },
-            Err(error) => {
-                warn!("failed to read '{}': {}", entry.path.display(), error);
-            },
+    /// Turns the iterator into one that yields errors along with entries.
+    pub fn with_errors(self) -> WithErrors<WalkDir> {
+        WithErrors {
+            iter: self,
         }
     }
 
-    fn pop(&mut self) -> Option<Entry> {
+    fn push(&mut self, entry: &Entry) -> Result<(), WalkError> {
+        let mut iter = list_dir(&entry.path)
+            .map_err(|error| WalkError::new(&entry.path, error))?;
+        iter.depth = entry.depth + 1;
+
+        let children = self.children(iter);
+        self.pending.push(children);
+
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<Result<Entry, WalkError>> {
         while let Some(iter) = self.pending.last_mut() {
             for entry in iter {
                 return Some(entry);
@@ -146,14 +380,69 @@ impl WalkDir {
         None
     }
 
-    #[cfg(target_family = "unix")]
-    fn same_dev(&self, entry: &Entry) -> bool {
-        self.dev == std::os::unix::fs::MetadataExt::dev(&entry.metadata)
+    fn children(&self, mut iter: ListDir) -> Children {
+        if self.options.sorted {
+            let mut entries = std::iter::from_fn(|| iter.try_next())
+                .collect::<Vec<_>>();
+            entries.sort_by(|lhs, rhs| result_path(lhs).cmp(result_path(rhs)));
+
+            Children::Sorted(entries.into_iter())
+        } else {
+            Children::Unsorted(iter)
+        }
+    }
+
+    fn try_next(&mut self) -> Option<Result<Entry, WalkError>> {
+        if self.root.is_some() {
+            return self.root.take().map(Ok);
+        }
+
+        // An error of listing a directory is reported right after the entry of
+        // the directory itself.
+        if self.error.is_some() {
+            return self.error.take().map(Err);
+        }
+
+        loop {
+            let mut entry = match self.pop()? {
+                Ok(entry) => entry,
+                Err(error) => return Some(Err(error)),
+            };
+            self.options.resolve(&mut entry);
+
+            if self.options.prunes(&entry) {
+                continue;
+            }
+
+            if self.descends(&entry) {
+                if let Err(error) = self.push(&entry) {
+                    self.error = Some(error);
+                }
+            }
+
+            return Some(Ok(entry));
+        }
     }
 
-    #[cfg(target_family = "windows")]
-    fn same_dev(&self, _entry: &Entry) -> bool {
-        true
+    /// Checks whether the traversal should descend into the given entry.
+    ///
+    /// Note that this method marks the entry as visited if it is a directory
+    /// and symlinks are followed.
+    fn descends(&mut self, entry: &Entry) -> bool {
+        if !self.options.enters(entry, self.dev) {
+            return false;
+        }
+
+        // Without following symlinks there is no way to enter a directory more
+        // than once, so we track visited directories only when necessary.
+        if !self.options.follow_links {
+            return true;
+        }
+
+        match file_id(&entry.metadata) {
+            Some(id) => self.visited.insert(id),
+            None => true,
+        }
     }
 }
 
@@ -162,17 +451,19 @@ impl std::iter::Iterator for WalkDir {
     type Item = Entry;
 
     fn next(&mut self) -> Option<Entry> {
-        if self.root.is_some() {
-            return self.root.take();
-        }
+        swallow(|| self.try_next())
+    }
+}
 
-        let entry = self.pop()?;
+impl std::iter::Iterator for Children {
 
-        if entry.metadata.is_dir() && self.same_dev(&entry) {
-            self.push(&entry);
-        }
+    type Item = Result<Entry, WalkError>;
 
-        Some(entry)
+    fn next(&mut self) -> Option<Result<Entry, WalkError>> {
+        match *self {
+            Children::Unsorted(ref mut iter) => iter.try_next(),
+            Children::Sorted(ref mut iter) => iter.next(),
+        }
     }
 }
 
@@ -180,7 +471,7 @@ impl std::iter::Iterator for WalkDir {
 ///
 /// This iterator is very similar to the standard `ReadDir` iterator, except
 /// that it is swallows errors and only yields entries that did not cause any
-/// errors.
+/// errors. Use the [`with_errors`] method to have the errors yielded instead.
 ///
 /// Unlike the `ReadDir` iterator entries, `ListDir` entries are guaranteed to
 /// have valid metadata objects attached.
@@ -188,8 +479,40 @@ impl std::iter::Iterator for WalkDir {
 /// The iterator can be constructed with the [`list_dir`] function.
 ///
 /// [`list_dir`]: fn.list_dir.html
+/// [`with_errors`]: struct.ListDir.html#method.with_errors
 pub struct ListDir {
     iter: std::fs::ReadDir,
+    path: PathBuf,
+    depth: usize,
+}
+
+impl ListDir {
+
+    /// Turns the iterator into one that yields errors along with entries.
+    pub fn with_errors(self) -> WithErrors<ListDir> {
+        WithErrors {
+            iter: self,
+        }
+    }
+
+    fn try_next(&mut self) -> Option<Result<Entry, WalkError>> {
+        let entry = match self.iter.next()? {
+            Ok(entry) => entry,
+            Err(error) => return Some(Err(WalkError::new(&self.path, error))),
+        };
+
+        let path = entry.path();
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(error) => return Some(Err(WalkError::new(path, error))),
+        };
+
+        Some(Ok(Entry {
+            path: path,
+            metadata: metadata,
+            depth: self.depth,
+        }))
+    }
 }
 
 impl std::iter::Iterator for ListDir {
@@ -197,31 +520,188 @@ impl std::iter::Iterator for ListDir {
     type Item = Entry;
 
     fn next(&mut self) -> Option<Entry> {
-        for entry in &mut self.iter {
-            let entry = match entry {
-                Ok(entry) => entry,
-                Err(error) => {
-                    warn!("directory iteration error: {}", error);
-                    continue
-                },
-            };
+        swallow(|| self.try_next())
+    }
+}
 
-            let path = entry.path();
-            let metadata = match entry.metadata() {
-                Ok(metadata) => metadata,
-                Err(error) => {
-                    warn!("failed to stat '{}': {}", path.display(), error);
-                    continue
-                },
-            };
+/// Iterator adapter that yields errors encountered during the traversal.
+///
+/// The adapter can be constructed with the [`ListDir::with_errors`] and
+/// [`WalkDir::with_errors`] methods. Errors are yielded in place of the entries
+/// they concern. If listing a directory fails, the error follows the entry of
+/// the directory.
+///
+/// [`ListDir::with_errors`]: struct.ListDir.html#method.with_errors
+/// [`WalkDir::with_errors`]: struct.WalkDir.html#method.with_errors
+pub struct WithErrors<I> {
+    iter: I,
+}
+
+impl std::iter::Iterator for WithErrors<ListDir> {
+
+    type Item = Result<Entry, WalkError>;
+
+    fn next(&mut self) -> Option<Result<Entry, WalkError>> {
+        self.iter.try_next()
+    }
+}
+
+impl std::iter::Iterator for WithErrors<WalkDir> {
+
+    type Item = Result<Entry, WalkError>;
+
+    fn next(&mut self) -> Option<Result<Entry, WalkError>> {
+        self.iter.try_next()
+    }
+}
+
+/// Returns an identifier of the device the item with given `metadata` is on.
+#[cfg(target_family = "unix")]
+fn device(metadata: &Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::dev(metadata)
+}
+
+/// Returns an identifier of the device the item with given `metadata` is on.
+#[cfg(target_family = "windows")]
+fn device(_metadata: &Metadata) -> u64 {
+    0
+}
+
+/// Returns a pair of device and inode numbers identifying the item.
+#[cfg(target_family = "unix")]
+fn file_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt as _;
+
+    Some((metadata.dev(), metadata.ino()))
+}
 
-            return Some(Entry {
-                path: path,
-                metadata: metadata,
-            });
+// TODO: Windows does not expose stable file identifiers through the standard
+// library, so cycles are not detected there.
+/// Returns a pair of device and inode numbers identifying the item.
+#[cfg(target_family = "windows")]
+fn file_id(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Pulls items from `try_next` until an entry is found, logging all errors.
+fn swallow<F>(mut try_next: F) -> Option<Entry>
+where
+    F: FnMut() -> Option<Result<Entry, WalkError>>,
+{
+    loop {
+        match try_next()? {
+            Ok(entry) => return Some(entry),
+            Err(error) => warn!("{}", error),
         }
+    }
+}
 
-        None
+/// Returns the path that the result of a traversal step concerns.
+fn result_path(result: &Result<Entry, WalkError>) -> &Path {
+    match *result {
+        Ok(ref entry) => &entry.path,
+        Err(ref error) => &error.path,
+    }
+}
+
+/// An error type for failures to access filesystem items during a traversal.
+#[derive(Debug)]
+pub struct WalkError {
+    /// A path of the item that could not be accessed.
+    path: PathBuf,
+    /// The underlying I/O error.
+    error: std::io::Error,
+}
+
+impl WalkError {
+
+    /// Creates a new error for the item at `path` caused by `error`.
+    pub fn new<P: Into<PathBuf>>(path: P, error: std::io::Error) -> WalkError {
+        WalkError {
+            path: path.into(),
+            error: error,
+        }
+    }
+
+    /// Returns the path of the item that could not be accessed.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the kind of the underlying I/O error.
+    pub fn kind(&self) -> ErrorKind {
+        self.error.kind()
+    }
+}
+
+impl Display for WalkError {
+
+    fn fmt(&self, fmt: &mut Formatter) -> std::fmt::Result {
+        write!(fmt, "failed to access '{}': {}", self.path.display(), self.error)
+    }
+}
+
+impl std::error::Error for WalkError {
+
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// A maximum number of errors kept verbatim in an error summary.
+pub const MAX_SUMMARY_ERRORS: usize = 100;
+
+/// A summary of errors encountered during a filesystem traversal.
+///
+/// The summary keeps counts of all recorded errors (grouped by their kinds),
+/// but only the first [`MAX_SUMMARY_ERRORS`] errors are kept verbatim. This way
+/// a traversal of a tree with millions of inaccessible files does not consume
+/// excessive amounts of memory.
+///
+/// [`MAX_SUMMARY_ERRORS`]: constant.MAX_SUMMARY_ERRORS.html
+#[derive(Debug, Default)]
+pub struct ErrorSummary {
+    /// Numbers of recorded errors of particular kinds.
+    counts: HashMap<ErrorKind, u64>,
+    /// The first recorded errors.
+    errors: Vec<WalkError>,
+}
+
+impl ErrorSummary {
+
+    /// Creates a new empty summary.
+    pub fn new() -> ErrorSummary {
+        ErrorSummary::default()
+    }
+
+    /// Logs the `error` and adds it to the summary.
+    pub fn record(&mut self, error: WalkError) {
+        warn!("{}", error);
+
+        *self.counts.entry(error.kind()).or_insert(0) += 1;
+        if self.errors.len() < MAX_SUMMARY_ERRORS {
+            self.errors.push(error);
+        }
+    }
+
+    /// Checks whether any errors were recorded.
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    /// Returns the total number of recorded errors.
+    pub fn count(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    /// Returns numbers of recorded errors grouped by their kinds.
+    pub fn counts(&self) -> impl Iterator<Item = (ErrorKind, u64)> + '_ {
+        self.counts.iter().map(|(kind, count)| (*kind, *count))
+    }
+
+    /// Returns the errors kept verbatim (in the order they were recorded).
+    pub fn errors(&self) -> &[WalkError] {
+        &self.errors
     }
 }
 
@@ -349,6 +829,20 @@ mod tests {
         assert!(iter.next().is_none());
     }
 
+    #[test]
+    fn test_walk_dir_file() {
+        let tempdir = tempfile::tempdir().unwrap();
+        std::fs::write(tempdir.path().join("foo"), b"").unwrap();
+
+        let mut iter = walk_dir(tempdir.path().join("foo")).unwrap();
+
+        let entry = iter.next().unwrap();
+        assert_eq!(entry.path, tempdir.path().join("foo"));
+        assert!(entry.metadata.is_file());
+
+        assert!(iter.next().is_none());
+    }
+
     #[test]
     fn test_walk_dir_with_flat_files() {
         let tempdir = tempfile::tempdir().unwrap();
@@ -552,4 +1046,235 @@ mod tests {
         assert_eq!(results.len(), 2);
         assert_eq!(results[1].metadata.len(), 9);
     }
+
+    #[test]
+    fn test_walk_dir_depth() {
+        let tempdir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tempdir.path().join("foo")).unwrap();
+        File::create(tempdir.path().join("foo").join("bar")).unwrap();
+
+        let mut results = walk_dir(&tempdir).unwrap().collect::<Vec<_>>();
+        results.sort_by_key(|entry| entry.path.clone());
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].depth, 0);
+        assert_eq!(results[1].depth, 1);
+        assert_eq!(results[2].depth, 2);
+    }
+
+    #[test]
+    fn test_walk_options_max_depth() {
+        let tempdir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(tempdir.path().join("a").join("b").join("c")).unwrap();
+
+        let mut results = WalkOptions::new()
+            .max_depth(2)
+            .walk(&tempdir).unwrap()
+            .collect::<Vec<_>>();
+        results.sort_by_key(|entry| entry.path.clone());
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].path, tempdir.path());
+        assert_eq!(results[1].path, tempdir.path().join("a"));
+        assert_eq!(results[2].path, tempdir.path().join("a").join("b"));
+    }
+
+    #[test]
+    fn test_walk_options_max_depth_zero() {
+        let tempdir = tempfile::tempdir().unwrap();
+        File::create(tempdir.path().join("foo")).unwrap();
+
+        let results = WalkOptions::new()
+            .max_depth(0)
+            .walk(&tempdir).unwrap()
+            .collect::<Vec<_>>();
+
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].path, tempdir.path());
+    }
+
+    #[test]
+    fn test_walk_options_prune() {
+        let tempdir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tempdir.path().join("foo")).unwrap();
+        std::fs::create_dir(tempdir.path().join("bar")).unwrap();
+        File::create(tempdir.path().join("foo").join("abc")).unwrap();
+        File::create(tempdir.path().join("bar").join("def")).unwrap();
+
+        let mut results = WalkOptions::new()
+            .prune(|entry| entry.path.ends_with("foo"))
+            .walk(&tempdir).unwrap()
+            .collect::<Vec<_>>();
+        results.sort_by_key(|entry| entry.path.clone());
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].path, tempdir.path());
+        assert_eq!(results[1].path, tempdir.path().join("bar"));
+        assert_eq!(results[2].path, tempdir.path().join("bar").join("def"));
+    }
+
+    #[test]
+    fn test_walk_options_sorted() {
+        let tempdir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tempdir.path().join("b")).unwrap();
+        File::create(tempdir.path().join("b").join("c")).unwrap();
+        File::create(tempdir.path().join("d")).unwrap();
+        File::create(tempdir.path().join("a")).unwrap();
+
+        let results = WalkOptions::new()
+            .sorted(true)
+            .walk(&tempdir).unwrap()
+            .map(|entry| entry.path)
+            .collect::<Vec<_>>();
+
+        assert_eq!(results, vec![
+            tempdir.path().to_path_buf(),
+            tempdir.path().join("a"),
+            tempdir.path().join("b"),
+            tempdir.path().join("b").join("c"),
+            tempdir.path().join("d"),
+        ]);
+    }
+
+    // Symlinking is supported only on Unix-like systems.
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_walk_options_follow_links() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path().join("abc");
+        let file = dir.join("def");
+        let symlink = tempdir.path().join("ghi");
+
+        std::fs::create_dir(&dir).unwrap();
+        File::create(&file).unwrap();
+        std::os::unix::fs::symlink(&dir, &symlink).unwrap();
+
+        let results = WalkOptions::new()
+            .follow_links(true)
+            .sorted(true)
+            .walk(&tempdir).unwrap()
+            .collect::<Vec<_>>();
+
+        // The symlinked directory is the same as the original one, so it is
+        // entered only once (as the original one, which is encountered first).
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].path, tempdir.path());
+        assert_eq!(results[1].path, dir);
+        assert_eq!(results[2].path, file);
+        assert_eq!(results[3].path, symlink);
+        assert!(results[3].metadata.is_dir());
+    }
+
+    // Symlinking is supported only on Unix-like systems.
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_walk_options_follow_circular_links() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path().join("foo");
+        let symlink = tempdir.path().join("foo").join("bar");
+
+        std::fs::create_dir(&dir).unwrap();
+        std::os::unix::fs::symlink(&dir, &symlink).unwrap();
+
+        let mut results = WalkOptions::new()
+            .follow_links(true)
+            .walk(&tempdir).unwrap()
+            .collect::<Vec<_>>();
+        results.sort_by_key(|entry| entry.path.clone());
+
+        assert_eq!(results.len(), 3);
+
+        assert_eq!(results[2].path, symlink);
+        assert!(results[2].metadata.is_dir());
+    }
+
+    // Symlinking is supported only on Unix-like systems.
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_walk_options_follow_broken_links() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let symlink = tempdir.path().join("foo");
+
+        std::os::unix::fs::symlink(tempdir.path().join("bar"), &symlink).unwrap();
+
+        let results = WalkOptions::new()
+            .follow_links(true)
+            .walk(&tempdir).unwrap()
+            .collect::<Vec<_>>();
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[1].path, symlink);
+        assert!(results[1].metadata.file_type().is_symlink());
+    }
+
+    #[test]
+    fn test_walk_dir_with_errors() {
+        let tempdir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tempdir.path().join("abc")).unwrap();
+        std::fs::create_dir(tempdir.path().join("def")).unwrap();
+
+        // With sorting enabled, children of the root are collected eagerly so
+        // removing one of them makes descending into it fail.
+        let iter = WalkOptions::new()
+            .sorted(true)
+            .walk(&tempdir).unwrap()
+            .with_errors();
+        std::fs::remove_dir(tempdir.path().join("def")).unwrap();
+
+        let results = iter.collect::<Vec<_>>();
+        assert_eq!(results.len(), 4);
+
+        assert_eq!(results[2].as_ref().unwrap().path, tempdir.path().join("def"));
+
+        let error = results[3].as_ref().unwrap_err();
+        assert_eq!(error.path(), tempdir.path().join("def"));
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_walk_dir_swallows_errors() {
+        let tempdir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tempdir.path().join("abc")).unwrap();
+
+        let iter = WalkOptions::new()
+            .sorted(true)
+            .walk(&tempdir).unwrap();
+        std::fs::remove_dir(tempdir.path().join("abc")).unwrap();
+
+        assert_eq!(iter.count(), 2);
+    }
+
+    #[test]
+    fn test_error_summary() {
+        let mut summary = ErrorSummary::new();
+        assert!(summary.is_empty());
+
+        let not_found = || std::io::Error::from(ErrorKind::NotFound);
+        let denied = || std::io::Error::from(ErrorKind::PermissionDenied);
+
+        summary.record(WalkError::new("/foo", not_found()));
+        summary.record(WalkError::new("/bar", denied()));
+        summary.record(WalkError::new("/baz", not_found()));
+
+        assert!(!summary.is_empty());
+        assert_eq!(summary.count(), 3);
+
+        let counts = summary.counts().collect::<HashMap<_, _>>();
+        assert_eq!(counts[&ErrorKind::NotFound], 2);
+        assert_eq!(counts[&ErrorKind::PermissionDenied], 1);
+
+        assert_eq!(summary.errors()[1].path(), Path::new("/bar"));
+    }
+
+    #[test]
+    fn test_error_summary_bounded() {
+        let mut summary = ErrorSummary::new();
+        for _ in 0..(MAX_SUMMARY_ERRORS + 10) {
+            let error = std::io::Error::from(ErrorKind::NotFound);
+            summary.record(WalkError::new("/foo", error));
+        }
+
+        assert_eq!(summary.count(), (MAX_SUMMARY_ERRORS + 10) as u64);
+        assert_eq!(summary.errors().len(), MAX_SUMMARY_ERRORS);
+    }
 }
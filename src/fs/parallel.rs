@@ -0,0 +1,754 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! A parallel variant of the filesystem walker.
+//!
+//! Traversal of large trees is dominated by the latency of listing directories
+//! and collecting file metadata (especially on network filesystems). Multiple
+//! directories can be processed at the same time, so the work is distributed
+//! among a pool of threads with per-thread queues: threads take directories
+//! from their own queue first and steal from queues of other threads once they
+//! run out of work.
+//!
+//! By default, entries are yielded in the order in which threads find them. If
+//! sorted traversal is requested, entries are yielded in exactly the same order
+//! as with the sequential walker and threads are used to prefetch listings of
+//! directories that are going to be visited soon.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use log::error;
+
+use super::{file_id, device, list_dir, result_path, swallow};
+use super::{Entry, WalkError, WalkOptions, WithErrors};
+
+/// A default number of threads used by the parallel walker.
+pub const DEFAULT_THREADS: usize = 4;
+
+/// A maximum number of entries found by threads but not yet yielded.
+///
+/// Once the limit is reached, threads wait for the consumer to catch up. This
+/// keeps the memory usage bounded even if the consumer is much slower than the
+/// traversal itself.
+const CHANNEL_CAPACITY: usize = 4096;
+
+/// A maximum number of directory listings prefetched during sorted traversal.
+const MAX_PREFETCHED: usize = 256;
+
+/// A time that idle threads wait for new work before checking again.
+const IDLE_WAIT: Duration = Duration::from_millis(10);
+
+impl WalkOptions {
+
+    /// Returns a deep iterator over entries within the `root` directory that
+    /// traverses the tree using multiple threads.
+    ///
+    /// The traversal behaves exactly like the one with the [`walk`] method,
+    /// except that (unless sorting is requested) entries are yielded in an
+    /// unspecified order.
+    ///
+    /// [`walk`]: struct.WalkOptions.html#method.walk
+    pub fn walk_parallel<P: AsRef<Path>>(self, root: P) -> std::io::Result<ParallelWalk> {
+        let root = self.root(root.as_ref())?;
+
+        // Unlike with other directories, failure to list the root is an error.
+        // Roots that are not directories are yielded on their own.
+        let iter = if root.metadata.is_dir() {
+            let mut iter = list_dir(&root.path)?;
+            iter.depth = 1;
+            Some(iter)
+        } else {
+            None
+        };
+
+        let threads = self.threads;
+        let shared = Arc::new(Shared {
+            queues: (0..threads).map(|_| Mutex::new(VecDeque::new())).collect(),
+            pending: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            visited: Mutex::new(HashSet::new()),
+            wakeup: (Mutex::new(()), Condvar::new()),
+            dev: device(&root.metadata),
+            options: self,
+        });
+
+        let descends = shared.descends(&root);
+        let sorted = shared.options.sorted;
+
+        let (mode, output) = if sorted {
+            let mut ordered = Ordered {
+                stack: vec![],
+                prefetched: HashMap::new(),
+                next_queue: 0,
+                error: None,
+            };
+
+            if let (true, Some(iter)) = (descends, iter) {
+                let listing = shared.collect(iter);
+                ordered.prefetch(&shared, &listing);
+                ordered.stack.push(listing.into_iter());
+            }
+
+            (Mode::Ordered(ordered), None)
+        } else {
+            let (sender, receiver) = sync_channel(CHANNEL_CAPACITY);
+
+            if descends {
+                shared.push(0, Job {
+                    path: root.path.clone(),
+                    depth: root.depth,
+                    slot: None,
+                });
+            }
+
+            (Mode::Unordered(receiver), Some(sender))
+        };
+
+        let mut workers = Vec::with_capacity(threads);
+        for index in 0..threads {
+            let shared = shared.clone();
+            let output = output.clone();
+
+            let worker = std::thread::Builder::new()
+                .name(format!("walk-{}", index))
+                .spawn(move || shared.work(index, output));
+
+            match worker {
+                Ok(worker) => workers.push(worker),
+                Err(error) => {
+                    // Threads steal work from each other, so everything gets
+                    // done as long as at least one of them is running.
+                    error!("failed to spawn a walker thread: {}", error);
+                }
+            }
+        }
+
+        if workers.is_empty() {
+            shared.close();
+            return Err(std::io::Error::new(std::io::ErrorKind::Other,
+                                           "failed to spawn walker threads"));
+        }
+
+        Ok(ParallelWalk {
+            root: Some(root),
+            mode: mode,
+            shared: shared,
+            workers: workers,
+        })
+    }
+}
+
+/// Iterator over entries in all subdirectories traversed using many threads.
+///
+/// This iterator yields the same entries as [`WalkDir`] does for the same
+/// options, but directories are listed (and their entries stat-ed) by a pool
+/// of threads. Unless sorted traversal is requested, the order of yielded
+/// entries is unspecified (in particular, children are not guaranteed to be
+/// yielded after their parent directories).
+///
+/// Just like with [`WalkDir`], all errors are logged and swallowed unless the
+/// iterator is turned into one that yields them with the [`with_errors`]
+/// method.
+///
+/// The iterator can be constructed with the [`WalkOptions::walk_parallel`]
+/// method.
+///
+/// [`WalkDir`]: struct.WalkDir.html
+/// [`with_errors`]: struct.ParallelWalk.html#method.with_errors
+/// [`WalkOptions::walk_parallel`]: struct.WalkOptions.html#method.walk_parallel
+pub struct ParallelWalk {
+    root: Option<Entry>,
+    mode: Mode,
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+/// A mode in which the parallel walker yields entries.
+enum Mode {
+    /// Entries are yielded in the order in which threads find them.
+    Unordered(Receiver<Result<Entry, WalkError>>),
+    /// Entries are yielded in the order of the sequential sorted traversal.
+    Ordered(Ordered),
+    /// The traversal is over.
+    Finished,
+}
+
+/// A state of the sorted parallel traversal.
+struct Ordered {
+    /// Listings of directories being traversed (from the root to the deepest).
+    stack: Vec<std::vec::IntoIter<Result<Entry, WalkError>>>,
+    /// Listings of directories requested from the threads ahead of time.
+    prefetched: HashMap<PathBuf, Receiver<Listing>>,
+    /// An index of the thread queue to put the next prefetch request into.
+    next_queue: usize,
+    /// An error of listing a directory to yield after its entry.
+    error: Option<WalkError>,
+}
+
+/// A sorted listing of the children of a directory.
+type Listing = Vec<Result<Entry, WalkError>>;
+
+/// A request to process a directory.
+struct Job {
+    /// A path to the directory.
+    path: PathBuf,
+    /// A depth of the directory.
+    depth: usize,
+    /// A channel to send the sorted listing to (if traversing in order).
+    ///
+    /// If absent, entries are sent directly to the consumer and subdirectories
+    /// are processed by the threads as well.
+    slot: Option<Sender<Listing>>,
+}
+
+/// A state shared between the consumer and the walker threads.
+struct Shared {
+    /// Options of the traversal.
+    options: WalkOptions,
+    /// A device of the traversal root.
+    dev: u64,
+    /// Queues of directories to process (one per thread).
+    queues: Vec<Mutex<VecDeque<Job>>>,
+    /// A number of queued jobs plus the number of jobs being processed.
+    pending: AtomicUsize,
+    /// Whether the traversal has been abandoned by the consumer.
+    closed: AtomicBool,
+    /// Directories already entered (tracked only if symlinks are followed).
+    visited: Mutex<HashSet<(u64, u64)>>,
+    /// A condition variable for notifying idle threads about new work.
+    wakeup: (Mutex<()>, Condvar),
+}
+
+impl ParallelWalk {
+
+    /// Turns the iterator into one that yields errors along with entries.
+    pub fn with_errors(self) -> WithErrors<ParallelWalk> {
+        WithErrors {
+            iter: self,
+        }
+    }
+
+    fn try_next(&mut self) -> Option<Result<Entry, WalkError>> {
+        if self.root.is_some() {
+            return self.root.take().map(Ok);
+        }
+
+        let next = match self.mode {
+            Mode::Unordered(ref receiver) => receiver.recv().ok(),
+            Mode::Ordered(ref mut ordered) => ordered.next(&self.shared),
+            Mode::Finished => None,
+        };
+
+        if next.is_none() {
+            self.finish();
+        }
+
+        next
+    }
+
+    /// Stops all the threads and waits for them to exit.
+    fn finish(&mut self) {
+        self.shared.close();
+
+        // Threads might be blocked on sending entries to the consumer, so the
+        // channel has to be closed before waiting for them.
+        self.mode = Mode::Finished;
+
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                error!("walker thread panicked");
+            }
+        }
+    }
+}
+
+impl std::iter::Iterator for ParallelWalk {
+
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        swallow(|| self.try_next())
+    }
+}
+
+impl std::iter::Iterator for WithErrors<ParallelWalk> {
+
+    type Item = Result<Entry, WalkError>;
+
+    fn next(&mut self) -> Option<Result<Entry, WalkError>> {
+        self.iter.try_next()
+    }
+}
+
+impl Drop for ParallelWalk {
+
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+impl Ordered {
+
+    fn next(&mut self, shared: &Shared) -> Option<Result<Entry, WalkError>> {
+        if self.error.is_some() {
+            return self.error.take().map(Err);
+        }
+
+        loop {
+            let entry = match self.stack.last_mut()?.next() {
+                Some(Ok(entry)) => entry,
+                Some(Err(error)) => return Some(Err(error)),
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+
+            let prefetched = self.prefetched.remove(&entry.path);
+            if !shared.descends(&entry) {
+                return Some(Ok(entry));
+            }
+
+            // If the listing was not prefetched (or the thread processing it
+            // has been interrupted), the directory is listed right away.
+            let listing = prefetched.and_then(|receiver| receiver.recv().ok());
+            let listing = match listing {
+                Some(listing) => Ok(listing),
+                None => shared.list(&entry.path, entry.depth),
+            };
+
+            match listing {
+                Ok(listing) => {
+                    self.prefetch(shared, &listing);
+                    self.stack.push(listing.into_iter());
+                }
+                Err(error) => self.error = Some(error),
+            }
+
+            return Some(Ok(entry));
+        }
+    }
+
+    /// Requests threads to list subdirectories in the given `listing`.
+    fn prefetch(&mut self, shared: &Shared, listing: &Listing) {
+        for entry in listing.iter().filter_map(|result| result.as_ref().ok()) {
+            if self.prefetched.len() >= MAX_PREFETCHED {
+                return;
+            }
+
+            // Whether the traversal actually enters the directory (it might
+            // have been visited already) is decided only when it is reached.
+            if !shared.options.enters(entry, shared.dev) {
+                continue;
+            }
+
+            let (sender, receiver) = channel();
+            self.prefetched.insert(entry.path.clone(), receiver);
+
+            shared.push(self.next_queue, Job {
+                path: entry.path.clone(),
+                depth: entry.depth,
+                slot: Some(sender),
+            });
+            self.next_queue = (self.next_queue + 1) % shared.queues.len();
+        }
+    }
+}
+
+impl Shared {
+
+    /// Runs the loop of the thread with the given `index`.
+    ///
+    /// In the unordered mode, found entries are sent to the `output` channel.
+    fn work(&self, index: usize, output: Option<SyncSender<Result<Entry, WalkError>>>) {
+        while let Some(job) = self.pop(index, output.is_some()) {
+            let result = match job.slot {
+                Some(ref slot) => {
+                    // The consumer might have stopped caring about the listing
+                    // (e.g. because it has been dropped), which is fine.
+                    let listing = self.list(&job.path, job.depth)
+                        .unwrap_or_else(|error| vec![Err(error)]);
+                    drop(slot.send(listing));
+
+                    Ok(())
+                }
+                None => match output {
+                    Some(ref output) => self.expand(index, &job, output),
+                    None => Ok(()),
+                },
+            };
+
+            if self.pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+                self.notify();
+            }
+
+            if result.is_err() {
+                self.close();
+            }
+        }
+    }
+
+    /// Sends entries within the directory of the given `job` to the `output`
+    /// channel and queues its subdirectories.
+    ///
+    /// An error is returned if the consumer no longer accepts entries.
+    fn expand(&self, index: usize, job: &Job, output: &SyncSender<Result<Entry, WalkError>>)
+        -> Result<(), ()>
+    {
+        let mut iter = match list_dir(&job.path) {
+            Ok(iter) => iter,
+            Err(error) => {
+                let error = WalkError::new(&job.path, error);
+                return output.send(Err(error)).map_err(|_| ());
+            }
+        };
+        iter.depth = job.depth + 1;
+
+        for result in iter.with_errors() {
+            if self.closed.load(Ordering::SeqCst) {
+                return Err(());
+            }
+
+            let mut entry = match result {
+                Ok(entry) => entry,
+                Err(error) => {
+                    output.send(Err(error)).map_err(|_| ())?;
+                    continue;
+                }
+            };
+
+            self.options.resolve(&mut entry);
+            if self.options.prunes(&entry) {
+                continue;
+            }
+
+            if self.descends(&entry) {
+                self.push(index, Job {
+                    path: entry.path.clone(),
+                    depth: entry.depth,
+                    slot: None,
+                });
+            }
+
+            output.send(Ok(entry)).map_err(|_| ())?;
+        }
+
+        Ok(())
+    }
+
+    /// Collects a sorted listing of the directory at `path`.
+    fn list(&self, path: &Path, depth: usize) -> Result<Listing, WalkError> {
+        let mut iter = list_dir(path)
+            .map_err(|error| WalkError::new(path, error))?;
+        iter.depth = depth + 1;
+
+        Ok(self.collect(iter))
+    }
+
+    /// Collects a sorted listing of entries yielded by the given iterator.
+    fn collect(&self, iter: super::ListDir) -> Listing {
+        let mut listing = iter.with_errors()
+            .map(|result| result.map(|mut entry| {
+                self.options.resolve(&mut entry);
+                entry
+            }))
+            .filter(|result| match *result {
+                Ok(ref entry) => !self.options.prunes(entry),
+                Err(_) => true,
+            })
+            .collect::<Vec<_>>();
+        listing.sort_by(|lhs, rhs| result_path(lhs).cmp(result_path(rhs)));
+
+        listing
+    }
+
+    /// Checks whether the traversal should descend into the given entry.
+    ///
+    /// Note that this method marks the entry as visited if it is a directory
+    /// and symlinks are followed.
+    fn descends(&self, entry: &Entry) -> bool {
+        if !self.options.enters(entry, self.dev) {
+            return false;
+        }
+
+        if !self.options.follow_links {
+            return true;
+        }
+
+        match file_id(&entry.metadata) {
+            Some(id) => {
+                let mut visited = self.visited.lock()
+                    .unwrap_or_else(|error| error.into_inner());
+                visited.insert(id)
+            }
+            None => true,
+        }
+    }
+
+    /// Puts the `job` into the queue of the thread with the given `index`.
+    fn push(&self, index: usize, job: Job) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+
+        let mut queue = self.queues[index].lock()
+            .unwrap_or_else(|error| error.into_inner());
+        queue.push_back(job);
+        drop(queue);
+
+        self.notify();
+    }
+
+    /// Takes the next job for the thread with the given `index`.
+    ///
+    /// In the unordered mode, the thread takes the most recently queued job
+    /// from its own queue (so that the traversal is depth-first and the queues
+    /// stay short) and the oldest one when stealing. In the ordered mode jobs
+    /// are always taken in the order in which the consumer needs them.
+    ///
+    /// `None` is returned once the traversal is over.
+    fn pop(&self, index: usize, unordered: bool) -> Option<Job> {
+        loop {
+            if self.closed.load(Ordering::SeqCst) {
+                return None;
+            }
+
+            let count = self.queues.len();
+            for offset in 0..count {
+                let own = offset == 0;
+
+                let mut queue = self.queues[(index + offset) % count].lock()
+                    .unwrap_or_else(|error| error.into_inner());
+
+                let job = if own && unordered {
+                    queue.pop_back()
+                } else {
+                    queue.pop_front()
+                };
+
+                if job.is_some() {
+                    return job;
+                }
+            }
+
+            // In the ordered mode the consumer can request more work at any
+            // moment, so threads wait until the traversal is closed.
+            if unordered && self.pending.load(Ordering::SeqCst) == 0 {
+                return None;
+            }
+
+            let lock = self.wakeup.0.lock()
+                .unwrap_or_else(|error| error.into_inner());
+            drop(self.wakeup.1.wait_timeout(lock, IDLE_WAIT));
+        }
+    }
+
+    /// Makes all threads exit as soon as possible.
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.notify();
+    }
+
+    fn notify(&self) {
+        self.wakeup.1.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs::{create_dir, create_dir_all, File};
+
+    use super::*;
+    use crate::fs::walk_dir;
+
+    fn sorted_paths<I: Iterator<Item = Entry>>(iter: I) -> Vec<PathBuf> {
+        let mut paths = iter.map(|entry| entry.path).collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+
+    fn tree() -> tempfile::TempDir {
+        let tempdir = tempfile::tempdir().unwrap();
+        for dir in &["a", "b", "c"] {
+            for subdir in &["d", "e"] {
+                let path = tempdir.path().join(dir).join(subdir);
+                create_dir_all(&path).unwrap();
+                File::create(path.join("foo")).unwrap();
+                File::create(path.join("bar")).unwrap();
+            }
+        }
+
+        tempdir
+    }
+
+    #[test]
+    fn test_walk_parallel_non_existing() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let iter = WalkOptions::new().walk_parallel(tempdir.path().join("foo"));
+        assert!(iter.is_err());
+    }
+
+    #[test]
+    fn test_walk_parallel_empty() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let results = WalkOptions::new()
+            .walk_parallel(&tempdir).unwrap()
+            .collect::<Vec<_>>();
+
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].path, tempdir.path());
+    }
+
+    #[test]
+    fn test_walk_parallel_file() {
+        let tempdir = tempfile::tempdir().unwrap();
+        std::fs::write(tempdir.path().join("foo"), b"").unwrap();
+
+        for &sorted in &[false, true] {
+            let results = WalkOptions::new()
+                .sorted(sorted)
+                .walk_parallel(tempdir.path().join("foo")).unwrap()
+                .collect::<Vec<_>>();
+
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].path, tempdir.path().join("foo"));
+        }
+    }
+
+    #[test]
+    fn test_walk_parallel_same_as_sequential() {
+        let tempdir = tree();
+
+        let expected = sorted_paths(walk_dir(&tempdir).unwrap());
+        for threads in 1..5 {
+            let iter = WalkOptions::new()
+                .threads(threads)
+                .walk_parallel(&tempdir).unwrap();
+
+            assert_eq!(sorted_paths(iter), expected);
+        }
+    }
+
+    #[test]
+    fn test_walk_parallel_sorted_order() {
+        let tempdir = tree();
+
+        let expected = WalkOptions::new()
+            .sorted(true)
+            .walk(&tempdir).unwrap()
+            .map(|entry| entry.path)
+            .collect::<Vec<_>>();
+
+        let results = WalkOptions::new()
+            .sorted(true)
+            .threads(3)
+            .walk_parallel(&tempdir).unwrap()
+            .map(|entry| entry.path)
+            .collect::<Vec<_>>();
+
+        assert_eq!(results, expected);
+    }
+
+    #[test]
+    fn test_walk_parallel_depth() {
+        let tempdir = tree();
+
+        for entry in WalkOptions::new().walk_parallel(&tempdir).unwrap() {
+            let suffix = entry.path.strip_prefix(tempdir.path()).unwrap();
+            assert_eq!(entry.depth, suffix.components().count());
+        }
+    }
+
+    #[test]
+    fn test_walk_parallel_max_depth_and_prune() {
+        let tempdir = tree();
+
+        let iter = WalkOptions::new()
+            .max_depth(2)
+            .prune(|entry| entry.path.ends_with("b"))
+            .walk_parallel(&tempdir).unwrap();
+
+        assert_eq!(sorted_paths(iter), vec![
+            tempdir.path().to_path_buf(),
+            tempdir.path().join("a"),
+            tempdir.path().join("a").join("d"),
+            tempdir.path().join("a").join("e"),
+            tempdir.path().join("c"),
+            tempdir.path().join("c").join("d"),
+            tempdir.path().join("c").join("e"),
+        ]);
+    }
+
+    #[test]
+    fn test_walk_parallel_early_drop() {
+        let tempdir = tree();
+
+        let mut iter = WalkOptions::new()
+            .threads(2)
+            .walk_parallel(&tempdir).unwrap();
+
+        assert!(iter.next().is_some());
+        drop(iter);
+    }
+
+    // Permissions are supported only on Unix-like systems.
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_walk_parallel_with_errors() {
+        use std::fs::{set_permissions, Permissions};
+        use std::os::unix::fs::PermissionsExt;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path().join("abc");
+        create_dir(&dir).unwrap();
+        set_permissions(&dir, Permissions::from_mode(0o000)).unwrap();
+
+        // Permissions are not enforced for privileged users.
+        if std::fs::read_dir(&dir).is_ok() {
+            return;
+        }
+
+        let results = WalkOptions::new()
+            .sorted(true)
+            .walk_parallel(&tempdir).unwrap()
+            .with_errors()
+            .collect::<Vec<_>>();
+
+        set_permissions(&dir, Permissions::from_mode(0o755)).unwrap();
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[1].as_ref().unwrap().path, dir);
+        assert!(results[2].is_err());
+    }
+
+    // Symlinking is supported only on Unix-like systems.
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_walk_parallel_follow_circular_links() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path().join("foo");
+        let symlink = tempdir.path().join("foo").join("bar");
+
+        create_dir(&dir).unwrap();
+        std::os::unix::fs::symlink(&dir, &symlink).unwrap();
+
+        let iter = WalkOptions::new()
+            .follow_links(true)
+            .walk_parallel(&tempdir).unwrap();
+
+        assert_eq!(sorted_paths(iter), vec![
+            tempdir.path().to_path_buf(),
+            dir,
+            symlink,
+        ]);
+    }
+}
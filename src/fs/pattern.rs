@@ -0,0 +1,580 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Glob patterns for matching filesystem paths.
+//!
+//! The syntax follows the one used by the GRR file finder: `*` matches any
+//! (possibly empty) sequence of characters within a path component, `?` matches
+//! any single character and a standalone `**` component matches any number
+//! (including zero) of nested path components up to a certain depth (3 by
+//! default, can be specified explicitly like `**5`).
+//!
+//! Patterns can be used both to check whether a given path matches and to look
+//! for all the existing paths that do.
+
+use std::ffi::{OsStr, OsString};
+use std::fmt::{Display, Formatter};
+use std::path::{Component as PathComponent, Path, PathBuf};
+
+use super::{list_dir, WalkDir, WalkError, WalkOptions, WithErrors};
+
+/// A default maximum depth of the recursive component.
+const DEFAULT_RECURSIVE_DEPTH: u32 = 3;
+
+/// A parsed path pattern.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pattern {
+    /// Components of the pattern (following the filesystem root).
+    components: Vec<Component>,
+}
+
+/// A single component of a path pattern.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Component {
+    /// A component matching exactly the given name.
+    Literal(OsString),
+    /// A component matching names that match the wildcard pattern.
+    Wildcard(String),
+    /// A component matching up to the given number of path components.
+    Recursive(u32),
+}
+
+impl Pattern {
+
+    /// Parses the given `pattern`.
+    ///
+    /// The pattern has to be an absolute path.
+    pub fn parse(pattern: &str) -> Result<Pattern, PatternError> {
+        let error = |reason| PatternError {
+            pattern: String::from(pattern),
+            reason: reason,
+        };
+
+        if !pattern.starts_with('/') {
+            return Err(error("not an absolute path"));
+        }
+
+        let mut components = Vec::new();
+        for part in pattern.split('/').filter(|part| !part.is_empty()) {
+            let component = if part.starts_with("**") {
+                let depth = &part[2..];
+                if depth.is_empty() {
+                    Component::Recursive(DEFAULT_RECURSIVE_DEPTH)
+                } else {
+                    match depth.parse() {
+                        Ok(depth) => Component::Recursive(depth),
+                        Err(_) => return Err(error("invalid recursion depth")),
+                    }
+                }
+            } else if part.contains(|c| c == '*' || c == '?') {
+                Component::Wildcard(String::from(part))
+            } else {
+                Component::Literal(OsString::from(part))
+            };
+
+            components.push(component);
+        }
+
+        Ok(Pattern {
+            components: components,
+        })
+    }
+
+    /// Checks whether the given (absolute) `path` matches the pattern.
+    pub fn matches<P: AsRef<Path>>(&self, path: P) -> bool {
+        let names = path.as_ref().components()
+            .filter_map(|component| match component {
+                PathComponent::Normal(name) => Some(name),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        matches(&self.components, &names)
+    }
+
+    /// Returns an iterator over all existing paths matching the pattern.
+    ///
+    /// Paths are yielded in a depth-first order and the same path might be
+    /// yielded multiple times if it is matched in different ways (e.g. by two
+    /// consecutive recursive components). Errors (e.g. caused by insufficient
+    /// permissions) are yielded in place of the problematic paths. Paths that
+    /// simply do not exist are skipped silently.
+    pub fn expand(&self) -> Expand<'_> {
+        Expand {
+            pattern: self,
+            pending: vec![Pending::Path(PathBuf::from("/"), 0)],
+        }
+    }
+}
+
+impl Display for Pattern {
+
+    fn fmt(&self, fmt: &mut Formatter) -> std::fmt::Result {
+        if self.components.is_empty() {
+            return write!(fmt, "/");
+        }
+
+        for component in &self.components {
+            match *component {
+                Component::Literal(ref literal) => write!(fmt, "/{}", literal.to_string_lossy())?,
+                Component::Wildcard(ref pattern) => write!(fmt, "/{}", pattern)?,
+                Component::Recursive(DEFAULT_RECURSIVE_DEPTH) => write!(fmt, "/**")?,
+                Component::Recursive(depth) => write!(fmt, "/**{}", depth)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// An iterator over paths matching a pattern.
+///
+/// The iterator can be constructed with the [`Pattern::expand`] method.
+///
+/// [`Pattern::expand`]: struct.Pattern.html#method.expand
+pub struct Expand<'p> {
+    /// The pattern being expanded.
+    pattern: &'p Pattern,
+    /// A stack of work items, processed from the end.
+    pending: Vec<Pending>,
+}
+
+/// A single item of work of the pattern expansion.
+enum Pending {
+    /// A path to match against the component at the given index.
+    Path(PathBuf, usize),
+    /// An error encountered when looking for matching paths.
+    Error(WalkError),
+    /// A walk over paths matched by the recursive component at the given index.
+    ///
+    /// Walks are consumed lazily, so that the expansion of deep recursive
+    /// components does not need to keep the whole subtree in memory.
+    Walk(WithErrors<WalkDir>, usize),
+}
+
+impl<'p> Expand<'p> {
+
+    /// Queues the `path` that matched the component at `index`.
+    ///
+    /// If the component is not the last one, only directories are queued as
+    /// there is nothing to match within other files.
+    fn queue(&mut self, index: usize, path: PathBuf) {
+        let last = index + 1 >= self.pattern.components.len();
+        if last || path.is_dir() {
+            self.pending.push(Pending::Path(path, index + 1));
+        }
+    }
+
+    /// Queues the `children` of a path that matched the component at `index`.
+    fn queue_all(&mut self, index: usize, mut children: Vec<Result<PathBuf, WalkError>>) {
+        // The stack is processed from the end, so the children are sorted in
+        // the reverse order to be yielded in the lexicographical one.
+        children.sort_by(|lhs, rhs| result_path(rhs).cmp(result_path(lhs)));
+        for child in children {
+            match child {
+                Ok(path) => self.queue(index, path),
+                Err(error) => self.pending.push(Pending::Error(error)),
+            }
+        }
+    }
+
+    /// Expands the `path` against the component at `index`.
+    ///
+    /// If there are no more components to match, the path itself is returned.
+    fn expand(&mut self, path: PathBuf, index: usize) -> Option<PathBuf> {
+        let component = match self.pattern.components.get(index) {
+            Some(component) => component,
+            None => return Some(path),
+        };
+
+        match *component {
+            Component::Literal(ref name) => {
+                let child = path.join(name);
+                match std::fs::symlink_metadata(&child) {
+                    Ok(_) => self.queue(index, child),
+                    Err(ref error) if error.kind() == std::io::ErrorKind::NotFound => (),
+                    Err(error) => self.pending.push(Pending::Error(WalkError::new(child, error))),
+                }
+            }
+            Component::Wildcard(ref pattern) => match list_dir(&path) {
+                Ok(iter) => {
+                    let children = iter.with_errors()
+                        .map(|entry| entry.map(|entry| entry.path))
+                        .filter(|child| match *child {
+                            Ok(ref child) => name_matches(pattern, child),
+                            Err(_) => true,
+                        })
+                        .collect();
+                    self.queue_all(index, children);
+                }
+                Err(error) => self.pending.push(Pending::Error(WalkError::new(path, error))),
+            },
+            Component::Recursive(depth) => match walk(&path, depth) {
+                Ok(iter) => {
+                    let mut iter = iter.with_errors();
+                    // The first entry yielded by the walker is the root itself.
+                    // It is matched against the following component directly,
+                    // as the recursive component can match zero directories.
+                    iter.next();
+                    self.pending.push(Pending::Walk(iter, index));
+                    self.pending.push(Pending::Path(path, index + 1));
+                }
+                Err(error) => self.pending.push(Pending::Error(WalkError::new(path, error))),
+            },
+        }
+
+        None
+    }
+}
+
+impl<'p> Iterator for Expand<'p> {
+
+    type Item = Result<PathBuf, WalkError>;
+
+    fn next(&mut self) -> Option<Result<PathBuf, WalkError>> {
+        while let Some(pending) = self.pending.pop() {
+            match pending {
+                Pending::Path(path, index) => {
+                    if let Some(path) = self.expand(path, index) {
+                        return Some(Ok(path));
+                    }
+                }
+                Pending::Error(error) => return Some(Err(error)),
+                Pending::Walk(mut iter, index) => match iter.next() {
+                    Some(Ok(entry)) => {
+                        // The walk is resumed only after everything matched
+                        // within the yielded entry is processed.
+                        self.pending.push(Pending::Walk(iter, index));
+                        self.queue(index, entry.path);
+                    }
+                    Some(Err(error)) => {
+                        self.pending.push(Pending::Walk(iter, index));
+                        return Some(Err(error));
+                    }
+                    None => (),
+                },
+            }
+        }
+
+        None
+    }
+}
+
+/// Returns the path that the expansion `result` concerns.
+fn result_path(result: &Result<PathBuf, WalkError>) -> &Path {
+    match *result {
+        Ok(ref path) => path,
+        Err(ref error) => error.path(),
+    }
+}
+
+/// Checks whether the last component of `path` matches the wildcard `pattern`.
+fn name_matches(pattern: &str, path: &Path) -> bool {
+    match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => wildcard_matches(pattern, name),
+        None => false,
+    }
+}
+
+/// Returns a sorted walk over entries at most `depth` components below `root`.
+fn walk(root: &Path, depth: u32) -> std::io::Result<WalkDir> {
+    WalkOptions::new()
+        .max_depth(depth as usize)
+        .sorted(true)
+        .walk(root)
+}
+
+/// Checks whether the sequence of path component `names` matches the sequence
+/// of pattern `components`.
+fn matches(components: &[Component], names: &[&OsStr]) -> bool {
+    let (component, components) = match components.split_first() {
+        Some(split) => split,
+        None => return names.is_empty(),
+    };
+
+    if let Component::Recursive(depth) = *component {
+        let max_skip = std::cmp::min(depth as usize, names.len());
+        return (0..=max_skip).any(|skip| matches(components, &names[skip..]));
+    }
+
+    let (name, names) = match names.split_first() {
+        Some(split) => split,
+        None => return false,
+    };
+
+    let matched = match *component {
+        Component::Literal(ref literal) => literal == name,
+        Component::Wildcard(ref pattern) => match name.to_str() {
+            Some(name) => wildcard_matches(pattern, name),
+            None => false,
+        },
+        Component::Recursive(_) => unreachable!(),
+    };
+
+    matched && self::matches(components, names)
+}
+
+/// Checks whether the given `name` matches the wildcard `pattern`.
+///
+/// The pattern can contain `*` (matching any sequence of characters) and `?`
+/// (matching exactly one character). All other characters match themselves.
+fn wildcard_matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+
+    // A classic greedy algorithm with backtracking to the last star: whenever a
+    // mismatch occurs, the last star is made to consume one more character.
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_n)) => {
+                    backtrack = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// An error type for failures when parsing path patterns.
+#[derive(Debug)]
+pub struct PatternError {
+    /// The pattern that failed to parse.
+    pattern: String,
+    /// A reason of the failure.
+    reason: &'static str,
+}
+
+impl Display for PatternError {
+
+    fn fmt(&self, fmt: &mut Formatter) -> std::fmt::Result {
+        write!(fmt, "invalid pattern '{}': {}", self.pattern, self.reason)
+    }
+}
+
+impl std::error::Error for PatternError {
+
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+impl From<PatternError> for crate::session::ParseError {
+
+    fn from(error: PatternError) -> crate::session::ParseError {
+        crate::session::ParseError::malformed(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs::{create_dir, create_dir_all, File};
+
+    use super::*;
+
+    fn pattern(root: &Path, pattern: &str) -> Pattern {
+        Pattern::parse(&format!("{}/{}", root.display(), pattern)).unwrap()
+    }
+
+    fn expand(pattern: &Pattern, root: &Path) -> Vec<PathBuf> {
+        pattern.expand()
+            .map(|path| path.unwrap().strip_prefix(root).unwrap().to_path_buf())
+            .collect()
+    }
+
+    #[test]
+    fn test_wildcard_matches() {
+        assert!(wildcard_matches("foo", "foo"));
+        assert!(wildcard_matches("*", "foo"));
+        assert!(wildcard_matches("*", ""));
+        assert!(wildcard_matches("f*", "foo"));
+        assert!(wildcard_matches("*o", "foo"));
+        assert!(wildcard_matches("f?o", "foo"));
+        assert!(wildcard_matches("*.tar.*", "foo.tar.gz"));
+        assert!(wildcard_matches("a*b*c", "aXbYbZc"));
+
+        assert!(!wildcard_matches("foo", "bar"));
+        assert!(!wildcard_matches("f?", "foo"));
+        assert!(!wildcard_matches("*.txt", "foo.txt.gz"));
+    }
+
+    #[test]
+    fn test_display() {
+        let pattern = Pattern::parse("/home/**/.cache/*.tmp").unwrap();
+        assert_eq!(pattern.to_string(), "/home/**/.cache/*.tmp");
+        assert_eq!(Pattern::parse("//").unwrap().to_string(), "/");
+    }
+
+    #[test]
+    fn test_display_depth() {
+        let pattern = Pattern::parse("/foo/**3/**5").unwrap();
+        assert_eq!(pattern.to_string(), "/foo/**/**5");
+    }
+
+    #[test]
+    fn test_parse_relative() {
+        assert!(Pattern::parse("foo/bar").is_err());
+    }
+
+    #[test]
+    fn test_parse_components() {
+        let pattern = Pattern::parse("/foo/*.txt/**/**7").unwrap();
+        assert_eq!(pattern.components, vec![
+            Component::Literal(OsString::from("foo")),
+            Component::Wildcard(String::from("*.txt")),
+            Component::Recursive(DEFAULT_RECURSIVE_DEPTH),
+            Component::Recursive(7),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_invalid_depth() {
+        assert!(Pattern::parse("/foo/**bar").is_err());
+    }
+
+    #[test]
+    fn test_matches_literal() {
+        let pattern = Pattern::parse("/proc").unwrap();
+        assert!(pattern.matches("/proc"));
+        assert!(pattern.matches("/proc/"));
+        assert!(!pattern.matches("/proc/1"));
+        assert!(!pattern.matches("/process"));
+        assert!(!pattern.matches("/"));
+    }
+
+    #[test]
+    fn test_matches_wildcard() {
+        let pattern = Pattern::parse("/var/lib/docker/overlay*/*").unwrap();
+        assert!(pattern.matches("/var/lib/docker/overlay2/abc"));
+        assert!(!pattern.matches("/var/lib/docker/overlay2"));
+        assert!(!pattern.matches("/var/lib/docker/volumes/abc"));
+    }
+
+    #[test]
+    fn test_matches_recursive() {
+        let pattern = Pattern::parse("/home/**/.cache").unwrap();
+        assert!(pattern.matches("/home/.cache"));
+        assert!(pattern.matches("/home/foo/.cache"));
+        assert!(pattern.matches("/home/foo/bar/.cache"));
+        assert!(!pattern.matches("/home/foo/.cache/bar"));
+        assert!(!pattern.matches("/root/.cache"));
+    }
+
+    #[test]
+    fn test_matches_recursive_depth() {
+        let pattern = Pattern::parse("/home/**1/.cache").unwrap();
+        assert!(pattern.matches("/home/.cache"));
+        assert!(pattern.matches("/home/foo/.cache"));
+        assert!(!pattern.matches("/home/foo/bar/.cache"));
+
+        let pattern = Pattern::parse("/home/**").unwrap();
+        assert!(pattern.matches("/home/a/b/c"));
+        assert!(!pattern.matches("/home/a/b/c/d"));
+    }
+
+    #[test]
+    fn test_expand_literal() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let root = tempdir.path();
+        create_dir(root.join("foo")).unwrap();
+        File::create(root.join("foo").join("bar")).unwrap();
+
+        let existing = pattern(root, "foo/bar");
+        assert_eq!(expand(&existing, root), vec![PathBuf::from("foo/bar")]);
+
+        let missing = pattern(root, "foo/baz");
+        assert!(expand(&missing, root).is_empty());
+    }
+
+    #[test]
+    fn test_expand_wildcard() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let root = tempdir.path();
+        create_dir(root.join("foo")).unwrap();
+        create_dir(root.join("bar")).unwrap();
+        File::create(root.join("foo").join("a.txt")).unwrap();
+        File::create(root.join("foo").join("b.log")).unwrap();
+        File::create(root.join("bar").join("c.txt")).unwrap();
+
+        let pattern = pattern(root, "*/*.txt");
+        assert_eq!(expand(&pattern, root), vec![
+            PathBuf::from("bar/c.txt"),
+            PathBuf::from("foo/a.txt"),
+        ]);
+    }
+
+    #[test]
+    fn test_expand_recursive_depth() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let root = tempdir.path();
+        create_dir_all(root.join("a").join("b").join("c")).unwrap();
+
+        let pattern = pattern(root, "**2");
+        assert_eq!(expand(&pattern, root), vec![
+            PathBuf::from(""),
+            PathBuf::from("a"),
+            PathBuf::from("a/b"),
+        ]);
+    }
+
+    #[test]
+    fn test_expand_recursive_zero_directories() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let root = tempdir.path();
+        create_dir_all(root.join("a").join("b")).unwrap();
+        create_dir_all(root.join("a").join("c").join("b")).unwrap();
+
+        let pattern = pattern(root, "a/**/b");
+        assert_eq!(expand(&pattern, root), vec![
+            PathBuf::from("a/b"),
+            PathBuf::from("a/c/b"),
+        ]);
+    }
+
+    #[test]
+    fn test_expand_recursive_with_suffix() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let root = tempdir.path();
+        create_dir_all(root.join("a").join("b")).unwrap();
+        File::create(root.join("a").join("foo.txt")).unwrap();
+        File::create(root.join("a").join("b").join("bar.txt")).unwrap();
+        File::create(root.join("a").join("b").join("baz.log")).unwrap();
+
+        let pattern = pattern(root, "**/*.txt");
+        assert_eq!(expand(&pattern, root), vec![
+            PathBuf::from("a/foo.txt"),
+            PathBuf::from("a/b/bar.txt"),
+        ]);
+    }
+
+    #[test]
+    fn test_expand_matches() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let root = tempdir.path();
+        create_dir_all(root.join("a").join("b")).unwrap();
+        File::create(root.join("a").join("b").join("foo.txt")).unwrap();
+
+        let pattern = pattern(root, "**/b/*.txt");
+        for path in pattern.expand() {
+            assert!(pattern.matches(path.unwrap()));
+        }
+    }
+}
@@ -13,8 +13,8 @@ use std::default::Default;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use flate2::{Compression, write::GzEncoder, read::GzDecoder};
 
-/// Size of a gzchunked block.
-const BLOCK_SIZE: usize = 10 << 20;
+/// Default size of a gzchunked block.
+pub const BLOCK_SIZE: usize = 10 << 20;
 
 /// A wrapper type for gzip compression level.
 pub struct GzChunkedCompression(Compression);
@@ -23,6 +23,7 @@ pub struct GzChunkedCompression(Compression);
 pub struct GzChunkedEncoder {
     encoder: GzEncoder<Vec<u8>>,
     compression: GzChunkedCompression,
+    block_size: usize,
 }
 
 /// A gzchunked streaming decoder.
@@ -46,9 +47,16 @@ impl Default for GzChunkedCompression {
 impl GzChunkedEncoder {
     /// Creates a new encoder with specified gzip compression level.
     pub fn new(compression: GzChunkedCompression) -> GzChunkedEncoder {
+        GzChunkedEncoder::with_block_size(compression, BLOCK_SIZE)
+    }
+
+    /// Creates a new encoder with specified gzip compression level that yields
+    /// blocks of (roughly) `block_size` bytes.
+    pub fn with_block_size(compression: GzChunkedCompression, block_size: usize) -> GzChunkedEncoder {
         GzChunkedEncoder {
             encoder: GzEncoder::new(Vec::new(), compression.0),
             compression,
+            block_size,
         }
     }
 
@@ -63,7 +71,7 @@ impl GzChunkedEncoder {
     /// Returns `Ok(None)` if there's not enough data for a whole block.
     pub fn try_next_chunk(&mut self) -> std::io::Result<Option<Vec<u8>>> {
         self.encoder.flush()?;
-        if self.encoder.get_ref().len() < BLOCK_SIZE {
+        if self.encoder.get_ref().len() < self.block_size {
             return Ok(None)
         }
 
@@ -4,31 +4,181 @@
 // in the LICENSE file or at https://opensource.org/licenses/MIT.
 
 pub mod action;
+pub mod clock;
+pub mod control;
+pub mod crash;
 pub mod fs;
+pub mod logging;
 pub mod message;
 pub mod metadata;
+pub mod metrics;
 pub mod opts;
+pub mod selftest;
 pub mod session;
 pub mod gzchunked;
 
+use std::convert::TryFrom;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Condvar, Mutex};
+
+use log::{error, info, warn};
+
+use crate::action::Registry;
+use crate::message::Inbound;
 use crate::opts::{Opts};
+use crate::session::{Demand, Priority, Queue};
+
+/// A message waiting in the queue to be processed.
+enum Job {
+    /// An action request issued by a flow.
+    Request(Demand),
+    /// An agent-level control command.
+    Control(rrg_proto::GrrMessage),
+    /// A failure of the receiving thread (no more messages are going to come).
+    Disconnected(String),
+}
+
+/// A queue of jobs shared between the receiving and the processing thread.
+type SharedQueue = Arc<(Mutex<Queue<Job>>, Condvar)>;
 
 /// Enters the agent's main loop and waits for messages.
 ///
-/// It will poll for messages from the GRR server and should consume very few
-/// resources when idling. Once it picks a message, it dispatches it to an
-/// appropriate action handler (which should take care of sending heartbeat
-/// signals if expected to be long-running) and goes back to idling when action
-/// execution is finished.
+/// Messages from the GRR server are received on a background thread and put
+/// into a priority queue, so that urgent requests do not wait behind a backlog
+/// of less important ones. The main loop should consume very few resources
+/// when idling (apart from sending heartbeats). Once it picks a message, it
+/// dispatches it to an appropriate action handler (which should take care of
+/// sending heartbeat signals if expected to be long-running) and goes back to
+/// idling when action execution is finished.
 ///
 /// This function never terminates and panics only if something went very wrong
-/// (e.g. the Fleetspeak connection has been broken). All non-critical errors
+/// (e.g. the Fleetspeak connection has been broken, also when it is noticed by
+/// the receiving thread). All non-critical errors
 /// are going to be handled carefully, notifying the server about the failure if
 /// appropriate.
-pub fn listen(opts: &Opts) {
+///
+/// Requests are dispatched to actions from the given `registry`. Control
+/// commands (sent through a separate Fleetspeak service) bypass the session
+/// pipeline and are handled by the [`control`] module.
+///
+/// [`control`]: control/index.html
+pub fn listen(opts: &Opts, registry: &Registry<session::Action>) {
+    let queue: SharedQueue = Arc::new((
+        Mutex::new(Queue::new(opts.priority_aging)),
+        Condvar::new(),
+    ));
+
+    let receiver_queue = queue.clone();
+    std::thread::spawn(move || {
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            receive(&receiver_queue)
+        }));
+
+        // The receiving thread ends only if it panics. Its failure is passed to
+        // the main thread, so that the whole agent is terminated (otherwise it
+        // would keep sending heartbeats without ever getting any message).
+        if let Err(payload) = result {
+            let message = crash::panic_message(&*payload);
+            push(&receiver_queue, Job::Disconnected(message), Priority::High);
+        }
+    });
+
     loop {
-        if let Some(message) = message::collect(&opts) {
-            session::handle(message);
+        match next(&queue, opts) {
+            Job::Request(demand) => session::handle(demand, opts, registry),
+            Job::Control(message) => {
+                if control::handle(message, opts) == control::Outcome::Restart {
+                    restart(&queue);
+                }
+            }
+            Job::Disconnected(message) => {
+                panic!("failed to receive messages: {}", message)
+            }
+        }
+    }
+}
+
+/// Fails all the demands waiting in the `queue` and terminates the agent.
+///
+/// The queue stays locked until the process exits, so no other demand can be
+/// queued in the meantime. Demands received after that are still journaled, so
+/// they are reported as interrupted once the agent starts again.
+fn restart(queue: &SharedQueue) -> ! {
+    let (ref mutex, _) = **queue;
+
+    let mut queue = mutex.lock()
+        .unwrap_or_else(|error| error.into_inner());
+
+    while let Some(job) = queue.pop() {
+        match job {
+            Job::Request(demand) => session::abandon(demand),
+            Job::Control(_) => warn!("dropping a control command because of a restart"),
+            Job::Disconnected(_) => (),
+        }
+    }
+
+    info!("restarting the agent");
+    std::process::exit(0);
+}
+
+/// Receives messages and puts them into the `queue` (never returns).
+///
+/// Control commands are queued with the highest priority, so that they are
+/// handled as soon as the currently running action finishes. Demands are
+/// journaled before they are queued.
+fn receive(queue: &SharedQueue) {
+    loop {
+        let (job, priority) = match message::receive() {
+            Some(Inbound::Request(message)) => match Demand::try_from(message) {
+                Ok(demand) => {
+                    session::accept(&demand);
+
+                    let priority = demand.priority;
+                    (Job::Request(demand), priority)
+                }
+                Err(error) => {
+                    error!("failed to parse the message: {}", error);
+                    continue;
+                }
+            },
+            Some(Inbound::Control(message)) => (Job::Control(message), Priority::High),
+            None => continue,
+        };
+
+        push(queue, job, priority);
+    }
+}
+
+/// Puts the `job` into the `queue` and wakes up the processing thread.
+fn push(queue: &SharedQueue, job: Job, priority: Priority) {
+    let (ref mutex, ref condvar) = **queue;
+
+    let mut queue = mutex.lock()
+        .unwrap_or_else(|error| error.into_inner());
+    queue.push(job, priority);
+    metrics::queue_depth(queue.len());
+    condvar.notify_one();
+}
+
+/// Waits for the next job in the `queue`, sending heartbeats while idling.
+fn next(queue: &SharedQueue, opts: &Opts) -> Job {
+    let (ref mutex, ref condvar) = **queue;
+
+    let mut queue = mutex.lock()
+        .unwrap_or_else(|error| error.into_inner());
+
+    loop {
+        if let Some(job) = queue.pop() {
+            metrics::queue_depth(queue.len());
+            return job;
+        }
+
+        let (guard, timeout) = condvar.wait_timeout(queue, opts.heartbeat_rate)
+            .unwrap_or_else(|error| error.into_inner());
+        queue = guard;
+
+        if timeout.timed_out() {
+            message::heartbeat(opts.heartbeat_rate);
         }
     }
 }
@@ -0,0 +1,127 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Utilities for adjusting log verbosity at runtime.
+//!
+//! Loggers are supposed to be created with the most permissive level and the
+//! actual filtering happens through the global filter exposed by the `log`
+//! crate. This way the verbosity can be changed without restarting the agent
+//! (which is not an option when debugging a problem that goes away after the
+//! restart).
+
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use log::{error, info, LevelFilter};
+
+use crate::opts::Verbosity;
+
+/// A counter of verbosity changes.
+///
+/// It is used to cancel pending reverts if the verbosity has been changed again
+/// in the meantime.
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the initial log verbosity.
+///
+/// This function should be called once, right after the loggers have been
+/// initialized.
+pub fn init(level: LevelFilter) {
+    log::set_max_level(level);
+}
+
+/// Changes the log verbosity to the given `level`.
+///
+/// If `revert_after` is specified, the previous verbosity is restored once
+/// the given duration elapses, unless the verbosity has been changed again
+/// before that happens.
+pub fn set_level(level: LevelFilter, revert_after: Option<Duration>) {
+    let previous = log::max_level();
+    let generation = GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+
+    log::set_max_level(level);
+    info!("changed log verbosity from '{}' to '{}'", previous, level);
+
+    if let Some(duration) = revert_after {
+        std::thread::spawn(move || {
+            std::thread::sleep(duration);
+
+            let current = GENERATION.compare_exchange(
+                generation, generation + 1,
+                Ordering::SeqCst, Ordering::SeqCst,
+            );
+
+            // If the generation has changed, there was another change of the
+            // verbosity that should take precedence over this revert.
+            if current.is_ok() {
+                log::set_max_level(previous);
+                info!("reverted log verbosity from '{}' to '{}'", level, previous);
+            }
+        });
+    }
+}
+
+/// Reloads the log verbosity from the configuration.
+///
+/// If `path` is specified, the verbosity is read from the file it points to.
+/// Otherwise (or if reading the file fails), the `default` verbosity (usually
+/// the one specified on the command line) is restored.
+pub fn reload(default: Verbosity, path: Option<&Path>) {
+    let verbosity = match path {
+        Some(path) => match read_verbosity(path) {
+            Ok(verbosity) => verbosity,
+            Err(error) => {
+                error!("failed to read verbosity from '{}': {}",
+                       path.display(), error);
+                default
+            }
+        },
+        None => default,
+    };
+
+    set_level(verbosity.level(), None);
+}
+
+/// Reads the verbosity specification from a file at the given `path`.
+fn read_verbosity(path: &Path) -> Result<Verbosity, String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|error| error.to_string())?;
+
+    content.trim().parse()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_read_verbosity() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("verbosity");
+        std::fs::write(&path, "debug\n").unwrap();
+
+        let verbosity = read_verbosity(&path).unwrap();
+        assert_eq!(verbosity.level(), LevelFilter::Debug);
+    }
+
+    #[test]
+    fn test_read_verbosity_invalid() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("verbosity");
+        std::fs::write(&path, "loud").unwrap();
+
+        assert!(read_verbosity(&path).is_err());
+    }
+
+    #[test]
+    fn test_read_verbosity_non_existing() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("verbosity");
+
+        assert!(read_verbosity(&path).is_err());
+    }
+}
@@ -7,18 +7,27 @@ use std::fs::File;
 
 use log::{error, info};
 
-use rrg::action;
+use rrg::action::{self, Registry};
 use rrg::session;
-use rrg::opts::{self, Opts};
+use rrg::opts::{self, Command, Opts};
 
 fn main() {
     let opts = opts::from_args();
+
+    if let Some(Command::SelfTest) = opts.command {
+        init_log(&opts);
+        self_test();
+    }
+
     init(&opts);
 
+    let registry = Registry::builtin();
+
     fleetspeak::startup(env!("CARGO_PKG_VERSION"))
         .expect("failed to initialize Fleetspeak connection");
 
-    match action::startup::handle(&mut session::Adhoc, ()) {
+    let actions = registry.actions().copied().collect();
+    match action::startup::handle(&mut session::Adhoc, actions) {
         Err(error) => {
             error!("failed to collect startup information: {}", error);
         }
@@ -27,15 +36,46 @@ fn main() {
         }
     }
 
-    rrg::listen(&opts);
+    if let Some(state_dir) = &opts.state_dir {
+        session::recover(state_dir);
+    }
+
+    rrg::listen(&opts, &registry);
+}
+
+fn self_test() -> ! {
+    let outcomes = rrg::selftest::run();
+    rrg::selftest::print(&outcomes);
+
+    if outcomes.iter().all(|outcome| outcome.passed()) {
+        std::process::exit(0);
+    } else {
+        std::process::exit(1);
+    }
 }
 
 fn init(opts: &Opts) {
+    // The crash reporting hook is installed first, so that failures during the
+    // rest of the initialization are reported as well.
+    rrg::crash::init(opts.state_dir.as_deref());
+
     init_log(opts);
+
+    rrg::clock::init(opts.state_dir.as_deref());
+
+    session::init(opts);
+
+    #[cfg(target_family = "unix")]
+    init_signals(opts);
+
+    #[cfg(target_family = "unix")]
+    init_metrics(opts);
 }
 
 fn init_log(opts: &Opts) {
-    let level = opts.log_verbosity.level();
+    // Loggers accept everything and the actual filtering is done through the
+    // global level. This way, the verbosity can be changed at runtime.
+    let level = log::LevelFilter::Trace;
 
     let mut loggers = Vec::<Box<dyn simplelog::SharedLogger>>::new();
 
@@ -59,4 +99,34 @@ fn init_log(opts: &Opts) {
 
     simplelog::CombinedLogger::init(loggers)
         .expect("failed to init logging");
+
+    rrg::logging::init(opts.log_verbosity.level());
+}
+
+#[cfg(target_family = "unix")]
+fn init_signals(opts: &Opts) {
+    use signal_hook::iterator::Signals;
+
+    let signals = Signals::new(&[signal_hook::SIGHUP])
+        .expect("failed to register signal handlers");
+
+    let verbosity = opts.log_verbosity;
+    let verbosity_file = opts.log_verbosity_file.clone();
+
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            info!("received SIGHUP, reloading log verbosity");
+            rrg::logging::reload(verbosity, verbosity_file.as_deref());
+        }
+    });
+}
+
+#[cfg(target_family = "unix")]
+fn init_metrics(opts: &Opts) {
+    if let Some(path) = &opts.metrics_socket {
+        rrg::metrics::serve(path)
+            .expect("failed to serve metrics");
+
+        info!("serving metrics on '{}'", path.display());
+    }
 }
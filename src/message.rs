@@ -3,30 +3,83 @@
 // Use of this source code is governed by an MIT-style license that can be found
 // in the LICENSE file or at https://opensource.org/licenses/MIT.
 
+use std::io::{Read, Write};
+
 use fleetspeak::Packet;
 use log::{error, warn};
+use rrg_proto::grr_message::CompressionType;
+
+use crate::clock;
+use crate::control;
 
-use crate::opts::Opts;
+/// A message received from Fleetspeak.
+pub enum Inbound {
+    /// A request to execute an action issued by a GRR flow.
+    Request(rrg_proto::GrrMessage),
+    /// An agent-level command sent through the control service.
+    Control(rrg_proto::GrrMessage),
+}
 
 pub fn send(message: rrg_proto::GrrMessage) {
+        if let Err(error) = try_send(message) {
+            // If we failed to deliver the message through Fleetspeak, it means
+            // that our communication is broken (e.g. the pipe was closed) and
+            // the agent should be killed.
+            panic!("message delivery failure: {}", error)
+        };
+}
+
+pub fn try_send(message: rrg_proto::GrrMessage) -> Result<(), fleetspeak::WriteError> {
         let packet = Packet {
             service: String::from("GRR"),
             kind: Some(String::from("GrrMessage")),
             data: message,
         };
 
-        if let Err(error) = fleetspeak::send(packet) {
-            // If we failed to deliver the message through Fleetspeak, it means
-            // that our communication is broken (e.g. the pipe was closed) and
-            // the agent should be killed.
-            panic!("message delivery failure: {}", error)
+        fleetspeak::send(packet)
+}
+
+/// Sends the result of a control command through the control service.
+pub fn send_control(response: rrg_proto::rrg::ControlResponse) -> Result<(), fleetspeak::WriteError> {
+        let mut args = Vec::new();
+        prost::Message::encode(&response, &mut args)?;
+
+        let packet = Packet {
+            service: String::from(control::SERVICE_NAME),
+            kind: Some(String::from(control::RESPONSE_KIND)),
+            data: rrg_proto::GrrMessage {
+                r#type: Some(rrg_proto::grr_message::Type::Message.into()),
+                args_rdf_name: Some(String::from(control::RESPONSE_KIND)),
+                args: Some(args),
+                ..Default::default()
+            },
         };
+
+        fleetspeak::send(packet)
 }
 
-pub fn collect(opts: &Opts) -> Option<rrg_proto::GrrMessage> {
+/// Sends a heartbeat signal to the Fleetspeak client.
+///
+/// Heartbeats are sent no more often than the given `rate`, so it is cheap to
+/// call this function frequently (e.g. after every processed block of data).
+pub fn heartbeat(rate: std::time::Duration) {
+    if let Err(error) = fleetspeak::heartbeat_with_throttle(rate) {
+        // Similarly to message delivery, failing to send a heartbeat means that
+        // our communication is broken and the agent should be killed.
+        panic!("heartbeat delivery failure: {}", error)
+    }
+}
+
+/// Receives the next message, routing it according to its Fleetspeak service.
+///
+/// This function blocks until a message arrives without sending heartbeats in
+/// the meantime, so it is supposed to be called on a dedicated thread.
+///
+/// Messages sent by unrecognized services are logged and dropped.
+pub fn receive() -> Option<Inbound> {
     use fleetspeak::ReadError::*;
 
-    let packet = match fleetspeak::collect(opts.heartbeat_rate) {
+    let packet: Packet<rrg_proto::GrrMessage> = match fleetspeak::receive() {
         Ok(packet) => packet,
         Err(Malformed(error)) => {
             error!("received a malformed message: {}", error);
@@ -44,8 +97,29 @@ pub fn collect(opts: &Opts) -> Option<rrg_proto::GrrMessage> {
         }
     };
 
+    if let Some(timestamp) = packet.data.timestamp {
+        clock::observe(timestamp);
+    }
+
+    if packet.service == control::SERVICE_NAME {
+        match packet.kind {
+            Some(ref kind) if kind == control::REQUEST_KIND => (),
+            Some(ref kind) => {
+                error!("control message with unrecognized type '{}'", kind);
+                return None;
+            }
+            None => {
+                error!("control message with missing type specification");
+                return None;
+            }
+        }
+
+        return Some(Inbound::Control(packet.data));
+    }
+
     if packet.service != "GRR" {
-        warn!("message send by '{}' service (instead of GRR)", packet.service);
+        error!("message send by unrecognized '{}' service", packet.service);
+        return None;
     }
 
     match packet.kind {
@@ -58,5 +132,161 @@ pub fn collect(opts: &Opts) -> Option<rrg_proto::GrrMessage> {
         }
     }
 
-    Some(packet.data)
+    Some(Inbound::Request(packet.data))
+}
+
+/// Compresses arguments of the `message` if they are bigger than `threshold`.
+///
+/// Arguments are compressed using zlib and the compression flag of the message
+/// is set accordingly. If compression does not make the arguments smaller, the
+/// message is left intact.
+pub fn compress(mut message: rrg_proto::GrrMessage, threshold: usize) -> rrg_proto::GrrMessage {
+    let args = match message.args {
+        Some(ref args) if args.len() > threshold => args,
+        _ => return message,
+    };
+
+    let compressed = match deflate(args) {
+        Ok(compressed) => compressed,
+        Err(error) => {
+            // Writing to a vector does not fail, but even if it did there is
+            // no reason not to send the message uncompressed.
+            error!("failed to compress message arguments: {}", error);
+            return message;
+        }
+    };
+
+    if compressed.len() < args.len() {
+        message.args = Some(compressed);
+        message.compression = Some(CompressionType::Zcompression.into());
+    }
+
+    message
+}
+
+/// Compresses the given `data` using zlib.
+pub fn deflate(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Decompresses arguments of the `message` if they are compressed.
+///
+/// Upon success, the returned message has its compression flag cleared. If the
+/// decompressed arguments exceed `limit` bytes, an error is returned.
+pub fn decompress(mut message: rrg_proto::GrrMessage, limit: usize) -> std::io::Result<rrg_proto::GrrMessage> {
+    if !is_compressed(&message) {
+        return Ok(message);
+    }
+
+    if let Some(ref args) = message.args {
+        message.args = Some(inflate(args, limit)?);
+    }
+    message.compression = Some(CompressionType::Uncompressed.into());
+
+    Ok(message)
+}
+
+/// Checks whether arguments of the `message` are compressed.
+pub fn is_compressed(message: &rrg_proto::GrrMessage) -> bool {
+    message.compression == Some(CompressionType::Zcompression.into())
+}
+
+/// Decompresses zlib-compressed `data` that should not exceed `limit` bytes.
+///
+/// The decompressed data is never read past the limit, so a small malicious or
+/// corrupted input cannot make the agent exhaust its memory. Instead, an error
+/// is returned.
+pub fn inflate(data: &[u8], limit: usize) -> std::io::Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    flate2::read::ZlibDecoder::new(data)
+        .take((limit as u64).saturating_add(1))
+        .read_to_end(&mut decompressed)?;
+
+    if decompressed.len() > limit {
+        use std::io::{Error, ErrorKind};
+
+        let message = format!("decompressed data exceeds {} bytes", limit);
+        return Err(Error::new(ErrorKind::InvalidData, message));
+    }
+
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn message(args: Vec<u8>) -> rrg_proto::GrrMessage {
+        rrg_proto::GrrMessage {
+            args: Some(args),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_compress_below_threshold() {
+        let message = compress(message(vec![0; 512]), 1024);
+        assert_eq!(message.args, Some(vec![0; 512]));
+        assert_eq!(message.compression, None);
+    }
+
+    #[test]
+    fn test_compress_above_threshold() {
+        let message = compress(message(vec![0; 4096]), 1024);
+        assert!(message.args.unwrap().len() < 4096);
+        assert_eq!(message.compression, Some(CompressionType::Zcompression.into()));
+    }
+
+    #[test]
+    fn test_compress_incompressible() {
+        use rand::RngCore as _;
+
+        let mut args = vec![0; 4096];
+        rand::thread_rng().fill_bytes(&mut args);
+
+        let message = compress(message(args.clone()), 1024);
+        assert_eq!(message.args, Some(args));
+        assert_eq!(message.compression, None);
+    }
+
+    #[test]
+    fn test_decompress_uncompressed() {
+        let message = decompress(message(b"foo".to_vec()), 1024).unwrap();
+        assert_eq!(message.args, Some(b"foo".to_vec()));
+    }
+
+    #[test]
+    fn test_compress_and_decompress() {
+        let args = b"foobar".repeat(1024);
+
+        let message = decompress(compress(message(args.clone()), 1024), 8192).unwrap();
+        assert_eq!(message.args, Some(args));
+        assert_eq!(message.compression, Some(CompressionType::Uncompressed.into()));
+    }
+
+    #[test]
+    fn test_decompress_malformed() {
+        let mut message = message(b"foo".to_vec());
+        message.compression = Some(CompressionType::Zcompression.into());
+
+        assert!(decompress(message, 1024).is_err());
+    }
+
+    #[test]
+    fn test_decompress_over_limit() {
+        let message = compress(message(vec![0; 1024 * 1024]), 1024);
+        assert!(message.args.as_ref().unwrap().len() < 8192);
+
+        assert!(decompress(message, 8192).is_err());
+    }
+
+    #[test]
+    fn test_inflate_at_limit() {
+        let message = compress(message(vec![0; 4096]), 1024);
+        let data = inflate(message.args.as_ref().unwrap(), 4096).unwrap();
+        assert_eq!(data, vec![0; 4096]);
+    }
 }
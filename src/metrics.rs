@@ -0,0 +1,259 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Utilities for exposing agent metrics to local monitoring.
+//!
+//! Metrics are aggregated globally from the profiles that sessions collect and
+//! can be optionally served on a Unix socket in the Prometheus text format. A
+//! scraper connecting to the socket receives the current snapshot of metrics
+//! and the connection is closed afterwards.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+
+use crate::session::Profile;
+
+lazy_static! {
+    /// Metrics aggregated through the entire lifetime of the agent.
+    static ref METRICS: Mutex<Metrics> = Mutex::new(Metrics::default());
+}
+
+/// A snapshot of the agent metrics.
+#[derive(Debug, Default)]
+struct Metrics {
+    /// Numbers of executed actions keyed by action name and status.
+    actions: BTreeMap<(String, Status), u64>,
+    /// A total number of bytes sent to the server.
+    bytes_sent: u64,
+    /// A moment at which the currently running action signalled liveness.
+    ///
+    /// If no action is running, heartbeats are taken care of by the Fleetspeak
+    /// connection itself and this value is not set.
+    heartbeat: Option<Instant>,
+    /// A number of messages waiting in the queue to be processed.
+    queue_depth: u64,
+}
+
+/// A status of the finished action.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Status {
+    /// The action finished successfully.
+    Success,
+    /// The action finished with an error.
+    Failure,
+}
+
+impl Status {
+
+    /// Yields a label value corresponding to the status.
+    fn label(&self) -> &'static str {
+        match *self {
+            Status::Success => "success",
+            Status::Failure => "failure",
+        }
+    }
+}
+
+/// Records that the given `action` finished with the collected `profile`.
+pub fn record_action(action: &str, profile: &Profile, success: bool) {
+    let status = if success { Status::Success } else { Status::Failure };
+
+    let mut metrics = lock();
+    *metrics.actions.entry((String::from(action), status)).or_default() += 1;
+    metrics.bytes_sent += profile.counter("bytes_sent");
+}
+
+/// Records that an action has started executing.
+pub fn action_started() {
+    lock().heartbeat = Some(Instant::now());
+}
+
+/// Records that an action has finished executing.
+pub fn action_finished() {
+    lock().heartbeat = None;
+}
+
+/// Records the current number of messages waiting in the queue.
+pub fn queue_depth(depth: usize) {
+    lock().queue_depth = depth as u64;
+}
+
+/// Records that the running action has sent a heartbeat signal.
+pub fn heartbeat() {
+    let mut metrics = lock();
+    if metrics.heartbeat.is_some() {
+        metrics.heartbeat = Some(Instant::now());
+    }
+}
+
+/// Renders the current metrics in the Prometheus text format.
+pub fn render() -> String {
+    let metrics = lock();
+    let mut output = String::new();
+
+    // Writing to a string cannot fail, so it is safe to ignore the results.
+    let _ = writeln!(output, "# HELP rrg_actions_total Number of executed actions.");
+    let _ = writeln!(output, "# TYPE rrg_actions_total counter");
+    for ((action, status), count) in &metrics.actions {
+        let _ = writeln!(output, "rrg_actions_total{{action=\"{}\",status=\"{}\"}} {}",
+                         escape(action), status.label(), count);
+    }
+
+    let _ = writeln!(output, "# HELP rrg_bytes_sent_total Number of bytes sent to the server.");
+    let _ = writeln!(output, "# TYPE rrg_bytes_sent_total counter");
+    let _ = writeln!(output, "rrg_bytes_sent_total {}", metrics.bytes_sent);
+
+    let lag = metrics.heartbeat
+        .map(|heartbeat| heartbeat.elapsed())
+        .unwrap_or(Duration::from_secs(0));
+
+    let _ = writeln!(output, "# HELP rrg_heartbeat_lag_seconds Time since the running action signalled liveness.");
+    let _ = writeln!(output, "# TYPE rrg_heartbeat_lag_seconds gauge");
+    let _ = writeln!(output, "rrg_heartbeat_lag_seconds {}", lag.as_secs_f64());
+
+    let _ = writeln!(output, "# HELP rrg_queue_depth Number of messages waiting to be processed.");
+    let _ = writeln!(output, "# TYPE rrg_queue_depth gauge");
+    let _ = writeln!(output, "rrg_queue_depth {}", metrics.queue_depth);
+
+    if let Some(skew) = crate::clock::skew_micros() {
+        let _ = writeln!(output, "# HELP rrg_clock_skew_seconds Estimated skew of the agent clock behind the server.");
+        let _ = writeln!(output, "# TYPE rrg_clock_skew_seconds gauge");
+        let _ = writeln!(output, "rrg_clock_skew_seconds {}", skew as f64 / 1_000_000.0);
+    }
+
+    if let Some(memory) = memory() {
+        let _ = writeln!(output, "# HELP rrg_memory_bytes Resident memory of the agent process.");
+        let _ = writeln!(output, "# TYPE rrg_memory_bytes gauge");
+        let _ = writeln!(output, "rrg_memory_bytes {}", memory);
+    }
+
+    output
+}
+
+/// Starts serving metrics on a Unix socket at the given `path`.
+///
+/// The socket is served by a background thread, so this function returns as
+/// soon as the socket is bound. A stale socket left at `path` by a previous
+/// agent run is removed.
+#[cfg(target_family = "unix")]
+pub fn serve(path: &std::path::Path) -> std::io::Result<()> {
+    use std::io::Write as _;
+    use std::os::unix::net::UnixListener;
+
+    use log::error;
+
+    match std::fs::remove_file(path) {
+        Ok(()) => (),
+        Err(ref error) if error.kind() == std::io::ErrorKind::NotFound => (),
+        Err(error) => return Err(error),
+    }
+
+    let listener = UnixListener::bind(path)?;
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|mut stream| {
+                stream.write_all(render().as_bytes())
+            });
+
+            if let Err(error) = result {
+                error!("failed to serve metrics: {}", error);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Locks the global metrics, ignoring lock poisoning.
+///
+/// Metrics are simple counters that cannot be left in an inconsistent state,
+/// so there is no reason to propagate panics of other threads.
+fn lock() -> MutexGuard<'static, Metrics> {
+    METRICS.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+/// Retrieves the resident memory (in bytes) of the agent process.
+fn memory() -> Option<u64> {
+    use sysinfo::{ProcessExt, SystemExt};
+
+    let pid = sysinfo::get_current_pid().ok()?;
+
+    let mut system = sysinfo::System::new();
+    system.refresh_process(pid);
+
+    // The `sysinfo` crate reports memory in kilobytes.
+    system.get_process(pid).map(|process| process.memory() * 1024)
+}
+
+/// Escapes a label value according to the Prometheus text format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("foo"), "foo");
+        assert_eq!(escape("foo\"bar\\baz\n"), "foo\\\"bar\\\\baz\\n");
+    }
+
+    #[test]
+    fn test_render_actions() {
+        let mut profile = Profile::new();
+        profile.count("bytes_sent", 1337);
+
+        record_action("MetricsTestAction", &profile, true);
+        record_action("MetricsTestAction", &profile, true);
+        record_action("MetricsTestAction", &profile, false);
+
+        let output = render();
+        assert!(output.contains("rrg_actions_total{action=\"MetricsTestAction\",status=\"success\"} 2\n"));
+        assert!(output.contains("rrg_actions_total{action=\"MetricsTestAction\",status=\"failure\"} 1\n"));
+        assert!(output.contains("# TYPE rrg_bytes_sent_total counter\n"));
+        assert!(output.contains("# TYPE rrg_heartbeat_lag_seconds gauge\n"));
+        assert!(output.contains("# TYPE rrg_queue_depth gauge\n"));
+    }
+
+    #[test]
+    fn test_render_queue_depth() {
+        queue_depth(42);
+        assert!(render().contains("rrg_queue_depth 42\n"));
+        queue_depth(0);
+    }
+
+    #[test]
+    fn test_memory() {
+        assert!(memory().unwrap() > 0);
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_serve() {
+        use std::io::Read as _;
+        use std::os::unix::net::UnixStream;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("metrics.sock");
+        serve(&path).unwrap();
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+
+        assert!(output.contains("rrg_bytes_sent_total"));
+    }
+}
@@ -38,11 +38,64 @@ pub struct Opts {
                 help="Enables logging to the specified file")]
     pub log_file: Option<PathBuf>,
 
+    /// A path to the file to read the log verbosity from on reload.
+    #[structopt(long="log-verbosity-file", name="VERBOSITY_FILE",
+                help="Specifies a file to read the log verbosity from on reload")]
+    pub log_verbosity_file: Option<PathBuf>,
+
     /// A frequency of heartbeat messages to send to the Fleetspeak client.
     #[structopt(long="heartbeat-rate", name="DURATION", default_value="5s",
-                parse(try_from_str = humantime::parse_duration),
+                parse(try_from_str = parse_heartbeat_rate),
                 help="Specifies the frequency of heartbeat messages")]
     pub heartbeat_rate: Duration,
+
+    /// A time after which queued requests are promoted to a higher priority.
+    #[structopt(long="priority-aging", name="AGING", default_value="10m",
+                parse(try_from_str = humantime::parse_duration),
+                help="Specifies after how long queued requests get a higher priority")]
+    pub priority_aging: Duration,
+
+    /// A size above which outgoing messages are compressed.
+    #[structopt(long="compression-threshold", name="SIZE", default_value="1024",
+                help="Specifies the size (in bytes) above which messages are compressed")]
+    pub compression_threshold: usize,
+
+    /// A maximum size of a single outgoing message.
+    #[structopt(long="max-message-size", name="MAX_SIZE", default_value="2097152",
+                help="Specifies the maximum size (in bytes) of outgoing messages")]
+    pub max_message_size: usize,
+
+    /// A maximum rate at which outgoing messages are sent (zero if unlimited).
+    #[structopt(long="send-rate-limit", name="RATE", default_value="1048576",
+                help="Limits the rate (in bytes per second) of outgoing messages (0 disables the limit)")]
+    pub send_rate_limit: u64,
+
+    /// A maximum number of bytes that can be sent in a burst when rate-limited.
+    #[structopt(long="send-burst-size", name="BURST_SIZE", default_value="8388608",
+                help="Specifies the size (in bytes) of bursts allowed above the rate limit")]
+    pub send_burst_size: u64,
+
+    /// A path to the Unix socket to expose metrics on.
+    #[structopt(long="metrics-socket", name="SOCKET",
+                help="Enables serving metrics on the specified Unix socket")]
+    pub metrics_socket: Option<PathBuf>,
+
+    /// A path to the directory for persisting agent state across restarts.
+    #[structopt(long="state-dir", name="DIR",
+                help="Specifies a directory for persisting state across restarts")]
+    pub state_dir: Option<PathBuf>,
+
+    /// A command to execute instead of running the agent.
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// A type listing commands that can be executed instead of running the agent.
+#[derive(StructOpt)]
+pub enum Command {
+    /// Runs actions against the local machine and validates their responses.
+    #[structopt(name="self-test")]
+    SelfTest,
 }
 
 /// Parses command-line arguments.
@@ -56,6 +109,21 @@ pub fn from_args() -> Opts {
     Opts::from_args()
 }
 
+/// Parses a heartbeat rate, rejecting zero durations.
+///
+/// Heartbeats are sent from loops waiting for the rate between iterations, so
+/// with a zero rate the agent would just spin.
+fn parse_heartbeat_rate(string: &str) -> std::result::Result<Duration, String> {
+    let rate = humantime::parse_duration(string)
+        .map_err(|error| error.to_string())?;
+
+    if rate == Duration::from_secs(0) {
+        return Err(String::from("heartbeat rate must be positive"));
+    }
+
+    Ok(rate)
+}
+
 /// A type representing level of log verbosity.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Verbosity {
@@ -0,0 +1,409 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Utilities for verifying that actions work on the local machine.
+//!
+//! The self-test executes a number of built-in actions (all the ones that do not
+//! need any arguments and a few with synthetic arguments) through an in-process
+//! session that never talks to Fleetspeak. Every response the actions send is
+//! validated against the GRR schema of its declared RDF class: it has to decode
+//! as the corresponding GRR message and all the fields that GRR knows about
+//! have to survive such decoding intact.
+//!
+//! It is supposed to be used to smoke-test new builds before rolling them out.
+
+use std::fmt::{Display, Formatter};
+
+use crate::action::{self, Registry};
+use crate::session::{self, Payload, Session, Sink, Task};
+
+/// A single self-test case.
+struct Case {
+    /// A name of the action to execute.
+    action: &'static str,
+    /// Serialized arguments of the action (if any).
+    args: Option<Vec<u8>>,
+}
+
+/// An outcome of a single self-test case.
+pub struct Outcome {
+    /// A name of the executed action.
+    pub action: &'static str,
+    /// A number of responses the action sent.
+    pub responses: usize,
+    /// A result of the action execution (with the error message on failure).
+    pub result: Result<(), String>,
+}
+
+impl Outcome {
+
+    /// Checks whether the case passed.
+    pub fn passed(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+/// Runs all the self-test cases and returns their outcomes.
+pub fn run() -> Vec<Outcome> {
+    let registry = Registry::builtin();
+
+    cases(&registry).into_iter()
+        .map(|case| check(&registry, case))
+        .collect()
+}
+
+/// Prints a pass/fail table of the given self-test `outcomes`.
+pub fn print(outcomes: &[Outcome]) {
+    println!("{:<24} {:<6} {:>9}  {}", "ACTION", "RESULT", "RESPONSES", "ERROR");
+
+    for outcome in outcomes {
+        let (status, error) = match outcome.result {
+            Ok(()) => ("pass", ""),
+            Err(ref error) => ("FAIL", error.as_str()),
+        };
+
+        println!("{:<24} {:<6} {:>9}  {}",
+                 outcome.action, status, outcome.responses, error);
+    }
+
+    let failed = outcomes.iter().filter(|outcome| !outcome.passed()).count();
+    println!("{} passed, {} failed", outcomes.len() - failed, failed);
+}
+
+/// Yields all the self-test cases applicable to the actions in the `registry`.
+fn cases(registry: &Registry<Probe>) -> Vec<Case> {
+    // Actions without a request RDF class do not take any arguments.
+    let mut cases = registry.actions()
+        .filter(|metadata| metadata.request.is_none())
+        .map(|metadata| Case { action: metadata.info.name, args: None })
+        .collect::<Vec<_>>();
+
+    // The network connections action has arguments, but all of them are
+    // optional, so it is fine to execute it with the default ones.
+    cases.push(Case { action: "ListNetworkConnections", args: None });
+
+    cases.push(Case {
+        action: "ListDirectory",
+        args: Some(encode(rrg_proto::ListDirRequest {
+            pathspec: Some(pathspec("/")),
+            iterator: None,
+        })),
+    });
+
+    #[cfg(target_family = "unix")]
+    cases.push(Case {
+        action: "GetFileStat",
+        args: Some(encode(rrg_proto::GetFileStatRequest {
+            pathspec: Some(pathspec("/etc/hostname")),
+            ..Default::default()
+        })),
+    });
+
+    cases
+}
+
+/// Executes the given self-test `case` with an action from the `registry`.
+fn check(registry: &Registry<Probe>, case: Case) -> Outcome {
+    let mut session = Probe::new();
+
+    let result = registry.dispatch(case.action, Task {
+        session: &mut session,
+        payload: Payload {
+            data: case.args,
+            rdf_name: None,
+            compressed: false,
+        },
+    });
+
+    Outcome {
+        action: case.action,
+        responses: session.responses,
+        result: result.map_err(|error| error.to_string()),
+    }
+}
+
+/// Constructs an ordinary filesystem path specification for the given `path`.
+fn pathspec(path: &str) -> rrg_proto::PathSpec {
+    use rrg_proto::path_spec::{Options, PathType};
+
+    rrg_proto::PathSpec {
+        path_options: Some(Options::CaseLiteral as i32),
+        pathtype: Some(PathType::Os as i32),
+        path: Some(String::from(path)),
+        ..Default::default()
+    }
+}
+
+/// Serializes the given proto `message` to bytes.
+fn encode<M: prost::Message>(message: M) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    // Encoding to a vector can fail only if we run out of memory, in which case
+    // we are doomed anyway.
+    message.encode(&mut bytes)
+        .expect("failed to encode a self-test request");
+
+    bytes
+}
+
+/// An in-process session that validates responses instead of sending them.
+struct Probe {
+    /// A number of responses validated so far.
+    responses: usize,
+}
+
+impl Probe {
+
+    /// Creates a new probe session.
+    fn new() -> Probe {
+        Probe {
+            responses: 0,
+        }
+    }
+
+    /// Checks that the response conforms to the schema of its RDF class.
+    fn validate<R>(&mut self, response: R) -> session::Result<()>
+    where
+        R: action::Response,
+    {
+        self.responses += 1;
+
+        let mut bytes = Vec::new();
+        prost::Message::encode(&response.into_proto(), &mut bytes)?;
+
+        let rdf_name = match R::RDF_NAME {
+            Some(rdf_name) => rdf_name,
+            None => return Err(self.error(SchemaErrorKind::Untyped)),
+        };
+
+        match conform(rdf_name, &bytes) {
+            Ok(()) => Ok(()),
+            Err(kind) => Err(self.error(kind)),
+        }
+    }
+
+    /// Creates an error for the response that has been validated last.
+    fn error(&self, kind: SchemaErrorKind) -> session::Error {
+        session::Error::action(SchemaError {
+            response: self.responses,
+            kind: kind,
+        })
+    }
+}
+
+/// Checks that serialized message `bytes` conform to the `rdf_name` class.
+///
+/// RDF classes that are not backed by proto messages on the GRR side (e.g.
+/// `ByteSize`) are primitive values and are accepted as they are.
+fn conform(rdf_name: &'static str, bytes: &[u8]) -> Result<(), SchemaErrorKind> {
+    match rdf_name {
+        "ClientCrash" => conform_to::<rrg_proto::ClientCrash>(bytes),
+        "ClientInformation" => conform_to::<rrg_proto::ClientInformation>(bytes),
+        "DataBlob" => conform_to::<rrg_proto::DataBlob>(bytes),
+        "FileFinderResult" => conform_to::<rrg_proto::FileFinderResult>(bytes),
+        "Filesystem" => conform_to::<rrg_proto::Filesystem>(bytes),
+        "Interface" => conform_to::<rrg_proto::Interface>(bytes),
+        "NetworkConnection" => conform_to::<rrg_proto::NetworkConnection>(bytes),
+        "StartupInfo" => conform_to::<rrg_proto::StartupInfo>(bytes),
+        "StatEntry" => conform_to::<rrg_proto::StatEntry>(bytes),
+        "TimelineResult" => conform_to::<rrg_proto::TimelineResult>(bytes),
+        "ByteSize" | "RDFString" => Ok(()),
+        _ => Err(SchemaErrorKind::UnknownClass(rdf_name)),
+    }
+}
+
+/// Checks that serialized message `bytes` conform to the GRR message `M`.
+///
+/// The message has to decode as `M` and, once encoded back, has to match the
+/// original bytes with RRG extension fields (the ones numbered from 101 up)
+/// removed. This means that GRR sees every field it knows about exactly as the
+/// agent sent it (e.g. no value got truncated to a narrower integer type).
+///
+/// Note that only top-level extension fields are skipped, so extensions nested
+/// in submessages are reported as mismatches.
+fn conform_to<M>(bytes: &[u8]) -> Result<(), SchemaErrorKind>
+where
+    M: prost::Message + Default,
+{
+    let message = M::decode(bytes)
+        .map_err(SchemaErrorKind::Malformed)?;
+
+    let mut encoded = Vec::new();
+    // Encoding to a vector can fail only if we run out of memory, in which case
+    // we are doomed anyway.
+    message.encode(&mut encoded)
+        .expect("failed to encode a self-test response");
+
+    let expected = strip_extensions(bytes)
+        .map_err(SchemaErrorKind::Malformed)?;
+
+    if encoded != expected {
+        return Err(SchemaErrorKind::Mismatch);
+    }
+
+    Ok(())
+}
+
+/// A number of the first field reserved for RRG extensions of GRR messages.
+const EXTENSION_FIELD_START: u32 = 101;
+
+/// Removes top-level RRG extension fields from serialized message `bytes`.
+fn strip_extensions(mut bytes: &[u8]) -> Result<Vec<u8>, prost::DecodeError> {
+    use prost::encoding::{decode_key, skip_field, DecodeContext};
+
+    let mut result = Vec::new();
+    while !bytes.is_empty() {
+        let field = bytes;
+
+        let (tag, wire_type) = decode_key(&mut bytes)?;
+        skip_field(wire_type, tag, &mut bytes, DecodeContext::default())?;
+
+        if tag < EXTENSION_FIELD_START {
+            result.extend_from_slice(&field[..field.len() - bytes.len()]);
+        }
+    }
+
+    Ok(result)
+}
+
+impl Session for Probe {
+
+    fn reply<R>(&mut self, response: R) -> session::Result<()>
+    where
+        R: action::Response,
+    {
+        self.validate(response)
+    }
+
+    fn send<R>(&mut self, _sink: Sink, response: R) -> session::Result<()>
+    where
+        R: action::Response,
+    {
+        self.validate(response)
+    }
+}
+
+/// An error type for responses that do not conform to their RDF class schema.
+#[derive(Debug)]
+struct SchemaError {
+    /// A number of the offending response.
+    response: usize,
+    /// A reason why the response does not conform to the schema.
+    kind: SchemaErrorKind,
+}
+
+/// Reasons why a response does not conform to its RDF class schema.
+#[derive(Debug)]
+enum SchemaErrorKind {
+    /// The response does not declare any RDF class.
+    Untyped,
+    /// There is no known schema for the declared RDF class.
+    UnknownClass(&'static str),
+    /// The response cannot be decoded as its RDF class.
+    Malformed(prost::DecodeError),
+    /// Some fields of the response are not decoded as they were sent.
+    Mismatch,
+}
+
+impl Display for SchemaError {
+
+    fn fmt(&self, fmt: &mut Formatter) -> std::fmt::Result {
+        use SchemaErrorKind::*;
+
+        write!(fmt, "response #{} ", self.response)?;
+        match self.kind {
+            Untyped => write!(fmt, "has no RDF class"),
+            UnknownClass(name) => write!(fmt, "has unknown RDF class '{}'", name),
+            Malformed(ref error) => write!(fmt, "is malformed: {}", error),
+            Mismatch => write!(fmt, "is not decoded by GRR as it was sent"),
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_check_no_args() {
+        let outcome = check(&Registry::builtin(), Case { action: "GetMemorySize", args: None });
+        assert!(outcome.passed());
+        assert_eq!(outcome.responses, 1);
+    }
+
+    #[test]
+    fn test_check_synthetic_args() {
+        let outcome = check(&Registry::builtin(), Case {
+            action: "ListDirectory",
+            args: Some(encode(rrg_proto::ListDirRequest {
+                pathspec: Some(pathspec("/")),
+                iterator: None,
+            })),
+        });
+
+        assert!(outcome.passed());
+        assert!(outcome.responses > 0);
+    }
+
+    #[test]
+    fn test_cases_without_args() {
+        let cases = cases(&Registry::builtin());
+        assert!(cases.iter().any(|case| case.action == "GetMemorySize"));
+        assert!(!cases.iter().any(|case| case.action == "SetLogLevel"));
+    }
+
+    #[test]
+    fn test_conform_stat_entry() {
+        let entry = rrg_proto::StatEntry {
+            st_size: Some(1337),
+            symlink: Some(String::from("/foo/bar")),
+            ..Default::default()
+        };
+
+        assert!(conform("StatEntry", &encode(entry)).is_ok());
+    }
+
+    #[test]
+    fn test_conform_extension_fields() {
+        let info = rrg_proto::rrg::ClientInformation {
+            client_name: Some(String::from("rrg")),
+            clock_skew_us: Some(-42),
+            ..Default::default()
+        };
+
+        assert!(conform("ClientInformation", &encode(info)).is_ok());
+    }
+
+    #[test]
+    fn test_conform_truncated_field() {
+        // GRR declares the number of links (field 4) as a 32-bit integer, so
+        // this value does not survive decoding on the server.
+        let mut bytes = Vec::new();
+        prost::encoding::uint64::encode(4, &(1 << 40), &mut bytes);
+
+        match conform("StatEntry", &bytes) {
+            Err(SchemaErrorKind::Mismatch) => (),
+            _ => panic!("unexpected conformance result"),
+        }
+    }
+
+    #[test]
+    fn test_conform_unknown_class() {
+        match conform("Foo", &[]) {
+            Err(SchemaErrorKind::UnknownClass("Foo")) => (),
+            _ => panic!("unexpected conformance result"),
+        }
+    }
+
+    #[test]
+    fn test_check_unknown_action() {
+        let outcome = check(&Registry::builtin(), Case { action: "Foo", args: None });
+        assert!(!outcome.passed());
+        assert_eq!(outcome.responses, 0);
+    }
+}
@@ -23,12 +23,52 @@ pub struct Demand {
     pub action: String,
     /// A demand metadata.
     pub header: Header,
+    /// A priority of the demand assigned by the server.
+    pub priority: Priority,
     /// Serialized action request.
     pub payload: Payload,
 }
 
+/// A priority of a demand assigned by the server.
+///
+/// Priorities are ordered, so a demand with a greater priority should be
+/// dispatched before the ones with a lesser one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// A priority of demands that can wait (e.g. issued by hunts).
+    Low,
+    /// A default priority of demands.
+    Medium,
+    /// A priority of urgent demands (e.g. issued by incident response flows).
+    High,
+}
+
+impl Priority {
+
+    /// Returns a priority that is one level higher (if there is any).
+    pub fn promoted(self) -> Priority {
+        match self {
+            Priority::Low => Priority::Medium,
+            Priority::Medium | Priority::High => Priority::High,
+        }
+    }
+}
+
+impl From<rrg_proto::grr_message::Priority> for Priority {
+
+    fn from(priority: rrg_proto::grr_message::Priority) -> Priority {
+        use rrg_proto::grr_message::Priority::*;
+
+        match priority {
+            LowPriority => Priority::Low,
+            MediumPriority => Priority::Medium,
+            HighPriority => Priority::High,
+        }
+    }
+}
+
 /// Metadata about the demand issued by the server.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Header {
     /// A server-issued session identifier (usually corresponds to a flow).
     pub session_id: String,
@@ -41,6 +81,10 @@ pub struct Header {
 pub struct Payload {
     /// Raw bytes of the serialized request.
     pub data: Option<Vec<u8>>,
+    /// A name of the RDF class of the serialized request (if specified).
+    pub rdf_name: Option<String>,
+    /// Whether the raw bytes are compressed with zlib.
+    pub compressed: bool,
 }
 
 impl Payload {
@@ -48,14 +92,29 @@ impl Payload {
     /// Parses the serialized data to a specific request type.
     ///
     /// If the payload contains no data, a default request instance is created.
+    /// Compressed data is decompressed first, up to `max_size` bytes.
     ///
     /// If the data is malformed and fails to correctly parse to a specific
-    /// request instance, an error is returned.
-    pub fn parse<R>(&self) -> Result<R, session::ParseError>
+    /// request instance, an error is returned. The same happens if the payload
+    /// specifies an RDF class different than the one the request expects, as
+    /// decoding a message of the wrong type usually "succeeds".
+    pub fn parse<R>(&self, max_size: usize) -> Result<R, session::ParseError>
     where
         R: action::Request,
     {
+        if let (Some(expected), Some(actual)) = (R::RDF_NAME, &self.rdf_name) {
+            if expected != actual {
+                return Err(session::RdfNameError::new(expected, actual.as_str()).into());
+            }
+        }
+
         let proto = match &self.data {
+            Some(ref bytes) if self.compressed => {
+                let bytes = crate::message::inflate(bytes, max_size)
+                    .map_err(session::ParseError::malformed)?;
+
+                prost::Message::decode(&bytes[..])?
+            }
             Some(ref bytes) => prost::Message::decode(&bytes[..])?,
             None => Default::default(),
         };
@@ -71,18 +130,162 @@ impl TryFrom<rrg_proto::GrrMessage> for Demand {
     fn try_from(message: rrg_proto::GrrMessage) -> Result<Demand, Self::Error> {
         let missing = session::MissingFieldError::new;
 
+        // Arguments are decompressed only when parsed, so that a demand with
+        // malformed ones still gets a status with an appropriate error.
+        let compressed = crate::message::is_compressed(&message);
+
         let header = Header {
             session_id: message.session_id.ok_or(missing("session id"))?,
             request_id: message.request_id.ok_or(missing("request id"))?,
         };
 
+        // GRR considers messages without an explicit priority to be of medium
+        // priority (and so do we with ones of an unknown priority).
+        let priority = message.priority
+            .and_then(rrg_proto::grr_message::Priority::from_i32)
+            .map_or(Priority::Medium, Priority::from);
+
         Ok(Demand {
             action: message.name.ok_or(missing("action name"))?,
             header: header,
+            priority: priority,
             payload: Payload {
                 data: message.args,
+                rdf_name: message.args_rdf_name,
+                compressed: compressed,
             },
         })
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::action::loglevel;
+
+    fn payload(rdf_name: Option<&str>) -> Payload {
+        let mut data = Vec::new();
+        prost::Message::encode(&rrg_proto::rrg::SetLogLevelArgs {
+            level: Some(String::from("debug")),
+            revert_after_us: None,
+        }, &mut data).unwrap();
+
+        Payload {
+            data: Some(data),
+            rdf_name: rdf_name.map(String::from),
+            compressed: false,
+        }
+    }
+
+    #[test]
+    fn test_parse_matching_rdf_name() {
+        let payload = payload(Some("SetLogLevelArgs"));
+        assert!(payload.parse::<loglevel::Request>(1024).is_ok());
+    }
+
+    #[test]
+    fn test_parse_missing_rdf_name() {
+        let payload = payload(None);
+        assert!(payload.parse::<loglevel::Request>(1024).is_ok());
+    }
+
+    #[test]
+    fn test_parse_mismatched_rdf_name() {
+        let payload = payload(Some("ListDirRequest"));
+
+        let error = payload.parse::<loglevel::Request>(1024).unwrap_err();
+        assert!(error.to_string().contains("SetLogLevelArgs"));
+        assert!(error.to_string().contains("ListDirRequest"));
+    }
+
+    #[test]
+    fn test_demand_from_message() {
+        let demand = Demand::try_from(rrg_proto::GrrMessage {
+            session_id: Some(String::from("F:ABC")),
+            request_id: Some(42),
+            name: Some(String::from("SetLogLevel")),
+            args_rdf_name: Some(String::from("SetLogLevelArgs")),
+            ..Default::default()
+        }).unwrap();
+
+        assert_eq!(demand.payload.rdf_name, Some(String::from("SetLogLevelArgs")));
+    }
+
+    #[test]
+    fn test_demand_from_compressed_message() {
+        use std::io::Write as _;
+
+        let args = payload(None).data.unwrap();
+
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(&args).unwrap();
+
+        let demand = Demand::try_from(rrg_proto::GrrMessage {
+            session_id: Some(String::from("F:ABC")),
+            request_id: Some(42),
+            name: Some(String::from("SetLogLevel")),
+            args: Some(encoder.finish().unwrap()),
+            compression: Some(rrg_proto::grr_message::CompressionType::Zcompression.into()),
+            ..Default::default()
+        }).unwrap();
+
+        assert!(demand.payload.compressed);
+        assert!(demand.payload.parse::<loglevel::Request>(1024).is_ok());
+    }
+
+    #[test]
+    fn test_demand_from_malformed_compressed_message() {
+        let demand = Demand::try_from(rrg_proto::GrrMessage {
+            session_id: Some(String::from("F:ABC")),
+            request_id: Some(42),
+            name: Some(String::from("SetLogLevel")),
+            args: Some(b"foo".to_vec()),
+            compression: Some(rrg_proto::grr_message::CompressionType::Zcompression.into()),
+            ..Default::default()
+        }).unwrap();
+
+        assert_eq!(demand.header.request_id, 42);
+        assert!(demand.payload.parse::<loglevel::Request>(1024).is_err());
+    }
+
+    #[test]
+    fn test_parse_compressed_over_limit() {
+        use std::io::Write as _;
+
+        let args = payload(None).data.unwrap();
+
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(&args).unwrap();
+
+        let payload = Payload {
+            data: Some(encoder.finish().unwrap()),
+            rdf_name: None,
+            compressed: true,
+        };
+
+        assert!(payload.parse::<loglevel::Request>(args.len()).is_ok());
+        assert!(payload.parse::<loglevel::Request>(args.len() - 1).is_err());
+    }
+
+    #[test]
+    fn test_demand_priority() {
+        use rrg_proto::grr_message::Priority::*;
+
+        fn demand(priority: Option<i32>) -> Demand {
+            Demand::try_from(rrg_proto::GrrMessage {
+                session_id: Some(String::from("F:ABC")),
+                request_id: Some(42),
+                name: Some(String::from("SetLogLevel")),
+                priority: priority,
+                ..Default::default()
+            }).unwrap()
+        }
+
+        assert_eq!(demand(Some(LowPriority.into())).priority, Priority::Low);
+        assert_eq!(demand(Some(HighPriority.into())).priority, Priority::High);
+        assert_eq!(demand(None).priority, Priority::Medium);
+        assert_eq!(demand(Some(1337)).priority, Priority::Medium);
+    }
+}
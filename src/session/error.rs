@@ -15,6 +15,10 @@ pub enum Error {
     Encode(prost::EncodeError),
     /// An error occurred when parsing a proto message.
     Parse(ParseError),
+    /// The agent restarted before the action execution finished.
+    Restarted,
+    /// A response was too large to be sent.
+    TooLarge(TooLargeError),
 }
 
 impl Error {
@@ -52,6 +56,12 @@ impl Display for Error {
             Parse(ref error) => {
                 write!(fmt, "malformed proto message: {}", error)
             }
+            Restarted => {
+                write!(fmt, "agent restarted before the action finished")
+            }
+            TooLarge(ref error) => {
+                write!(fmt, "failed to send the response: {}", error)
+            }
         }
     }
 }
@@ -66,6 +76,8 @@ impl std::error::Error for Error {
             Dispatch(_) => None,
             Encode(ref error) => Some(error),
             Parse(ref error) => Some(error),
+            Restarted => None,
+            TooLarge(ref error) => Some(error),
         }
     }
 }
@@ -84,6 +96,13 @@ impl From<ParseError> for Error {
     }
 }
 
+impl From<TooLargeError> for Error {
+
+    fn from(error: TooLargeError) -> Error {
+        Error::TooLarge(error)
+    }
+}
+
 /// An error type for failures that can occur when parsing proto messages.
 #[derive(Debug)]
 pub enum ParseError {
@@ -179,3 +198,82 @@ impl From<MissingFieldError> for ParseError {
         ParseError::malformed(error)
     }
 }
+
+/// An error type for situations where the request is of an unexpected class.
+#[derive(Debug)]
+pub struct RdfNameError {
+    /// A name of the RDF class that the action expects.
+    expected: &'static str,
+    /// A name of the RDF class that the demand specified.
+    actual: String,
+}
+
+impl RdfNameError {
+
+    /// Creates a new error indicating that `actual` was given instead of
+    /// the `expected` RDF class.
+    pub fn new<S: Into<String>>(expected: &'static str, actual: S) -> RdfNameError {
+        RdfNameError {
+            expected: expected,
+            actual: actual.into(),
+        }
+    }
+}
+
+impl Display for RdfNameError {
+
+    fn fmt(&self, fmt: &mut Formatter) -> std::fmt::Result {
+        write!(fmt, "expected arguments of class '{}', got '{}'",
+               self.expected, self.actual)
+    }
+}
+
+impl std::error::Error for RdfNameError {
+
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+impl From<RdfNameError> for ParseError {
+
+    fn from(error: RdfNameError) -> ParseError {
+        ParseError::malformed(error)
+    }
+}
+
+/// An error type for situations where a message exceeds the size limit.
+#[derive(Debug)]
+pub struct TooLargeError {
+    /// A size (in bytes) of the serialized message.
+    size: usize,
+    /// A maximum allowed size (in bytes) of the message.
+    limit: usize,
+}
+
+impl TooLargeError {
+
+    /// Creates a new error indicating that a message of `size` bytes exceeds
+    /// the `limit`.
+    pub fn new(size: usize, limit: usize) -> TooLargeError {
+        TooLargeError {
+            size: size,
+            limit: limit,
+        }
+    }
+}
+
+impl Display for TooLargeError {
+
+    fn fmt(&self, fmt: &mut Formatter) -> std::fmt::Result {
+        write!(fmt, "message of {} bytes exceeds the limit of {} bytes",
+               self.size, self.limit)
+    }
+}
+
+impl std::error::Error for TooLargeError {
+
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
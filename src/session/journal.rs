@@ -0,0 +1,404 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Utilities for journaling action requests on disk.
+//!
+//! If the agent is killed in the middle of an action execution, the server would
+//! wait for the final status forever. To prevent this, every request is written
+//! to the journal as soon as it is received (even if it has to wait in a queue
+//! before it starts) and removed from it once its final status is sent. Requests
+//! that are still in the journal when the agent starts again were interrupted
+//! and the server can be notified about them.
+//!
+//! The journal also keeps track of the next response identifier of every request,
+//! so that the notification does not collide with responses sent before.
+//!
+//! Responses can be sent thousands of times per request, so the journal is an
+//! append-only log of records rather than a file rewritten on every change. The
+//! records are replayed when the journal is opened. Once no request is pending
+//! (or the log grows too long) it is compacted to the pending requests only.
+//!
+//! Accepting and finishing requests is synced to disk right away. Updates of the
+//! response identifiers are only appended: they survive a crash of the agent,
+//! but not necessarily a crash of the whole system.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+
+use log::error;
+
+use crate::session::Header;
+
+/// A number of records above which the journal file is compacted.
+const MAX_RECORDS: usize = 4096;
+
+/// An on-disk journal of action requests being executed.
+#[derive(Debug)]
+pub struct Journal {
+    /// A path to the journal file.
+    path: PathBuf,
+    /// The journal file opened for appending.
+    file: File,
+    /// A number of records in the journal file.
+    records: usize,
+    /// Requests that have been accepted but not finished yet.
+    pending: Vec<Entry>,
+}
+
+/// A single journal entry corresponding to an action request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    /// A name of the requested action.
+    pub action: String,
+    /// Metadata of the request.
+    pub header: Header,
+    /// An identifier of the next response to send for the request.
+    pub next_response_id: u64,
+}
+
+/// A single change recorded in the journal file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Record {
+    /// A request has been accepted.
+    Accept(Entry),
+    /// A request is going to send a response with the given identifier next.
+    Advance(Header, u64),
+    /// A request has finished.
+    Finish(Header),
+}
+
+impl Journal {
+
+    /// Opens the journal at the given `path`, creating it if does not exist.
+    ///
+    /// Entries left in the existing journal are available as pending. Records
+    /// of the journal that cannot be parsed are logged and skipped.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Journal> {
+        let path = path.as_ref().to_path_buf();
+
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(ref error) if error.kind() == ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(error),
+        };
+
+        let mut journal = Journal {
+            file: OpenOptions::new().create(true).append(true).open(&path)?,
+            path: path,
+            records: 0,
+            pending: Vec::new(),
+        };
+
+        for line in content.lines() {
+            match parse_record(line) {
+                Ok(record) => journal.apply(record),
+                Err(error) => {
+                    error!("invalid journal record '{}': {}", line, error);
+                }
+            }
+            journal.records += 1;
+        }
+
+        Ok(journal)
+    }
+
+    /// Returns requests that have been accepted but not finished yet.
+    pub fn pending(&self) -> &[Entry] {
+        &self.pending
+    }
+
+    /// Records that the given request has been accepted.
+    pub fn accept(&mut self, entry: Entry) -> Result<()> {
+        self.record(Record::Accept(entry))?;
+        self.file.sync_data()
+    }
+
+    /// Records that the next response of the request with the given `header`
+    /// will have the `next_response_id` identifier.
+    pub fn advance(&mut self, header: &Header, next_response_id: u64) -> Result<()> {
+        self.record(Record::Advance(header.clone(), next_response_id))
+    }
+
+    /// Records that the request with the given `header` has finished.
+    pub fn finish(&mut self, header: &Header) -> Result<()> {
+        self.record(Record::Finish(header.clone()))?;
+        self.file.sync_data()
+    }
+
+    /// Removes all the pending entries from the journal.
+    pub fn clear(&mut self) -> Result<()> {
+        self.pending.clear();
+        self.compact()
+    }
+
+    /// Applies the `record` to the pending entries and appends it to the file.
+    fn record(&mut self, record: Record) -> Result<()> {
+        let line = format_record(&record);
+        self.apply(record);
+
+        if self.pending.is_empty() || self.records >= MAX_RECORDS {
+            return self.compact();
+        }
+
+        self.file.write_all(line.as_bytes())?;
+        self.records += 1;
+
+        Ok(())
+    }
+
+    /// Applies the `record` to the pending entries.
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::Accept(entry) => self.pending.push(entry),
+            Record::Advance(header, next_response_id) => {
+                for entry in self.pending.iter_mut() {
+                    if entry.header == header {
+                        entry.next_response_id = next_response_id;
+                    }
+                }
+            }
+            Record::Finish(header) => {
+                self.pending.retain(|entry| entry.header != header);
+            }
+        }
+    }
+
+    /// Replaces the journal file with one recording only the pending entries.
+    ///
+    /// The journal is first written (and synced) to a temporary file which then
+    /// replaces the actual one, so a crash while compacting cannot leave it
+    /// corrupted.
+    fn compact(&mut self) -> Result<()> {
+        let mut content = String::new();
+        for entry in &self.pending {
+            content.push_str(&format_record(&Record::Accept(entry.clone())));
+        }
+
+        let temp_path = self.path.with_extension("tmp");
+        let mut temp_file = File::create(&temp_path)?;
+        temp_file.write_all(content.as_bytes())?;
+        temp_file.sync_all()?;
+        std::fs::rename(&temp_path, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.records = self.pending.len();
+
+        Ok(())
+    }
+}
+
+/// Formats the journal `record` as a single (newline-terminated) line.
+///
+/// The session identifier is put at the end, as it is the only component that
+/// is not fully under the agent's control.
+fn format_record(record: &Record) -> String {
+    match *record {
+        Record::Accept(ref entry) => {
+            format!("accept\t{}\t{}\t{}\t{}\n", entry.header.request_id,
+                    entry.next_response_id, entry.action, entry.header.session_id)
+        }
+        Record::Advance(ref header, next_response_id) => {
+            format!("advance\t{}\t{}\t{}\n", header.request_id, next_response_id,
+                    header.session_id)
+        }
+        Record::Finish(ref header) => {
+            format!("finish\t{}\t{}\n", header.request_id, header.session_id)
+        }
+    }
+}
+
+/// Parses a single journal line into a record.
+fn parse_record(line: &str) -> Result<Record> {
+    let invalid = |message| Error::new(ErrorKind::InvalidData, message);
+
+    let (kind, rest) = match line.find('\t') {
+        Some(index) => (&line[..index], &line[index + 1..]),
+        None => return Err(invalid("missing record kind")),
+    };
+
+    let fields = match kind {
+        "accept" => 4,
+        "advance" => 3,
+        "finish" => 2,
+        _ => return Err(invalid("unknown record kind")),
+    };
+
+    let parts = rest.splitn(fields, '\t').collect::<Vec<_>>();
+    if parts.len() != fields {
+        return Err(invalid("missing record fields"));
+    }
+
+    let header = Header {
+        request_id: parts[0].parse()
+            .map_err(|_| invalid("invalid request id"))?,
+        session_id: String::from(parts[fields - 1]),
+    };
+
+    let next_response_id = || parts[1].parse()
+        .map_err(|_| invalid("invalid response id"));
+
+    match kind {
+        "accept" => Ok(Record::Accept(Entry {
+            action: String::from(parts[2]),
+            header: header,
+            next_response_id: next_response_id()?,
+        })),
+        "advance" => Ok(Record::Advance(header, next_response_id()?)),
+        _ => Ok(Record::Finish(header)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn entry(action: &str, session_id: &str, request_id: u64) -> Entry {
+        Entry {
+            action: String::from(action),
+            header: Header {
+                session_id: String::from(session_id),
+                request_id: request_id,
+            },
+            next_response_id: 1,
+        }
+    }
+
+    #[test]
+    fn test_open_non_existing() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let journal = Journal::open(tempdir.path().join("journal")).unwrap();
+        assert!(journal.pending().is_empty());
+    }
+
+    #[test]
+    fn test_accept_persists() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("journal");
+
+        let mut journal = Journal::open(&path).unwrap();
+        journal.accept(entry("Timeline", "F:ABC", 1)).unwrap();
+        journal.accept(entry("ListDirectory", "F:DEF", 2)).unwrap();
+
+        let journal = Journal::open(&path).unwrap();
+        assert_eq!(journal.pending(), &[
+            entry("Timeline", "F:ABC", 1),
+            entry("ListDirectory", "F:DEF", 2),
+        ]);
+    }
+
+    #[test]
+    fn test_finish_persists() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("journal");
+
+        let mut journal = Journal::open(&path).unwrap();
+        journal.accept(entry("Timeline", "F:ABC", 1)).unwrap();
+        journal.accept(entry("ListDirectory", "F:DEF", 2)).unwrap();
+        journal.finish(&entry("Timeline", "F:ABC", 1).header).unwrap();
+
+        let journal = Journal::open(&path).unwrap();
+        assert_eq!(journal.pending(), &[entry("ListDirectory", "F:DEF", 2)]);
+    }
+
+    #[test]
+    fn test_advance_persists() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("journal");
+
+        let mut journal = Journal::open(&path).unwrap();
+        journal.accept(entry("Timeline", "F:ABC", 1)).unwrap();
+        journal.accept(entry("ListDirectory", "F:DEF", 2)).unwrap();
+        journal.advance(&entry("Timeline", "F:ABC", 1).header, 42).unwrap();
+
+        let journal = Journal::open(&path).unwrap();
+        assert_eq!(journal.pending(), &[
+            Entry {
+                next_response_id: 42,
+                ..entry("Timeline", "F:ABC", 1)
+            },
+            entry("ListDirectory", "F:DEF", 2),
+        ]);
+    }
+
+    #[test]
+    fn test_clear_persists() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("journal");
+
+        let mut journal = Journal::open(&path).unwrap();
+        journal.accept(entry("Timeline", "F:ABC", 1)).unwrap();
+        journal.clear().unwrap();
+
+        let journal = Journal::open(&path).unwrap();
+        assert!(journal.pending().is_empty());
+    }
+
+    #[test]
+    fn test_open_skips_malformed() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("journal");
+        std::fs::write(&path, "foo\naccept\t42\t1\tTimeline\tF:A\tB\nfinish\tfoo\n").unwrap();
+
+        let journal = Journal::open(&path).unwrap();
+        assert_eq!(journal.pending(), &[entry("Timeline", "F:A\tB", 42)]);
+    }
+
+    #[test]
+    fn test_advance_appends() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("journal");
+
+        let mut journal = Journal::open(&path).unwrap();
+        journal.accept(entry("Timeline", "F:ABC", 1)).unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+
+        journal.advance(&entry("Timeline", "F:ABC", 1).header, 2).unwrap();
+        journal.advance(&entry("Timeline", "F:ABC", 1).header, 3).unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content.lines().count(), 3);
+        assert!(content.len() as u64 > len);
+    }
+
+    #[test]
+    fn test_compact_when_nothing_pending() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("journal");
+
+        let mut journal = Journal::open(&path).unwrap();
+        journal.accept(entry("Timeline", "F:ABC", 1)).unwrap();
+        journal.advance(&entry("Timeline", "F:ABC", 1).header, 2).unwrap();
+        journal.finish(&entry("Timeline", "F:ABC", 1).header).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+    }
+
+    #[test]
+    fn test_compact_when_too_long() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("journal");
+
+        let mut journal = Journal::open(&path).unwrap();
+        journal.accept(entry("Timeline", "F:ABC", 1)).unwrap();
+        for response_id in 0..(MAX_RECORDS as u64 + 1) {
+            let header = &entry("Timeline", "F:ABC", 1).header;
+            journal.advance(header, response_id + 2).unwrap();
+        }
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.lines().count() <= MAX_RECORDS);
+
+        let journal = Journal::open(&path).unwrap();
+        assert_eq!(journal.pending(), &[
+            Entry {
+                next_response_id: MAX_RECORDS as u64 + 2,
+                ..entry("Timeline", "F:ABC", 1)
+            },
+        ]);
+    }
+}
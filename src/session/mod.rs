@@ -16,23 +16,57 @@
 
 mod demand;
 mod error;
+mod journal;
+mod profile;
+mod progress;
+mod queue;
 mod response;
 mod sink;
+mod throttle;
+mod traversal;
 
 use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use log::{error, info};
+use lazy_static::lazy_static;
+use log::{error, info, warn};
 
 use crate::action;
+use crate::crash;
+use crate::fs::ErrorSummary;
 use crate::message;
-pub use self::demand::{Demand, Header, Payload};
-pub use self::error::{Error, ParseError, MissingFieldError};
+use crate::metrics;
+use crate::opts::Opts;
+pub use self::demand::{Demand, Header, Payload, Priority};
+pub use self::error::{Error, ParseError, MissingFieldError, RdfNameError, TooLargeError};
+pub use self::profile::{Phase, Profile};
+pub use self::progress::Progress;
+pub use self::queue::Queue;
+use self::journal::Journal;
 use self::response::{Response, Status};
 pub use self::sink::{Sink};
+pub use self::throttle::Throttle;
 
 /// A specialized `Result` type for sessions.
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// A number of bytes of the message size limit reserved for the message envelope.
+///
+/// Responses are checked against the limit before they are wrapped into a GRR
+/// message, so some room has to be left for the session identifier and other
+/// metadata.
+const ENVELOPE_RESERVE: usize = 1024;
+
+/// A name of the journal file within the state directory.
+const JOURNAL_FILE_NAME: &'static str = "journal";
+
+lazy_static! {
+    /// A journal of action requests being executed (if enabled).
+    static ref JOURNAL: Mutex<Option<Journal>> = Mutex::new(None);
+}
+
 /// Object associating a session with particular action request.
 ///
 /// This is just a convenience type used to avoid threading large numbers of
@@ -51,45 +85,48 @@ pub struct Task<'s, S: Session> {
 impl<'s, S: Session> Task<'s, S> {
 
     /// Executes the task with a particular action handler.
+    ///
+    /// Time spent on parsing the request and executing the handler is recorded
+    /// in the session profile.
     pub fn execute<R, H>(self, handler: H) -> Result<()>
     where
         R: action::Request,
         H: FnOnce(&mut S, R) -> Result<()>,
     {
-        let request = self.payload.parse()?;
-        handler(self.session, request)
+        let Task { session, payload } = self;
+
+        let started = Instant::now();
+        let request = payload.parse(session.max_message_size())?;
+        session.record("parse", started.elapsed());
+
+        let started = Instant::now();
+        let result = handler(session, request);
+        session.record("execute", started.elapsed());
+
+        result
     }
 }
 
-/// Processes given message, handling all errors.
+/// Processes given demand, handling all errors.
 ///
-/// This function takes a message from the GRR server and interprets it as an
-/// action request. It creates a new session object and dispatches the request
-/// to an appropriate action handler. Once the action finishes, the function
-/// will send a final status message, informing the server about a success
-/// or a failure.
+/// This function takes a demand parsed from a GRR server message. It creates a
+/// new session object and dispatches the request to an appropriate action
+/// handler from the `registry`. Once the action finishes, the function will
+/// send a final status message, informing the server about a success or a
+/// failure.
 ///
 /// Note that if action execution fails, this function deals with all the errors
 /// by sending appropriate information to the server (if possible), logging them
 /// and failing hard if a critical error (e.g. communication failure) occurred.
-pub fn handle<M>(message: M)
-where
-    M: TryInto<Demand, Error=ParseError>,
-{
-    let demand = match message.try_into() {
-        Ok(demand) => {
-            info!("requested to execute the '{}' action", demand.action);
-            demand
-        }
-        Err(error) => {
-            error!("failed to parse the message: {}", error);
-            return;
-        }
-    };
+pub fn handle(demand: Demand, opts: &Opts, registry: &action::Registry<Action>) {
+    info!("requested to execute the '{}' action ({:?} priority)",
+          demand.action, demand.priority);
 
-    let mut session = Action::from_demand(&demand);
+    metrics::action_started();
+    crash::set_action(Some((&demand.action, &demand.header.session_id)));
+    let mut session = Action::from_demand(&demand, opts);
 
-    let result = action::dispatch(&demand.action, Task {
+    let result = registry.dispatch(&demand.action, Task {
         session: &mut session,
         payload: demand.payload,
     });
@@ -100,6 +137,11 @@ where
         info!("finished executing the '{}' action", demand.action);
     }
 
+    info!("profile of the '{}' action: {}", demand.action, session.profile);
+    metrics::record_action(&demand.action, &session.profile, result.is_ok());
+    metrics::action_finished();
+    crash::set_action(None);
+
     let message = match session.status(result).try_into() {
         Ok(message) => message,
         Err(error) => {
@@ -112,6 +154,109 @@ where
     };
 
     message::send(message);
+
+    // The request is removed from the journal only once the status is sent, so
+    // that it is reported as interrupted if the agent dies before that.
+    let header = &demand.header;
+    journal(|journal| journal.finish(header));
+}
+
+/// Configures the state shared by all sessions according to `opts`.
+///
+/// This function should be called before the agent starts handling requests.
+pub fn init(opts: &Opts) {
+    throttle::init(opts.send_rate_limit, opts.send_burst_size);
+}
+
+/// Records that the `demand` has been received and is going to be handled.
+///
+/// Demands are journaled as soon as they are received rather than when they
+/// start, so that demands still waiting in the queue are reported as well if
+/// the agent dies before getting to them.
+pub fn accept(demand: &Demand) {
+    journal(|journal| journal.accept(journal::Entry {
+        action: demand.action.clone(),
+        header: demand.header.clone(),
+        next_response_id: 1,
+    }));
+}
+
+/// Enables the request journal and notifies the server about interrupted ones.
+///
+/// The journal is kept in the given `state_dir`. All requests that are still
+/// in the journal (i.e. were interrupted by the previous agent run) are failed
+/// with an appropriate status. This function should be called before the agent
+/// starts listening for new requests.
+pub fn recover(state_dir: &Path) {
+    let mut journal = match Journal::open(state_dir.join(JOURNAL_FILE_NAME)) {
+        Ok(journal) => journal,
+        Err(error) => {
+            error!("failed to open the request journal: {}", error);
+            return;
+        }
+    };
+
+    for entry in journal.pending() {
+        warn!("the '{}' action has been interrupted by a restart", entry.action);
+
+        // The status takes the identifier following the last response that
+        // was sent before the restart, so it does not collide with any of them.
+        fail_restarted(&entry.header, entry.next_response_id);
+    }
+
+    if let Err(error) = journal.clear() {
+        error!("failed to clear the request journal: {}", error);
+        return;
+    }
+
+    *JOURNAL.lock().unwrap_or_else(|error| error.into_inner()) = Some(journal);
+}
+
+/// Fails the `demand` without executing it, as the agent is about to restart.
+///
+/// Demands that are waiting to be handled have already been taken from
+/// Fleetspeak, so the server would wait for their status forever if they were
+/// simply dropped.
+pub fn abandon(demand: Demand) {
+    warn!("the '{}' action has been abandoned because of a restart", demand.action);
+
+    // Nothing has been sent for the demand yet, so the status is its very first
+    // response.
+    fail_restarted(&demand.header, 1);
+
+    let header = &demand.header;
+    journal(|journal| journal.finish(header));
+}
+
+/// Sends a status failing the request with `header` because of a restart.
+fn fail_restarted(header: &Header, response_id: u64) {
+    let status = Status {
+        session_id: header.session_id.clone(),
+        request_id: header.request_id,
+        response_id: response_id,
+        result: Err(Error::Restarted),
+    };
+
+    match status.try_into() {
+        Ok(message) => message::send(message),
+        Err(error) => error!("failed to encode status message: {}", error),
+    }
+}
+
+/// Applies the given `update` to the request journal (if enabled).
+///
+/// Failures to update the journal are not critical for action execution, so
+/// they are only logged.
+fn journal<F>(update: F)
+where
+    F: FnOnce(&mut Journal) -> std::io::Result<()>,
+{
+    let mut journal = JOURNAL.lock().unwrap_or_else(|error| error.into_inner());
+    if let Some(ref mut journal) = *journal {
+        if let Err(error) = update(journal) {
+            error!("failed to update the request journal: {}", error);
+        }
+    }
 }
 
 /// Abstraction for various kinds of sessions.
@@ -124,10 +269,66 @@ pub trait Session {
     fn send<R>(&mut self, sink: Sink, response: R) -> Result<()>
     where R: action::Response + 'static;
 
+    /// Returns the maximum size (in bytes) of a single message to send.
+    ///
+    /// Actions producing payloads that cannot be shrunk automatically (such as
+    /// streams of compressed blocks) can use it to size their messages.
+    fn max_message_size(&self) -> usize {
+        usize::MAX
+    }
+
     /// Sends a heartbeat signal to the Fleetspeak process.
     fn heartbeat(&mut self) {
         // TODO: Create a real implementation.
     }
+
+    /// Records that `elapsed` time has been spent in a particular `phase`.
+    ///
+    /// Sessions that do not keep track of profiling information can simply
+    /// ignore this call.
+    fn record(&mut self, _phase: &'static str, _elapsed: Duration) {
+    }
+
+    /// Increments a particular profiling `counter` by `value`.
+    ///
+    /// Sessions that do not keep track of profiling information can simply
+    /// ignore this call.
+    fn count(&mut self, _counter: &'static str, _value: u64) {
+    }
+
+    /// Reports progress of a long-running action to the server.
+    ///
+    /// Progress updates are rate-limited and the `progress` function is called
+    /// only if an update is actually due. This makes calling this method cheap
+    /// enough to do it for every processed item.
+    ///
+    /// Sessions that are not associated with any request can simply ignore
+    /// this call.
+    fn progress<F>(&mut self, _progress: F) -> Result<()>
+    where
+        F: FnOnce() -> Progress,
+    {
+        Ok(())
+    }
+
+    /// Reports paths that a filesystem-traversing action had to skip.
+    ///
+    /// The summary should be reported once, after the traversal is complete.
+    /// Empty summaries are not sent to the server at all.
+    ///
+    /// Sessions that are not associated with any request can simply ignore
+    /// this call.
+    fn report_errors(&mut self, _summary: ErrorSummary) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns a directory where actions can persist state across requests.
+    ///
+    /// Sessions that are not allowed to keep any state (or when no state
+    /// directory is configured) simply return `None`.
+    fn state_dir(&self) -> Option<&Path> {
+        None
+    }
 }
 
 /// A session type for unrequested action executions.
@@ -157,7 +358,7 @@ impl Session for Adhoc {
     where
         R: action::Response,
     {
-        send(sink.wrap(response))?;
+        send(sink.wrap(response), None)?;
 
         Ok(())
     }
@@ -171,12 +372,27 @@ impl Session for Adhoc {
 pub struct Action {
     header: Header,
     next_response_id: u64,
+    profile: Profile,
+    progress: progress::Limiter,
+    limits: Limits,
+    heartbeat_rate: Duration,
+    state_dir: Option<PathBuf>,
+}
+
+/// Limits that outgoing messages of a session have to respect.
+struct Limits {
+    /// A size above which messages are sent compressed.
+    compression_threshold: usize,
+    /// A maximum size of a single message.
+    max_message_size: usize,
 }
 
 impl Action {
 
     /// Constructs a new session for the given `demand` object.
-    pub fn from_demand(demand: &Demand) -> Action {
+    ///
+    /// Limits and thresholds the session should respect are taken from `opts`.
+    pub fn from_demand(demand: &Demand, opts: &Opts) -> Action {
         // Response identifiers that GRR agents use start at 1. Unfortunately,
         // the server uses this assumption (to determine the number of expected
         // responses when status message is received), so we have to follow this
@@ -184,9 +400,60 @@ impl Action {
         Action {
             header: demand.header.clone(),
             next_response_id: 1,
+            profile: Profile::new(),
+            progress: progress::Limiter::new(),
+            limits: Limits {
+                compression_threshold: opts.compression_threshold,
+                max_message_size: opts.max_message_size,
+            },
+            heartbeat_rate: opts.heartbeat_rate,
+            state_dir: opts.state_dir.clone(),
         }
     }
 
+    /// Applies backpressure after `size` bytes have been sent.
+    ///
+    /// If the outgoing traffic is rate-limited and the limit has been exceeded,
+    /// this method blocks until the action is allowed to send more data. During
+    /// long waits heartbeats are sent, so that Fleetspeak does not consider the
+    /// agent to be stuck.
+    fn throttle(&mut self, size: usize) {
+        let delay = throttle::consume(size as u64);
+        if delay == Duration::from_secs(0) {
+            return;
+        }
+
+        let started = Instant::now();
+        let mut remaining = delay;
+        while remaining > self.heartbeat_rate {
+            std::thread::sleep(self.heartbeat_rate);
+            self.heartbeat();
+            remaining -= self.heartbeat_rate;
+        }
+        std::thread::sleep(remaining);
+
+        self.profile.record("throttle", started.elapsed());
+    }
+
+    /// Shrinks the `response` so that it fits into the message size limit.
+    ///
+    /// Blobs moved out of the response are sent to the transfer store right
+    /// away, so the returned response can safely refer to them.
+    fn fit<R>(&mut self, response: R) -> Result<Raw<R>>
+    where
+        R: action::Response,
+    {
+        let limit = self.limits.max_message_size.saturating_sub(ENVELOPE_RESERVE);
+        let threshold = self.limits.compression_threshold;
+        let shrunk = fit::<R>(response.into_proto(), limit, threshold)?;
+
+        for blob in shrunk.blobs {
+            self.send(Sink::TRANSFER_STORE, Blob(blob))?;
+        }
+
+        Ok(Raw(shrunk.proto))
+    }
+
     /// Wraps an action response to a session-specific response.
     fn wrap<R>(&self, response: R) -> Response<R>
     where
@@ -218,9 +485,20 @@ impl Action {
 impl Session for Action {
 
     fn reply<R: action::Response>(&mut self, response: R) -> Result<()> {
-        send(self.wrap(response))?;
+        let started = Instant::now();
+        let response = self.fit(response)?;
+        let size = send(self.wrap(response), Some(&self.limits))?;
         self.next_response_id += 1;
 
+        let header = &self.header;
+        let next_response_id = self.next_response_id;
+        journal(|journal| journal.advance(header, next_response_id));
+
+        self.profile.record("send", started.elapsed());
+        self.profile.count("replies", 1);
+        self.profile.count("bytes_sent", size as u64);
+        self.throttle(size);
+
         Ok(())
     }
 
@@ -228,25 +506,182 @@ impl Session for Action {
     where
         R: action::Response,
     {
-        send(sink.wrap(response))?;
+        let started = Instant::now();
+        let response = self.fit(response)?;
+        let size = send(sink.wrap(response), Some(&self.limits))?;
+
+        self.profile.record("send", started.elapsed());
+        self.profile.count("responses", 1);
+        self.profile.count("bytes_sent", size as u64);
+        self.throttle(size);
 
         Ok(())
     }
+
+    fn max_message_size(&self) -> usize {
+        self.limits.max_message_size
+    }
+
+    fn heartbeat(&mut self) {
+        message::heartbeat(self.heartbeat_rate);
+        metrics::heartbeat();
+    }
+
+    fn record(&mut self, phase: &'static str, elapsed: Duration) {
+        self.profile.record(phase, elapsed);
+    }
+
+    fn count(&mut self, counter: &'static str, value: u64) {
+        self.profile.count(counter, value);
+    }
+
+    fn progress<F>(&mut self, progress: F) -> Result<()>
+    where
+        F: FnOnce() -> Progress,
+    {
+        if !self.progress.poll() {
+            return Ok(());
+        }
+
+        self.send(Sink::PROGRESS, progress::Response {
+            header: self.header.clone(),
+            progress: progress(),
+        })
+    }
+
+    fn report_errors(&mut self, summary: ErrorSummary) -> Result<()> {
+        if summary.is_empty() {
+            return Ok(());
+        }
+
+        self.send(Sink::TRAVERSAL_ERRORS, traversal::Response {
+            header: self.header.clone(),
+            summary: summary,
+        })
+    }
+
+    fn state_dir(&self) -> Option<&Path> {
+        self.state_dir.as_deref()
+    }
 }
 
 /// Sends a session response to the server.
 ///
+/// If `limits` are specified, responses bigger than the compression threshold
+/// are sent compressed and responses that (even compressed) exceed the maximum
+/// message size are rejected with an error. On success, the size (in bytes) of
+/// the serialized (and possibly compressed) response is returned.
+///
 /// Note that this function is not exposed on purpose. Actions should send
 /// responses through session objects which introduce a layer of safety. `send`
 /// is a low-level utility supposed to be used internally.
-fn send<R>(response: Response<R>) -> Result<()>
+fn send<R>(response: Response<R>, limits: Option<&Limits>) -> Result<usize>
 where
     R: action::Response,
 {
-    let message = response.try_into()?;
+    let mut message: rrg_proto::GrrMessage = response.try_into()?;
+    if let Some(limits) = limits {
+        message = message::compress(message, limits.compression_threshold);
+
+        let size = prost::Message::encoded_len(&message);
+        if size > limits.max_message_size {
+            return Err(TooLargeError::new(size, limits.max_message_size).into());
+        }
+    }
+
+    let size = message.args.as_ref().map_or(0, Vec::len);
     message::send(message);
 
-    Ok(())
+    Ok(size)
+}
+
+/// Shrinks the raw `proto` of a response of type `R` to fit into `limit` bytes.
+///
+/// Protos bigger than `compression_threshold` are sent compressed, so it is
+/// their compressed size that has to fit. Protos that already fit are returned
+/// as they are. Otherwise the response type is asked to shrink it and an error
+/// is returned if it is unable to.
+fn fit<R>(proto: R::Proto, limit: usize, compression_threshold: usize)
+          -> Result<action::Shrunk<R::Proto>>
+where
+    R: action::Response,
+{
+    let size = sent_len(&proto, limit, compression_threshold);
+    if size <= limit {
+        return Ok(action::Shrunk::new(proto));
+    }
+
+    let shrunk = match R::shrink(proto, limit) {
+        Some(shrunk) => shrunk,
+        None => return Err(TooLargeError::new(size, limit).into()),
+    };
+
+    let size = sent_len(&shrunk.proto, limit, compression_threshold);
+    if size > limit {
+        return Err(TooLargeError::new(size, limit).into());
+    }
+
+    // Blobs are wrapped into messages on their own, so they are subject to the
+    // same limit.
+    if let Some(blob) = shrunk.blobs.iter().find(|blob| blob.len() > limit) {
+        return Err(TooLargeError::new(blob.len(), limit).into());
+    }
+
+    Ok(shrunk)
+}
+
+/// Computes the number of bytes the `proto` takes once sent.
+///
+/// Protos bigger than `compression_threshold` are compressed before being sent,
+/// but compressing is done only if the `proto` does not fit into `limit` bytes
+/// as it is.
+fn sent_len<P>(proto: &P, limit: usize, compression_threshold: usize) -> usize
+where
+    P: prost::Message,
+{
+    let size = proto.encoded_len();
+    if size <= limit || size <= compression_threshold {
+        return size;
+    }
+
+    let mut bytes = Vec::with_capacity(size);
+    // Encoding to a vector can fail only if we run out of memory, in which case
+    // we are doomed anyway.
+    proto.encode(&mut bytes)
+        .expect("failed to encode a response");
+
+    match message::deflate(&bytes) {
+        Ok(compressed) => std::cmp::min(compressed.len(), size),
+        Err(_) => size,
+    }
+}
+
+/// A response that has already been converted to its raw proto.
+struct Raw<R: action::Response>(R::Proto);
+
+impl<R: action::Response> action::Response for Raw<R> {
+
+    const RDF_NAME: Option<&'static str> = R::RDF_NAME;
+
+    type Proto = R::Proto;
+
+    fn into_proto(self) -> R::Proto {
+        self.0
+    }
+}
+
+/// A blob moved out of an oversized response.
+struct Blob(Vec<u8>);
+
+impl action::Response for Blob {
+
+    const RDF_NAME: Option<&'static str> = Some("DataBlob");
+
+    type Proto = rrg_proto::DataBlob;
+
+    fn into_proto(self) -> rrg_proto::DataBlob {
+        self.0.into()
+    }
 }
 
 #[cfg(test)]
@@ -268,6 +703,12 @@ pub mod test {
     pub struct Fake {
         replies: Vec<Box<dyn Any>>,
         responses: HashMap<Sink, Vec<Box<dyn Any>>>,
+        profile: Profile,
+        progress: Vec<Progress>,
+        errors: Vec<ErrorSummary>,
+        state_dir: Option<PathBuf>,
+        max_message_size: usize,
+        response_limit: Option<usize>,
     }
 
     impl Fake {
@@ -277,9 +718,56 @@ pub mod test {
             Fake {
                 replies: Vec::new(),
                 responses: std::collections::HashMap::new(),
+                profile: Profile::new(),
+                progress: Vec::new(),
+                errors: Vec::new(),
+                state_dir: None,
+                max_message_size: usize::MAX,
+                response_limit: None,
             }
         }
 
+        /// Makes the session expose `state_dir` as its state directory.
+        pub fn with_state_dir<P: Into<PathBuf>>(mut self, state_dir: P) -> Fake {
+            self.state_dir = Some(state_dir.into());
+            self
+        }
+
+        /// Makes the session report `size` as the maximum message size.
+        pub fn with_max_message_size(mut self, size: usize) -> Fake {
+            self.max_message_size = size;
+            self
+        }
+
+        /// Makes the session fail to send responses once `limit` of them (to
+        /// all sinks in total) have been sent.
+        ///
+        /// This is useful for simulating actions interrupted halfway through.
+        pub fn with_response_limit(mut self, limit: usize) -> Fake {
+            self.response_limit = Some(limit);
+            self
+        }
+
+        /// Yields the profile that this session accumulated so far.
+        pub fn profile(&self) -> &Profile {
+            &self.profile
+        }
+
+        /// Yields all progress updates reported by the action so far.
+        ///
+        /// Unlike real sessions, the fake one does not rate-limit updates, so
+        /// every reported update is available here.
+        pub fn progress_updates(&self) -> &[Progress] {
+            &self.progress
+        }
+
+        /// Yields all traversal error summaries reported by the action so far.
+        ///
+        /// Unlike real sessions, the fake one keeps empty summaries as well.
+        pub fn error_summaries(&self) -> &[ErrorSummary] {
+            &self.errors
+        }
+
         /// Yields the number of replies that this session sent so far.
         pub fn reply_count(&self) -> usize {
             self.replies.len()
@@ -378,11 +866,51 @@ pub mod test {
         where
             R: action::Response + 'static,
         {
+            if let Some(limit) = self.response_limit {
+                let count = self.responses.values().map(Vec::len).sum::<usize>();
+                if count >= limit {
+                    let error = std::io::Error::new(std::io::ErrorKind::Other,
+                                                    "response limit exceeded");
+                    return Err(Error::action(error));
+                }
+            }
+
             let responses = self.responses.entry(sink).or_insert_with(Vec::new);
             responses.push(Box::new(response));
 
             Ok(())
         }
+
+        fn max_message_size(&self) -> usize {
+            self.max_message_size
+        }
+
+        fn record(&mut self, phase: &'static str, elapsed: Duration) {
+            self.profile.record(phase, elapsed);
+        }
+
+        fn count(&mut self, counter: &'static str, value: u64) {
+            self.profile.count(counter, value);
+        }
+
+        fn progress<F>(&mut self, progress: F) -> Result<()>
+        where
+            F: FnOnce() -> Progress,
+        {
+            self.progress.push(progress());
+
+            Ok(())
+        }
+
+        fn report_errors(&mut self, summary: ErrorSummary) -> Result<()> {
+            self.errors.push(summary);
+
+            Ok(())
+        }
+
+        fn state_dir(&self) -> Option<&Path> {
+            self.state_dir.as_deref()
+        }
     }
 }
 
@@ -575,6 +1103,103 @@ mod tests {
         assert_eq!(responses.next(), None);
     }
 
+    #[test]
+    fn test_task_execute_profile() {
+
+        fn handle<S: Session>(session: &mut S, _: ()) -> Result<()> {
+            session.count("foo", 42);
+            Ok(())
+        }
+
+        let mut session = test::Fake::new();
+        let task = Task {
+            session: &mut session,
+            payload: Payload {
+                data: None,
+                rdf_name: None,
+                compressed: false,
+            },
+        };
+        assert!(task.execute(handle).is_ok());
+
+        let profile = session.profile();
+        assert_eq!(profile.phase("parse").unwrap().count, 1);
+        assert_eq!(profile.phase("execute").unwrap().count, 1);
+        assert_eq!(profile.counter("foo"), 42);
+    }
+
+    #[test]
+    fn test_fit_small() {
+        let shrunk = fit::<StringResponse>(String::from("foo"), 1024, usize::MAX).unwrap();
+        assert_eq!(shrunk.proto, "foo");
+        assert!(shrunk.blobs.is_empty());
+    }
+
+    #[test]
+    fn test_fit_too_large() {
+        match fit::<StringResponse>("x".repeat(2048), 1024, usize::MAX) {
+            Err(Error::TooLarge(_)) => (),
+            _ => panic!("unexpected fit result"),
+        }
+    }
+
+    #[test]
+    fn test_fit_blobs() {
+        let shrunk = fit::<BlobResponse>(vec![42; 2048], 1024, usize::MAX).unwrap();
+        assert_eq!(shrunk.blobs.len(), 3);
+        assert!(shrunk.blobs.iter().all(|blob| blob.len() <= 1000));
+        assert_eq!(shrunk.blobs.concat(), vec![42; 2048]);
+        assert_eq!(shrunk.proto.len(), 3 * 32);
+    }
+
+    #[test]
+    fn test_fit_compressible() {
+        let shrunk = fit::<StringResponse>("x".repeat(2048), 1024, 512).unwrap();
+        assert_eq!(shrunk.proto, "x".repeat(2048));
+        assert!(shrunk.blobs.is_empty());
+    }
+
+    #[test]
+    fn test_fit_incompressible() {
+        use rand::RngCore as _;
+
+        let mut data = vec![0; 2048];
+        rand::thread_rng().fill_bytes(&mut data);
+
+        let shrunk = fit::<BlobResponse>(data.clone(), 1024, 512).unwrap();
+        assert_eq!(shrunk.blobs.concat(), data);
+    }
+
+    #[test]
+    fn test_too_large_error_message() {
+        let error = Error::from(TooLargeError::new(2048, 1024));
+        assert!(error.to_string().contains("2048"));
+        assert!(error.to_string().contains("1024"));
+    }
+
+    /// A response carrying a blob that is moved out if it is too large.
+    ///
+    /// The shrunk response consists of concatenated digests of the blobs.
+    struct BlobResponse(Vec<u8>);
+
+    impl action::Response for BlobResponse {
+
+        const RDF_NAME: Option<&'static str> = Some("DataBlob");
+
+        type Proto = Vec<u8>;
+
+        fn into_proto(self) -> Vec<u8> {
+            self.0
+        }
+
+        fn shrink(proto: Vec<u8>, _limit: usize) -> Option<action::Shrunk<Vec<u8>>> {
+            let mut shrunk = action::Shrunk::new(Vec::new());
+            shrunk.proto = shrunk.move_blob(proto, 1000).concat();
+
+            Some(shrunk)
+        }
+    }
+
     #[derive(Debug, PartialEq, Eq)]
     struct StringResponse(String);
 
@@ -0,0 +1,153 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Utilities for profiling action execution.
+//!
+//! Profiles are lightweight accumulators of timings and counters. Each session
+//! keeps its own profile which actions can feed with timings of their phases
+//! (e.g. how much time was spent on stat syscalls) and arbitrary counters
+//! (e.g. how many entries were processed).
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/// Accumulated timings and counters of a single action execution.
+#[derive(Debug, Default)]
+pub struct Profile {
+    /// Timings of particular execution phases.
+    phases: BTreeMap<&'static str, Phase>,
+    /// Values of particular counters.
+    counters: BTreeMap<&'static str, u64>,
+}
+
+/// Accumulated timing of a single execution phase.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Phase {
+    /// A number of times the phase has been recorded.
+    pub count: u64,
+    /// A total time spent in the phase.
+    pub total: Duration,
+}
+
+impl Profile {
+
+    /// Creates a new, empty profile.
+    pub fn new() -> Profile {
+        Profile::default()
+    }
+
+    /// Records that `elapsed` time has been spent in the given `phase`.
+    pub fn record(&mut self, phase: &'static str, elapsed: Duration) {
+        let phase = self.phases.entry(phase).or_default();
+        phase.count += 1;
+        phase.total += elapsed;
+    }
+
+    /// Increments the given `counter` by `value`.
+    pub fn count(&mut self, counter: &'static str, value: u64) {
+        *self.counters.entry(counter).or_default() += value;
+    }
+
+    /// Retrieves the accumulated timing of the given `phase` (if recorded).
+    pub fn phase(&self, phase: &str) -> Option<Phase> {
+        self.phases.get(phase).copied()
+    }
+
+    /// Retrieves the value of the given `counter` (zero if never counted).
+    pub fn counter(&self, counter: &str) -> u64 {
+        self.counters.get(counter).copied().unwrap_or(0)
+    }
+
+    /// Returns an iterator over all the recorded phases.
+    pub fn phases(&self) -> impl Iterator<Item = (&'static str, Phase)> + '_ {
+        self.phases.iter().map(|(name, phase)| (*name, *phase))
+    }
+
+    /// Returns an iterator over all the counters.
+    pub fn counters(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        self.counters.iter().map(|(name, value)| (*name, *value))
+    }
+}
+
+impl Display for Profile {
+
+    fn fmt(&self, fmt: &mut Formatter) -> std::fmt::Result {
+        let mut first = true;
+
+        for (name, phase) in self.phases() {
+            if !first {
+                write!(fmt, ", ")?;
+            }
+            first = false;
+
+            write!(fmt, "{}: {:?} ({}x)", name, phase.total, phase.count)?;
+        }
+
+        for (name, value) in self.counters() {
+            if !first {
+                write!(fmt, ", ")?;
+            }
+            first = false;
+
+            write!(fmt, "{}: {}", name, value)?;
+        }
+
+        if first {
+            write!(fmt, "empty")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_record_accumulates() {
+        let mut profile = Profile::new();
+        profile.record("foo", Duration::from_millis(10));
+        profile.record("foo", Duration::from_millis(32));
+
+        let phase = profile.phase("foo").unwrap();
+        assert_eq!(phase.count, 2);
+        assert_eq!(phase.total, Duration::from_millis(42));
+    }
+
+    #[test]
+    fn test_record_missing_phase() {
+        let profile = Profile::new();
+        assert_eq!(profile.phase("foo"), None);
+    }
+
+    #[test]
+    fn test_count_accumulates() {
+        let mut profile = Profile::new();
+        profile.count("foo", 40);
+        profile.count("foo", 2);
+        profile.count("bar", 1);
+
+        assert_eq!(profile.counter("foo"), 42);
+        assert_eq!(profile.counter("bar"), 1);
+        assert_eq!(profile.counter("baz"), 0);
+    }
+
+    #[test]
+    fn test_display_empty() {
+        assert_eq!(Profile::new().to_string(), "empty");
+    }
+
+    #[test]
+    fn test_display() {
+        let mut profile = Profile::new();
+        profile.record("foo", Duration::from_millis(5));
+        profile.count("bar", 3);
+
+        assert_eq!(profile.to_string(), "foo: 5ms (1x), bar: 3");
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Utilities for reporting progress of long-running actions.
+//!
+//! Some actions (e.g. the timeline action) can run for hours and without any
+//! feedback analysts cannot tell whether they are stuck or just slow. Progress
+//! updates are sent to a dedicated sink and do not affect the regular flow of
+//! responses.
+
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::action;
+use crate::session::Header;
+
+/// A minimum interval between two consecutive progress updates.
+pub const PROGRESS_RATE: Duration = Duration::from_secs(10);
+
+/// A snapshot of the progress of a long-running action.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Progress {
+    /// A number of entries (e.g. files) processed so far.
+    pub entries: u64,
+    /// A number of bytes processed so far.
+    pub bytes: u64,
+    /// A path of the item that is being currently processed (if applicable).
+    pub path: Option<PathBuf>,
+    /// An estimated fraction (between 0 and 1) of the work done (if known).
+    pub fraction: Option<f32>,
+}
+
+/// A rate limiter for progress updates.
+///
+/// The first update is due only after the rate interval elapses since the
+/// creation of the limiter. This way, short actions do not send any progress
+/// updates at all.
+pub struct Limiter {
+    /// A moment after which the next update can be sent.
+    due: Instant,
+}
+
+impl Limiter {
+
+    /// Creates a new limiter allowing the first update after `PROGRESS_RATE`.
+    pub fn new() -> Limiter {
+        Limiter {
+            due: Instant::now() + PROGRESS_RATE,
+        }
+    }
+
+    /// Checks whether an update is due and if so, postpones the next one.
+    pub fn poll(&mut self) -> bool {
+        let now = Instant::now();
+        if now < self.due {
+            return false;
+        }
+
+        self.due = now + PROGRESS_RATE;
+        true
+    }
+}
+
+/// A progress update of a particular action request.
+pub struct Response {
+    /// Metadata of the request the progress is reported for.
+    pub header: Header,
+    /// The actual progress snapshot.
+    pub progress: Progress,
+}
+
+impl action::Response for Response {
+
+    const RDF_NAME: Option<&'static str> = Some("Progress");
+
+    type Proto = rrg_proto::rrg::Progress;
+
+    fn into_proto(self) -> rrg_proto::rrg::Progress {
+        let path = self.progress.path
+            .map(|path| path.to_string_lossy().into_owned());
+
+        rrg_proto::rrg::Progress {
+            session_id: Some(self.header.session_id),
+            request_id: Some(self.header.request_id),
+            entries_processed: Some(self.progress.entries),
+            bytes_processed: Some(self.progress.bytes),
+            current_path: path,
+            fraction: self.progress.fraction,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_limiter_not_due_initially() {
+        let mut limiter = Limiter::new();
+        assert!(!limiter.poll());
+    }
+
+    #[test]
+    fn test_limiter_due_after_rate() {
+        let mut limiter = Limiter {
+            due: Instant::now(),
+        };
+
+        assert!(limiter.poll());
+        assert!(!limiter.poll());
+    }
+
+    #[test]
+    fn test_response_into_proto() {
+        use crate::action::Response as _;
+
+        let response = Response {
+            header: Header {
+                session_id: String::from("F:ABC"),
+                request_id: 42,
+            },
+            progress: Progress {
+                entries: 1337,
+                bytes: 4096,
+                path: Some(PathBuf::from("/foo/bar")),
+                fraction: Some(0.5),
+            },
+        };
+
+        let proto = response.into_proto();
+        assert_eq!(proto.session_id, Some(String::from("F:ABC")));
+        assert_eq!(proto.request_id, Some(42));
+        assert_eq!(proto.entries_processed, Some(1337));
+        assert_eq!(proto.bytes_processed, Some(4096));
+        assert_eq!(proto.current_path, Some(String::from("/foo/bar")));
+        assert_eq!(proto.fraction, Some(0.5));
+    }
+}
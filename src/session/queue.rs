@@ -0,0 +1,196 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! A priority queue of demands waiting to be dispatched.
+//!
+//! Demands are dispatched one at a time, so without prioritization an urgent
+//! request could wait for hours behind a backlog of hunt requests. The queue
+//! always yields the item of the highest priority (the oldest one among items
+//! of equal priority).
+//!
+//! To avoid starvation of low-priority items, every item is promoted one level
+//! up for each aging interval it spends in the queue.
+
+use std::time::{Duration, Instant};
+
+use crate::session::Priority;
+
+/// A priority queue with aging.
+pub struct Queue<T> {
+    /// Items waiting in the queue.
+    entries: Vec<Entry<T>>,
+    /// A time after which waiting items are promoted to a higher priority.
+    aging: Duration,
+    /// A sequence number to assign to the next item.
+    next_sequence: u64,
+}
+
+/// A single queued item with its scheduling metadata.
+struct Entry<T> {
+    /// The queued item itself.
+    item: T,
+    /// A priority the item has been queued with.
+    priority: Priority,
+    /// A moment at which the item has been queued.
+    queued: Instant,
+    /// A sequence number of the item (to keep the arrival order).
+    sequence: u64,
+}
+
+impl<T> Entry<T> {
+
+    /// Computes the priority of the item including its promotions at `now`.
+    fn priority(&self, aging: Duration, now: Instant) -> Priority {
+        if aging == Duration::from_secs(0) {
+            return self.priority;
+        }
+
+        let waited = now.saturating_duration_since(self.queued);
+        let mut priority = self.priority;
+        let mut promotions = waited.as_nanos() / aging.as_nanos();
+        while promotions > 0 && priority != Priority::High {
+            priority = priority.promoted();
+            promotions -= 1;
+        }
+
+        priority
+    }
+}
+
+impl<T> Queue<T> {
+
+    /// Creates a new empty queue promoting items after the `aging` interval.
+    ///
+    /// If the interval is zero, items are never promoted.
+    pub fn new(aging: Duration) -> Queue<T> {
+        Queue {
+            entries: Vec::new(),
+            aging: aging,
+            next_sequence: 0,
+        }
+    }
+
+    /// Returns the number of items waiting in the queue.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Checks whether there are no items waiting in the queue.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Puts the `item` into the queue with the given `priority`.
+    pub fn push(&mut self, item: T, priority: Priority) {
+        self.push_at(item, priority, Instant::now());
+    }
+
+    /// Takes the most urgent item out of the queue (if there is any).
+    pub fn pop(&mut self) -> Option<T> {
+        self.pop_at(Instant::now())
+    }
+
+    /// Puts the `item` into the queue as if it was queued at `now`.
+    fn push_at(&mut self, item: T, priority: Priority, now: Instant) {
+        self.entries.push(Entry {
+            item: item,
+            priority: priority,
+            queued: now,
+            sequence: self.next_sequence,
+        });
+        self.next_sequence += 1;
+    }
+
+    /// Takes the most urgent item out of the queue as of `now`.
+    fn pop_at(&mut self, now: Instant) -> Option<T> {
+        let aging = self.aging;
+
+        let index = self.entries.iter()
+            .enumerate()
+            .max_by_key(|(_, entry)| {
+                (entry.priority(aging, now), std::cmp::Reverse(entry.sequence))
+            })
+            .map(|(index, _)| index)?;
+
+        Some(self.entries.remove(index).item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const AGING: Duration = Duration::from_secs(600);
+
+    #[test]
+    fn test_pop_empty() {
+        let mut queue = Queue::<()>::new(AGING);
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn test_pop_by_priority() {
+        let mut queue = Queue::new(AGING);
+        queue.push("low", Priority::Low);
+        queue.push("high", Priority::High);
+        queue.push("medium", Priority::Medium);
+
+        assert_eq!(queue.pop(), Some("high"));
+        assert_eq!(queue.pop(), Some("medium"));
+        assert_eq!(queue.pop(), Some("low"));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_pop_equal_priority_in_arrival_order() {
+        let mut queue = Queue::new(AGING);
+        queue.push("foo", Priority::Medium);
+        queue.push("bar", Priority::Medium);
+        queue.push("baz", Priority::Medium);
+
+        assert_eq!(queue.pop(), Some("foo"));
+        assert_eq!(queue.pop(), Some("bar"));
+        assert_eq!(queue.pop(), Some("baz"));
+    }
+
+    #[test]
+    fn test_pop_aged_item_promoted() {
+        let now = Instant::now();
+
+        let mut queue = Queue::new(AGING);
+        queue.push_at("low", Priority::Low, now);
+        queue.push_at("medium", Priority::Medium, now + AGING);
+
+        // The low-priority item waited for a full aging interval, so it is now
+        // on par with the medium-priority one and it arrived earlier.
+        assert_eq!(queue.pop_at(now + AGING), Some("low"));
+        assert_eq!(queue.pop_at(now + AGING), Some("medium"));
+    }
+
+    #[test]
+    fn test_pop_aged_item_not_above_high() {
+        let now = Instant::now();
+
+        let mut queue = Queue::new(AGING);
+        queue.push_at("low", Priority::Low, now);
+        queue.push_at("high", Priority::High, now + 10 * AGING);
+
+        assert_eq!(queue.pop_at(now + 10 * AGING), Some("low"));
+        assert_eq!(queue.pop_at(now + 10 * AGING), Some("high"));
+    }
+
+    #[test]
+    fn test_pop_no_aging() {
+        let now = Instant::now();
+
+        let mut queue = Queue::new(Duration::from_secs(0));
+        queue.push_at("low", Priority::Low, now);
+        queue.push_at("medium", Priority::Medium, now);
+
+        assert_eq!(queue.pop_at(now + 100 * AGING), Some("medium"));
+        assert_eq!(queue.len(), 1);
+    }
+}
@@ -31,6 +31,15 @@ impl Sink {
     /// A handle to the transfer store sink.
     pub const TRANSFER_STORE: Sink = Sink { id: "/flows/F:TransferStore" };
 
+    /// A handle to the sink expecting progress updates of running actions.
+    pub const PROGRESS: Sink = Sink { id: "/flows/F:Progress" };
+
+    /// A handle to the sink expecting summaries of skipped paths of actions.
+    pub const TRAVERSAL_ERRORS: Sink = Sink { id: "/flows/F:TraversalErrors" };
+
+    /// A handle to the sink expecting agent crash reports.
+    pub const CRASH: Sink = Sink { id: "/flows/F:CrashHandler" };
+
     /// Wraps an action response to a sink-specific session response.
     pub fn wrap<R>(&self, response: R) -> session::Response<R>
     where
@@ -0,0 +1,161 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Utilities for limiting the rate of data sent by actions.
+//!
+//! Fleetspeak does not acknowledge messages, so the agent cannot tell how many
+//! bytes are still waiting to be delivered and it has no way to bound them.
+//! Instead, the outgoing traffic is shaped with a token bucket: the agent can
+//! send a burst of data right away but after that it is slowed down to the
+//! configured rate. The bucket is shared by all the sessions of the agent, so
+//! the rate holds across actions executed one after another as well.
+//!
+//! Token buckets can also be used by actions to limit the rate of other kinds
+//! of I/O (e.g. reading file contents).
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// A bucket limiting the outgoing traffic of all sessions (if enabled).
+    static ref SHARED: Mutex<Option<Throttle>> = Mutex::new(None);
+}
+
+/// Limits the outgoing traffic of all sessions to `rate` bytes per second with
+/// bursts of up to `capacity` bytes.
+///
+/// If the `rate` is zero, the outgoing traffic is not limited.
+pub fn init(rate: u64, capacity: u64) {
+    *lock() = if rate > 0 {
+        Some(Throttle::new(rate, capacity))
+    } else {
+        None
+    };
+}
+
+/// Records that `bytes` have been sent by any of the sessions.
+///
+/// The returned duration specifies how long the sender should wait before
+/// sending anything else (zero if it can carry on right away or if the traffic
+/// is not limited).
+pub fn consume(bytes: u64) -> Duration {
+    match *lock() {
+        Some(ref mut throttle) => throttle.consume(bytes, Instant::now()),
+        None => Duration::from_secs(0),
+    }
+}
+
+/// Locks the shared bucket, ignoring lock poisoning.
+fn lock() -> std::sync::MutexGuard<'static, Option<Throttle>> {
+    SHARED.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+/// A token bucket limiting the rate of outgoing bytes.
+#[derive(Debug)]
+pub struct Throttle {
+    /// A number of bytes per second the bucket is refilled with.
+    rate: u64,
+    /// A maximum number of bytes that can be sent in a single burst.
+    capacity: u64,
+    /// A number of bytes that can be sent right away.
+    ///
+    /// It becomes negative if more bytes than available have been sent, in
+    /// which case the sender has to wait until the bucket refills.
+    balance: f64,
+    /// A moment at which the balance was last updated.
+    updated: Instant,
+}
+
+impl Throttle {
+
+    /// Creates a new full bucket with the given refill `rate` and `capacity`.
+    pub fn new(rate: u64, capacity: u64) -> Throttle {
+        Throttle {
+            rate: std::cmp::max(rate, 1),
+            capacity: capacity,
+            balance: capacity as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Records that `bytes` have been sent at the moment `now`.
+    ///
+    /// The returned duration specifies how long the sender should wait before
+    /// sending anything else (zero if it can carry on right away).
+    pub fn consume(&mut self, bytes: u64, now: Instant) -> Duration {
+        self.refill(now);
+        self.balance -= bytes as f64;
+
+        if self.balance >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.balance / self.rate as f64)
+        }
+    }
+
+    /// Refills the bucket with bytes accumulated until the moment `now`.
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated);
+        let refill = elapsed.as_secs_f64() * self.rate as f64;
+
+        self.balance = f64::min(self.balance + refill, self.capacity as f64);
+        self.updated = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_consume_within_capacity() {
+        let now = Instant::now();
+        let mut throttle = Throttle::new(1024, 4096);
+
+        assert_eq!(throttle.consume(1024, now), Duration::from_secs(0));
+        assert_eq!(throttle.consume(3072, now), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_consume_over_capacity() {
+        let now = Instant::now();
+        let mut throttle = Throttle::new(1024, 4096);
+
+        assert_eq!(throttle.consume(4096 + 2048, now), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_consume_after_refill() {
+        let now = Instant::now();
+        let mut throttle = Throttle::new(1024, 4096);
+
+        assert_eq!(throttle.consume(4096 + 1024, now), Duration::from_secs(1));
+
+        let later = now + Duration::from_secs(3);
+        assert_eq!(throttle.consume(1024, later), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_shared() {
+        init(1024, 4096);
+        assert_eq!(consume(4096), Duration::from_secs(0));
+        assert!(consume(2048) > Duration::from_secs(1));
+
+        init(0, 4096);
+        assert_eq!(consume(1 << 30), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_refill_does_not_exceed_capacity() {
+        let now = Instant::now();
+        let mut throttle = Throttle::new(1024, 4096);
+
+        let later = now + Duration::from_secs(3600);
+        assert_eq!(throttle.consume(4096 + 1024, later), Duration::from_secs(1));
+    }
+}
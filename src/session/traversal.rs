@@ -0,0 +1,98 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Utilities for reporting errors of actions traversing the filesystem.
+//!
+//! Actions such as listing a directory or collecting a timeline skip paths that
+//! they cannot access. Without any feedback analysts cannot tell an empty
+//! directory from an inaccessible one, so a summary of skipped paths is sent to
+//! a dedicated sink once such an action finishes.
+
+use crate::action;
+use crate::fs::ErrorSummary;
+use crate::session::Header;
+
+/// A summary of traversal errors of a particular action request.
+pub struct Response {
+    /// Metadata of the request the errors are reported for.
+    pub header: Header,
+    /// The actual summary of the errors.
+    pub summary: ErrorSummary,
+}
+
+impl action::Response for Response {
+
+    const RDF_NAME: Option<&'static str> = Some("TraversalErrors");
+
+    type Proto = rrg_proto::rrg::TraversalErrors;
+
+    fn into_proto(self) -> rrg_proto::rrg::TraversalErrors {
+        let mut error_counts = self.summary.counts()
+            .map(|(kind, count)| rrg_proto::rrg::ErrorCount {
+                kind: Some(format!("{:?}", kind)),
+                count: Some(count),
+            })
+            .collect::<Vec<_>>();
+        error_counts.sort_by(|lhs, rhs| lhs.kind.cmp(&rhs.kind));
+
+        let skipped_paths = self.summary.errors().iter()
+            .map(|error| rrg_proto::rrg::SkippedPath {
+                path: Some(error.path().to_string_lossy().into_owned()),
+                kind: Some(format!("{:?}", error.kind())),
+                message: Some(error.to_string()),
+            })
+            .collect();
+
+        rrg_proto::rrg::TraversalErrors {
+            session_id: Some(self.header.session_id),
+            request_id: Some(self.header.request_id),
+            error_count: Some(self.summary.count()),
+            error_counts: error_counts,
+            skipped_paths: skipped_paths,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::ErrorKind;
+
+    use super::*;
+    use crate::fs::WalkError;
+
+    #[test]
+    fn test_response_into_proto() {
+        use crate::action::Response as _;
+
+        let mut summary = ErrorSummary::new();
+        summary.record(WalkError::new("/foo", ErrorKind::PermissionDenied.into()));
+        summary.record(WalkError::new("/bar", ErrorKind::NotFound.into()));
+        summary.record(WalkError::new("/baz", ErrorKind::NotFound.into()));
+
+        let response = Response {
+            header: Header {
+                session_id: String::from("F:ABC"),
+                request_id: 42,
+            },
+            summary: summary,
+        };
+
+        let proto = response.into_proto();
+        assert_eq!(proto.session_id, Some(String::from("F:ABC")));
+        assert_eq!(proto.request_id, Some(42));
+        assert_eq!(proto.error_count, Some(3));
+
+        assert_eq!(proto.error_counts.len(), 2);
+        assert_eq!(proto.error_counts[0].kind, Some(String::from("NotFound")));
+        assert_eq!(proto.error_counts[0].count, Some(2));
+        assert_eq!(proto.error_counts[1].kind, Some(String::from("PermissionDenied")));
+        assert_eq!(proto.error_counts[1].count, Some(1));
+
+        assert_eq!(proto.skipped_paths.len(), 3);
+        assert_eq!(proto.skipped_paths[0].path, Some(String::from("/foo")));
+        assert_eq!(proto.skipped_paths[0].kind, Some(String::from("PermissionDenied")));
+    }
+}